@@ -0,0 +1,186 @@
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::prelude::*;
+use crate::*;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+// What we know about one loaded identity's end-entity certificate.
+#[derive(Debug, Clone)]
+pub struct IdentityStatus {
+    pub name: String,
+    pub source: String,
+    pub subject: String,
+    pub sans: Vec<String>,
+    pub not_before: i64,
+    pub not_after: i64,
+    pub names_covered: bool,
+    pub fingerprint: String,
+}
+
+lazy_static! {
+    // Identities from the most recent certificate (re)load
+    static ref TLS_STATUS: RwLock<Vec<IdentityStatus>> = RwLock::new(Vec::new());
+
+    // Smallest expiry threshold already warned about, per name and
+    // certificate fingerprint, so the 60 second reload doesn't repeat warnings.
+    static ref EXPIRY_WARNED: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+impl IdentityStatus {
+    pub fn from_der(name: &str, source: &Path, der: &[u8]) -> Result<IdentityStatus> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| anyhow::anyhow!("failed to parse certificate: {e}"))?;
+
+        let mut sans = Vec::<String>::new();
+        if let Ok(Some(ext)) = cert.subject_alternative_name() {
+            for general_name in &ext.value.general_names {
+                match general_name {
+                    GeneralName::DNSName(dns) => sans.push(dns.to_lowercase()),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => sans.push(std::net::IpAddr::from(<[u8; 4]>::try_from(*ip)?).to_string()),
+                        16 => sans.push(std::net::IpAddr::from(<[u8; 16]>::try_from(*ip)?).to_string()),
+                        _ => (),
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        let names_covered = name_covered(name, &sans);
+        Ok(IdentityStatus {
+            name: String::from(name),
+            source: source.to_string_lossy().into_owned(),
+            subject: cert.subject().to_string(),
+            sans,
+            not_before: cert.validity().not_before.timestamp(),
+            not_after: cert.validity().not_after.timestamp(),
+            names_covered,
+            fingerprint: sha256_fingerprint(der),
+        })
+    }
+
+    // Whole days until notAfter; negative once expired.
+    pub fn days_remaining(&self) -> i64 {
+        (self.not_after - unix_now()).div_euclid(SECONDS_PER_DAY)
+    }
+
+    pub fn expired(&self) -> bool {
+        let now = unix_now();
+        now < self.not_before || now > self.not_after
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Does one of the SANs match name? A wildcard SAN covers exactly one label.
+pub fn name_covered(name: &str, sans: &[String]) -> bool {
+    let name = name.to_lowercase();
+    sans.iter().any(|san| {
+        if *san == name {
+            return true;
+        }
+        match (san.strip_prefix("*."), name.split_once('.')) {
+            (Some(suffix), Some((label, rest))) => !label.is_empty() && rest == suffix,
+            _ => false,
+        }
+    })
+}
+
+// Replace the published status after a certificate (re)load, logging any
+// certificate that has crossed one of the configured expiry thresholds.
+pub fn publish_tls_status(status: Vec<IdentityStatus>) {
+    let mut warned = EXPIRY_WARNED.lock().unwrap();
+    for identity in &status {
+        let key = format!("{} {}", &identity.name, &identity.fingerprint);
+        let days = identity.days_remaining();
+        if identity.expired() {
+            if warned.get(&key) != Some(&i64::MIN) {
+                eprintln!(
+                    "TLS: certificate for {} ({}) is not valid now (notAfter {} days ago)",
+                    &identity.name, &identity.source, -days
+                );
+                warned.insert(key.clone(), i64::MIN);
+            }
+            continue;
+        }
+
+        let crossed = CONFIG.tls_warn_days.iter().filter(|&&t| days <= t).min();
+        if let Some(&threshold) = crossed {
+            if warned.get(&key).is_none_or(|&w| threshold < w) {
+                eprintln!(
+                    "TLS: certificate for {} ({}) expires in {} day(s)",
+                    &identity.name, &identity.source, days
+                );
+                warned.insert(key.clone(), threshold);
+            }
+        }
+    }
+
+    *TLS_STATUS.write().unwrap() = status;
+}
+
+pub fn tls_status_json() -> String {
+    let status = TLS_STATUS.read().unwrap();
+    let identities: Vec<String> = status
+        .iter()
+        .map(|s| {
+            format!(
+                "{{\"name\":{},\"source\":{},\"subject\":{},\"sans\":[{}],\"not_before\":{},\"not_after\":{},\"days_remaining\":{},\"expired\":{},\"names_covered\":{},\"sha256\":{}}}",
+                json_string(&s.name),
+                json_string(&s.source),
+                json_string(&s.subject),
+                s.sans.iter().map(|x| json_string(x)).collect::<Vec<_>>().join(","),
+                s.not_before,
+                s.not_after,
+                s.days_remaining(),
+                s.expired(),
+                s.names_covered,
+                json_string(&s.fingerprint)
+            )
+        })
+        .collect();
+    format!("{{\"identities\":[{}]}}\n", identities.join(","))
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub async fn request_handler_tls_status<S: AsyncRead + AsyncWrite + Unpin>(
+    mut request: HttpRequest<S>,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let json = tls_status_json();
+    send_response(&mut request, "application/json", Some(&json)).await?;
+    println!(
+        "Request (server {}) client {} {} {} (TLS status) {} byte(s) in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        json.len(),
+        start_time.elapsed()
+    );
+    request.stream.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use std::os::unix::fs::PermissionsExt;
+use std::process::Stdio;
+use tokio::io::AsyncBufRead;
+use tokio::process::Command;
+use crate::*;
+
+// Longest a script may run, from spawn to the end of its output
+const CGI_TIMEOUT: Duration = Duration::from_secs(30);
+const CGI_MAX_HEADER_BYTES: u64 = 64 * 1024;
+
+// A URL prefix whose scripts are run from a directory, from -C /prefix=dir
+#[derive(Debug, Clone)]
+pub struct CgiMapping {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+impl CgiMapping {
+    pub fn parse(spec: &str) -> Result<CgiMapping> {
+        let (prefix, dir) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected /prefix=directory, got {spec}"))?;
+        if !prefix.starts_with('/') {
+            return Err(anyhow::anyhow!("CGI prefix {prefix} should start with /"));
+        }
+        let dir = std::fs::canonicalize(dir).map_err(|e| anyhow::anyhow!("CGI directory {dir}: {e}"))?;
+        Ok(CgiMapping {
+            prefix: String::from(prefix.trim_end_matches('/')),
+            dir,
+        })
+    }
+
+    // Script name and PATH_INFO for a URL under the prefix
+    fn split<'a>(&self, url: &'a str) -> Option<(&'a str, &'a str)> {
+        let rest = url.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        match rest.find('/') {
+            Some(i) => Some((&rest[..i], &rest[i..])),
+            None => Some((rest, "")),
+        }
+    }
+}
+
+pub fn cgi_mapping(url: &str) -> Option<&'static CgiMapping> {
+    CONFIG
+        .cgi
+        .iter()
+        .filter(|mapping| mapping.split(url).is_some_and(|(script, _)| !script.is_empty()))
+        .max_by_key(|mapping| mapping.prefix.len())
+}
+
+// Run a CGI/1.1 script (RFC 3875) for the request: body to its stdin, its
+// output parsed for CGI headers and streamed back.
+pub async fn request_handler_cgi<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut request: HttpRequest<S>,
+    mapping: &CgiMapping,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let (script_name, path_info) = mapping.split(&request.url).unwrap_or_default();
+    let (script_name, path_info) = (String::from(script_name), String::from(path_info));
+
+    // Only executable files directly inside the directory
+    let dir = chroot_path(&mapping.dir);
+    let script = match tokio::fs::canonicalize(dir.join(&script_name)).await {
+        Ok(path) if path.parent() == Some(dir.as_ref()) => path,
+        _ => return cgi_error(&mut request, "404 Not Found", "no such script").await,
+    };
+    match tokio::fs::metadata(&script).await {
+        Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => (),
+        _ => return cgi_error(&mut request, "404 Not Found", "not an executable file").await,
+    }
+
+    if request.headers.contains_key("transfer-encoding") {
+        // CGI needs CONTENT_LENGTH up front
+        return cgi_error(&mut request, "411 Length Required", "chunked request body").await;
+    }
+    let content_length: u64 = match request.headers.get("content-length").map(|l| l.parse()) {
+        None => 0,
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return cgi_error(&mut request, "400 Bad Request", "bad Content-Length").await,
+    };
+
+    let mut command = Command::new(&script);
+    command
+        .env_clear()
+        .envs(cgi_environment(
+            &request,
+            &format!("{}/{}", mapping.prefix, script_name),
+            &script,
+            &path_info,
+            content_length,
+        ))
+        .current_dir(&mapping.dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        // Own process group, so a timeout kills whatever the script started
+        .process_group(0)
+        .kill_on_drop(true);
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return cgi_error(&mut request, "500 Internal Server Error", &format!("spawn: {e}")).await,
+    };
+    let mut stdin = child.stdin.take();
+    let mut stdout = tokio::io::BufReader::new(child.stdout.take().ok_or_else(|| Error::msg("no stdout"))?);
+
+    let deadline = tokio::time::Instant::now() + CGI_TIMEOUT;
+
+    // Feed the body while waiting for the headers, so a script that starts
+    // answering before reading all of its input doesn't stall
+    let body = async {
+        if let Some(mut stdin) = stdin.take() {
+            let _ = tokio::io::copy(&mut (&mut request.stream).take(content_length), &mut stdin).await;
+        }
+    };
+    let (_, head) = match tokio::time::timeout_at(deadline, async { tokio::join!(body, read_cgi_head(&mut stdout)) }).await {
+        Ok(result) => result,
+        Err(_) => {
+            kill_process_group(&child);
+            return cgi_error(&mut request, "504 Gateway Timeout", "timed out").await;
+        }
+    };
+    let (status, headers) = match head {
+        Ok(head) => head,
+        Err(e) => return cgi_error(&mut request, "500 Internal Server Error", &e.to_string()).await,
+    };
+
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in &headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "{}{}{}Connection: close\r\n\r\n",
+        cache_control_header(),
+        virtual_host_headers(&request.server_name),
+        alt_svc_header()
+    ));
+    request.stream.write_all(response.as_bytes()).await?;
+
+    let bytes = if request.method == "head" {
+        0
+    } else {
+        match tokio::time::timeout_at(deadline, tokio::io::copy(&mut stdout, &mut request.stream)).await {
+            Ok(bytes) => bytes?,
+            Err(_) => {
+                kill_process_group(&child);
+                eprintln!(
+                    "Request (server {}) client {} {} {}: CGI {} timed out, killed",
+                    &request.server_name.as_ref().map_or("default", |x| x),
+                    &request.client,
+                    &request.method,
+                    &request.url,
+                    script.to_string_lossy()
+                );
+                0
+            }
+        }
+    };
+    request.stream.flush().await?;
+    let _ = child.start_kill();
+    let exit = child.wait().await?;
+
+    println!(
+        "Request (server {}) client {} {} {} (CGI {}) {} {}, {} byte(s) in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        script.to_string_lossy(),
+        status,
+        exit,
+        bytes,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+fn kill_process_group(child: &tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+async fn cgi_error<S: AsyncRead + AsyncWrite + Unpin>(
+    request: &mut HttpRequest<S>,
+    status: &str,
+    reason: &str,
+) -> Result<()> {
+    eprintln!(
+        "Request (server {}) client {} {} {}: CGI: {} ({})",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        reason,
+        status
+    );
+    send_status_response(request, status).await?;
+    request.stream.flush().await?;
+    Ok(())
+}
+
+// The port the server is reached on: the one bound, or behind a reverse
+// proxy on a Unix domain socket, the scheme's own
+fn server_port(scheme: &str) -> u16 {
+    CONFIG.bind_addr.port().unwrap_or(if scheme == "https" { 443 } else { 80 })
+}
+
+// Meta-variables of RFC 3875 section 4.1, plus the request headers as HTTP_*.
+// Also the parameters of a FastCGI request.
+pub fn cgi_environment<S>(
+    request: &HttpRequest<S>,
+    script_url: &str,
+    script: &Path,
+    path_info: &str,
+    content_length: u64,
+) -> Vec<(String, String)> {
+    let query = request.target.split_once('?').map_or("", |(_, q)| q);
+    let server_name = request.server_name.clone().unwrap_or_else(|| {
+        request
+            .headers
+            .get("host")
+            .map(|h| h.rsplit_once(':').filter(|(_, p)| !p.contains(']')).map_or(h.as_str(), |(h, _)| h))
+            .unwrap_or("localhost")
+            .to_string()
+    });
+
+    let mut env = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_SOFTWARE"), format!("{}/{}", PKG_NAME, PKG_VERSION)),
+        (String::from("SERVER_PROTOCOL"), request.version.clone()),
+        (String::from("SERVER_NAME"), server_name),
+        (String::from("SERVER_PORT"), server_port(request.scheme).to_string()),
+        (String::from("REQUEST_METHOD"), request.method.to_uppercase()),
+        (String::from("REQUEST_URI"), request.target.clone()),
+        (String::from("SCRIPT_NAME"), String::from(script_url)),
+        (String::from("SCRIPT_FILENAME"), script.to_string_lossy().to_string()),
+        (String::from("QUERY_STRING"), String::from(query)),
+        (String::from("REMOTE_ADDR"), request.client.ip().to_string()),
+        (String::from("PATH"), env::var("PATH").unwrap_or_else(|_| String::from("/usr/bin:/bin"))),
+    ];
+    // A Unix domain socket peer has no port
+    if let Some(port) = request.client.port() {
+        env.push((String::from("REMOTE_PORT"), port.to_string()));
+    }
+    if !path_info.is_empty() {
+        env.push((String::from("PATH_INFO"), String::from(path_info)));
+        env.push((
+            String::from("PATH_TRANSLATED"),
+            site_root(&request.server_name).join(path_info.trim_start_matches('/')).to_string_lossy().to_string(),
+        ));
+    }
+    if content_length > 0 {
+        env.push((String::from("CONTENT_LENGTH"), content_length.to_string()));
+    }
+    if let Some(content_type) = request.headers.get("content-type") {
+        env.push((String::from("CONTENT_TYPE"), content_type.clone()));
+    }
+    if request.scheme == "https" {
+        env.push((String::from("HTTPS"), String::from("on")));
+    }
+    for (name, value) in &request.headers {
+        // Proxy: would become HTTP_PROXY, which scripts' HTTP clients obey
+        if name == "content-type" || name == "content-length" || name == "proxy" {
+            continue;
+        }
+        env.push((format!("HTTP_{}", name.to_uppercase().replace('-', "_")), value.clone()));
+    }
+    env
+}
+
+// Status and headers from a CGI response header section, which FastCGI
+// applications send too. A Location without a Status is a redirect
+// (RFC 3875 6.2.3).
+pub async fn read_cgi_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<(String, Vec<(String, String)>)> {
+    let mut status = None;
+    let mut headers = Vec::new();
+    let mut limited = reader.take(CGI_MAX_HEADER_BYTES);
+    loop {
+        let mut line = String::new();
+        if limited.read_line(&mut line).await? == 0 {
+            return Err(Error::msg("script ended without a complete header"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("malformed header line from script: {line:?}"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            status = Some(String::from(value));
+        } else if !name.eq_ignore_ascii_case("connection") && !name.eq_ignore_ascii_case("transfer-encoding") {
+            headers.push((String::from(name.trim()), String::from(value)));
+        }
+    }
+
+    let has_location = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("location"));
+    let has_content_type = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+    if !has_location && !has_content_type {
+        return Err(Error::msg("script sent neither Content-Type nor Location"));
+    }
+    let status = match status {
+        Some(status) => status,
+        None if has_location => String::from("302 Found"),
+        None => String::from("200 OK"),
+    };
+    Ok((status, headers))
+}
@@ -0,0 +1,241 @@
+use std::io::ErrorKind;
+use crate::*;
+
+// mchttp --check: parse the command line and configuration file, and check
+// everything startup would otherwise only find out when it gets there (roots,
+// TLS identities, WebSocket handlers, listening addresses). The effective
+// configuration is printed, then every problem found; returns the exit
+// status, non-zero if there were any.
+pub fn check_config() -> i32 {
+    let (config, mut errors) = Config::parse_all();
+    let config: &'static Config = Box::leak(Box::new(config));
+
+    with_candidate_config(config, || {
+        errors.extend(check_listeners(config));
+        errors.extend(check_files(config));
+        errors.extend(check_tls(config));
+        errors.extend(check_routes(config));
+        errors.extend(check_vhosts(config));
+        errors.extend(check_privileges(config));
+    });
+
+    if errors.is_empty() {
+        println!("Configuration OK");
+        return 0;
+    }
+    for error in &errors {
+        eprintln!("{error}");
+    }
+    eprintln!("{} configuration error(s)", errors.len());
+    1
+}
+
+// Bind each address and let go of it straight away. One in use is only
+// noted: it's most likely the running server this check is for.
+fn check_listeners(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    println!("Listeners:");
+    for addr in wanted_listeners(config) {
+        let bound = match &addr {
+            ListenAddr::Tcp(tcp) => std::net::TcpListener::bind(tcp).map(drop),
+            ListenAddr::Quic(udp) => std::net::UdpSocket::bind(udp).map(drop),
+            ListenAddr::Unix(path) => check_unix_socket(config, path),
+        };
+        match bound {
+            Ok(()) => println!("  {addr}"),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => println!("  {addr} (in use, already running?)"),
+            Err(e) => {
+                println!("  {addr} (can't bind)");
+                errors.push(format!("{addr}: {e}"));
+            }
+        }
+    }
+    if config.quic_addr.is_some() && config.tls.is_none() {
+        errors.push(String::from("-Q: HTTP/3 requires TLS (-t)"));
+    }
+    errors
+}
+
+// Binding would leave a socket file behind (or take a stale one's place), so
+// just see that it could be: the directory is there, nothing else is using
+// the path, and the owner and group exist
+fn check_unix_socket(config: &Config, path: &Path) -> std::io::Result<()> {
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err(std::io::Error::new(ErrorKind::NotFound, "no such directory"));
+    }
+    stale_unix_socket(path)?;
+    if let Some(socket) = unix_socket(config, path) {
+        socket.ownership().map_err(std::io::Error::other)?;
+    }
+    Ok(())
+}
+
+fn check_files(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Some(data_dir) = &config.data_dir {
+        println!("Data directory: {}", data_dir.to_string_lossy());
+        if !data_dir.is_dir() {
+            errors.push(format!("data directory {}: not a directory", data_dir.to_string_lossy()));
+        }
+    }
+    let mut files: Vec<_> = config.files.iter().collect();
+    files.sort();
+    if !files.is_empty() {
+        println!("Files:");
+    }
+    for (url, path) in files {
+        println!("  {url} -> {}", path.to_string_lossy());
+    }
+    errors
+}
+
+// Load every identity, as the listener would, and the TLS options with them.
+// A -t auto certificate isn't generated (or persisted) here.
+fn check_tls(config: &Config) -> Vec<String> {
+    let Some(tls) = &config.tls else {
+        return Vec::new();
+    };
+    let mut errors = Vec::new();
+    println!("TLS: {tls}");
+
+    match tls_config_builder() {
+        Ok(builder) => {
+            let mut server_config = builder
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(IdentityResolver::new()));
+            if let Err(e) = tls_config_finish(&mut server_config) {
+                errors.push(format!("TLS: {e}"));
+            }
+        }
+        Err(e) => errors.push(format!("TLS: {e}")),
+    }
+    if let Some(passphrase_file) = &config.tls_passphrase_file {
+        if let Err(e) = std::fs::metadata(passphrase_file) {
+            errors.push(format!("TLS: passphrase file {}: {e}", passphrase_file.to_string_lossy()));
+        }
+    }
+    if tls == AUTO_TLS {
+        println!("  (self-signed, generated at startup)");
+        return errors;
+    }
+
+    let (identities, failed) = match check_identities(tls) {
+        Ok(checked) => checked,
+        Err(e) => {
+            errors.push(format!("TLS: {tls}: {e}"));
+            return errors;
+        }
+    };
+    for identity in &identities {
+        let days = identity.days_remaining();
+        let validity = if identity.expired() {
+            errors.push(format!("TLS: certificate for {} ({}) is not valid now", identity.name, identity.source));
+            String::from("not valid now")
+        } else {
+            format!("expires in {days} day(s)")
+        };
+        println!("  {} [{}] {}, {}", identity.name, identity.sans.join(", "), identity.source, validity);
+    }
+    errors.extend(failed.iter().map(|failure| format!("TLS: failed to load identity {failure}")));
+    if identities.is_empty() && failed.is_empty() {
+        errors.push(format!("TLS: no identities found in {tls}"));
+    }
+    errors
+}
+
+fn check_routes(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut routes = Vec::new();
+    for route in &config.proxies {
+        let upstreams: Vec<String> = route.backends.iter().map(|backend| backend.upstream.to_string()).collect();
+        routes.push((route.prefix.clone(), format!("proxy {} ({:?})", upstreams.join(", "), route.policy)));
+    }
+    for mapping in &config.cgi {
+        routes.push((mapping.prefix.clone(), format!("cgi {}", mapping.dir.to_string_lossy())));
+    }
+    for mapping in &config.fastcgi {
+        routes.push((format!("*{}", mapping.extension), format!("fastcgi {}", mapping.backend.upstream)));
+    }
+    for (path, spec) in &config.websockets {
+        if let Err(e) = websocket_handler_from_spec(spec) {
+            errors.push(format!("WebSocket handler {path}={spec}: {e}"));
+        }
+        routes.push((path.clone(), format!("websocket {spec}")));
+    }
+    if config.events {
+        routes.push((String::from(EVENTS_PATH), String::from("events")));
+    }
+    if let Some(path) = &config.tls_status_path {
+        routes.push((path.clone(), String::from("TLS status")));
+    }
+    if let Some(path) = &config.reload_path {
+        routes.push((path.clone(), String::from("reload")));
+    }
+
+    if !routes.is_empty() {
+        println!("Routes:");
+    }
+    for (path, target) in routes {
+        println!("  {path} -> {target}");
+    }
+    errors
+}
+
+fn check_vhosts(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    if !config.vhosts.is_empty() {
+        println!("Virtual hosts:");
+    }
+    for vhost in &config.vhosts {
+        let name = Some(vhost.name.clone());
+        let root = site_root(&name);
+        if !root.is_dir() {
+            errors.push(format!("vhost {}: root {}: not a directory", vhost.name, root.to_string_lossy()));
+        }
+
+        let mut settings = vec![format!("index {}", index_files(&name).join(","))];
+        if vhost.autoindex {
+            settings.push(String::from("autoindex"));
+        }
+        if vhost.upload {
+            settings.push(String::from("upload"));
+        }
+        if let Some(auth) = &vhost.auth {
+            settings.push(format!("auth \"{}\" ({} user(s))", auth.realm, auth.users.len()));
+        }
+        let aliases = if vhost.aliases.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", vhost.aliases.join(", "))
+        };
+        println!("  {}{} -> {}, {}", vhost.name, aliases, root.to_string_lossy(), settings.join(", "));
+    }
+    errors
+}
+
+// Who startup would switch to once listening, and whether it could
+fn check_privileges(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    let is_root = unsafe { libc::geteuid() } == 0;
+    let run_as = match run_as(config) {
+        Ok(run_as) => run_as,
+        Err(e) => {
+            errors.push(e.to_string());
+            return errors;
+        }
+    };
+    if let Some(run_as) = &run_as {
+        println!("Run as: {run_as}");
+    }
+    if let (true, Some(data_dir)) = (config.chroot, &config.data_dir) {
+        println!("Chroot: {}", data_dir.to_string_lossy());
+        if !is_root {
+            errors.push(String::from("--chroot: needs to be started as root"));
+        }
+    }
+    let stays_root = is_root && run_as.as_ref().is_none_or(|run_as| run_as.user.as_ref().is_none_or(|(_, uid)| *uid == 0));
+    if stays_root && !config.allow_root {
+        errors.push(String::from("would run as root: give --user to switch once listening, or --allow-root"));
+    }
+    errors
+}
@@ -1,24 +1,161 @@
+use std::sync::atomic::AtomicPtr;
 use crate::*;
 
 const DEFAULT_BIND_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_TLS_WARN_DAYS: [i64; 4] = [30, 14, 7, 1];
 
 //#[derive(Debug)]
 #[derive(Debug)]
 pub struct Config {
     pub verbose: bool, // -v
-    pub bind_addr: SocketAddr,
+    pub bind_addr: BindAddr,
     pub files: HashMap<String, PathBuf>,
     pub data_dir: Option<PathBuf>,
     // pub tls: Option<rustls::ServerConfig>,
     // pub tls_cert_filename: Option<String>,
     // pub tls_key_filename: Option<String>,
     pub tls: Option<String>,
+    pub tls_persist: bool, // -p
+    pub tls_passphrase_file: Option<PathBuf>, // -k
+    pub tls_warn_days: Vec<i64>, // -w
+    pub tls_allow_name_mismatch: bool, // -N
+    pub tls_status_path: Option<String>, // -S
+    pub ocsp_fetch: bool, // -O
+    pub tls_options: TlsOptions, // -T
+    pub h2c: bool, // -H
+    pub quic_addr: Option<SocketAddr>, // -Q
+    pub websockets: Vec<(String, String)>, // -W path=handler
+    pub events: bool, // -E
+    pub dev: bool, // --dev
+    pub proxies: Vec<ProxyRoute>, // -P
+    pub cgi: Vec<CgiMapping>, // -C
+    pub fastcgi: Vec<FastcgiMapping>, // -F
+    pub proxy_protocol: Vec<IpNetwork>, // --proxy-protocol
+    pub trusted_proxies: Vec<IpNetwork>, // --trusted-proxies
+    pub config_file: Option<PathBuf>, // -c
+    pub listen: Vec<BindAddr>, // further listeners from the configuration file
+    pub vhosts: Vec<VirtualHost>, // configuration file [vhost."name"] sections
+    pub limits: Limits,
+    pub reload_path: Option<String>, // --reload-path
+    pub drain_timeout: Duration, // --drain-timeout
+    pub user: Option<String>, // -u
+    pub group: Option<String>, // -g
+    pub chroot: bool, // --chroot
+    pub allow_root: bool, // --allow-root
 }
 
-lazy_static! {
+// Request limits, settable in the configuration file's [limits] section
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub header_timeout: Duration,
+    pub max_header_lines: usize,
+    pub max_line_bytes: usize,
+    pub max_upload: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            header_timeout: REQUEST_HEADER_TIMEOUT,
+            max_header_lines: MAX_HEADER_LINES,
+            max_line_bytes: MAX_LINE_BYTES,
+            max_upload: DEFAULT_MAX_UPLOAD,
+        }
+    }
+}
+
+// An address to accept HTTP connections on: TCP, or a Unix domain socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(UnixSocket),
+}
+
+impl BindAddr {
+    pub fn parse(spec: &str) -> Result<BindAddr> {
+        if spec.starts_with("unix:") {
+            return Ok(BindAddr::Unix(UnixSocket::parse(spec)?));
+        }
+        Ok(BindAddr::Tcp(SocketAddr::from_str(spec)?))
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            BindAddr::Tcp(addr) => Some(addr.port()),
+            BindAddr::Unix(_) => None,
+        }
+    }
+}
+
+// The configuration in effect, from the command line (and -c file). A
+// reload swaps in a new snapshot for new connections, while connections
+// already open keep the one they started with (see with_config). Snapshots
+// are never freed: anything may hold a &'static into one, and reloads are
+// rare enough for that not to matter.
+pub struct CurrentConfig;
+pub static CONFIG: CurrentConfig = CurrentConfig;
+
+static LATEST_CONFIG: AtomicPtr<Config> = AtomicPtr::new(std::ptr::null_mut());
+static INITIAL_CONFIG: std::sync::OnceLock<&'static Config> = std::sync::OnceLock::new();
+
+tokio::task_local! {
+    static CONFIG_SNAPSHOT: &'static Config;
+}
+
+impl std::ops::Deref for CurrentConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        config_snapshot()
+    }
+}
+
+// The snapshot of the task's connection, or else the latest
+pub fn config_snapshot() -> &'static Config {
+    CONFIG_SNAPSHOT.try_with(|config| *config).unwrap_or_else(|_| latest_config())
+}
+
+fn latest_config() -> &'static Config {
+    let latest = LATEST_CONFIG.load(Ordering::Acquire);
+    if !latest.is_null() {
+        return unsafe { &*latest };
+    }
+    // Tests get the defaults rather than the test harness's arguments
+    let initial = INITIAL_CONFIG.get_or_init(|| {
+        Box::leak(Box::new(if cfg!(test) { Config::default() } else { Config::cmdline() }))
+    });
+    let _ = LATEST_CONFIG.compare_exchange(
+        std::ptr::null_mut(),
+        *initial as *const Config as *mut Config,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+    unsafe { &*LATEST_CONFIG.load(Ordering::Acquire) }
+}
+
+// Make a snapshot the latest, for connections accepted from now on, and
+// return the one it replaces
+pub fn publish_config(config: &'static Config) -> &'static Config {
+    let previous = latest_config();
+    LATEST_CONFIG.store(config as *const Config as *mut Config, Ordering::Release);
+    previous
+}
+
+// Run a connection's (or stream's) future against the snapshot current now,
+// however many reloads happen before it's done
+pub fn with_config<F: std::future::Future>(future: F) -> impl std::future::Future<Output = F::Output> {
+    CONFIG_SNAPSHOT.scope(config_snapshot(), future)
+}
+
+// Run a future against a configuration of its own
+#[cfg(test)]
+pub fn with_test_config<F: std::future::Future>(config: Config, future: F) -> impl std::future::Future<Output = F::Output> {
+    CONFIG_SNAPSHOT.scope(Box::leak(Box::new(config)), future)
+}
 
-    // Command line configuration
-    pub static ref CONFIG: Config = Config::cmdline();
+// Run code as if the candidate snapshot were current, to validate it
+pub fn with_candidate_config<R>(config: &'static Config, f: impl FnOnce() -> R) -> R {
+    CONFIG_SNAPSHOT.sync_scope(config, f)
 }
 
 // perhaps make a global structure of above so that it can
@@ -30,83 +167,525 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             verbose: false,
-            bind_addr: DEFAULT_BIND_ADDR.parse().unwrap(),
+            bind_addr: BindAddr::Tcp(DEFAULT_BIND_ADDR.parse().unwrap()),
             files: HashMap::new(),
             data_dir: None,
             tls: None,
+            tls_persist: false,
+            tls_passphrase_file: None,
+            tls_warn_days: DEFAULT_TLS_WARN_DAYS.to_vec(),
+            tls_allow_name_mismatch: false,
+            tls_status_path: None,
+            ocsp_fetch: false,
+            tls_options: TlsOptions::default(),
+            h2c: false,
+            quic_addr: None,
+            websockets: Vec::new(),
+            events: false,
+            dev: false,
+            proxies: Vec::new(),
+            cgi: Vec::new(),
+            fastcgi: Vec::new(),
+            proxy_protocol: Vec::new(),
+            trusted_proxies: Vec::new(),
+            config_file: None,
+            listen: Vec::new(),
+            vhosts: Vec::new(),
+            limits: Limits::default(),
+            reload_path: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            user: None,
+            group: None,
+            chroot: false,
+            allow_root: false,
             // tls_key_filename: None,
             // tls_cert_filename: None,
             // tls_store: None,
         }
     }
 }
+// What an option takes after it; the names are for usage and error messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Takes {
+    Nothing,
+    Value(&'static str),
+    // Repeatable; several may be given in its environment variable,
+    // separated by white space
+    Values(&'static str),
+}
+
+// Every option: the flag matched in parse_all(), its long form, what it
+// takes, and whether it may be set in the environment. The long form names
+// its environment variable: --tls-warn-days is MCHTTP_TLS_WARN_DAYS.
+const OPTIONS: [(&str, &str, Takes, bool); 34] = [
+    ("-c", "--config", Takes::Value("path to configuration file"), true),
+    ("--check", "--check", Takes::Nothing, false),
+    ("-v", "--verbose", Takes::Nothing, true),
+    ("-l", "--listen", Takes::Value("bind address specification"), true),
+    ("-t", "--tls", Takes::Value("path to TLS certificate/identity store"), true),
+    ("-p", "--tls-persist", Takes::Nothing, true),
+    ("-k", "--tls-passphrase-file", Takes::Value("path to TLS passphrase file"), true),
+    ("-w", "--tls-warn-days", Takes::Value("comma separated list of days"), true),
+    ("-N", "--tls-allow-name-mismatch", Takes::Nothing, true),
+    ("-S", "--tls-status-path", Takes::Value("URL path for TLS status report"), true),
+    ("-O", "--ocsp", Takes::Nothing, true),
+    ("-T", "--tls-option", Takes::Values("TLS option key=value"), true),
+    ("-H", "--h2c", Takes::Nothing, true),
+    ("-Q", "--quic", Takes::Value("HTTP/3 bind address specification"), true),
+    ("-W", "--websocket", Takes::Values("WebSocket path=handler"), true),
+    ("-E", "--events", Takes::Nothing, true),
+    ("--dev", "--dev", Takes::Nothing, true),
+    ("-P", "--proxy", Takes::Values("proxy route /prefix=upstream"), true),
+    ("-C", "--cgi", Takes::Values("CGI mapping /prefix=directory"), true),
+    ("-F", "--fastcgi", Takes::Values("FastCGI mapping .ext=upstream"), true),
+    ("--proxy-protocol", "--proxy-protocol", Takes::Value("trusted networks"), true),
+    ("--trusted-proxies", "--trusted-proxies", Takes::Value("trusted networks"), true),
+    ("--reload-path", "--reload-path", Takes::Value("URL path for configuration reloads"), true),
+    ("--drain-timeout", "--drain-timeout", Takes::Value("seconds to drain connections on shutdown"), true),
+    ("-u", "--user", Takes::Value("user to run as"), true),
+    ("-g", "--group", Takes::Value("group to run as"), true),
+    ("--chroot", "--chroot", Takes::Nothing, true),
+    ("--allow-root", "--allow-root", Takes::Nothing, true),
+    ("-d", "--data-dir", Takes::Value("path of data directory"), true),
+    ("-r", "--root", Takes::Value("root directory"), true),
+    ("--file", "--file", Takes::Values("file to serve"), true),
+    ("-h", "--help", Takes::Nothing, false),
+    ("-?", "--help", Takes::Nothing, false),
+    ("-V", "--version", Takes::Nothing, false),
+];
+
+const ENVIRONMENT_PREFIX: &str = "MCHTTP_";
+
+// One option as given, in the environment or on the command line
+struct Argument {
+    flag: &'static str,
+    value: Option<String>,
+    // As written, for error messages: -l, --listen or MCHTTP_LISTEN (empty
+    // for file arguments)
+    given: String,
+    from_environment: bool,
+}
+
+impl Argument {
+    fn describe(&self) -> String {
+        match (&self.value, self.from_environment) {
+            (Some(value), true) => format!("{}={}", self.given, value),
+            (Some(value), false) if self.given.is_empty() => value.clone(),
+            (Some(value), false) => format!("{} {}", self.given, value),
+            (None, _) => self.given.clone(),
+        }
+    }
+
+    // The option's value, converted; a bad value is recorded and the option
+    // otherwise ignored
+    fn value<T>(&self, errors: &mut Vec<String>, convert: impl FnOnce(&str) -> Result<T>) -> Option<T> {
+        match convert(self.value.as_deref().unwrap_or_default()) {
+            Ok(converted) => Some(converted),
+            Err(e) => {
+                errors.push(format!("{}: {e}", self.describe()));
+                None
+            }
+        }
+    }
+}
+
+fn environment_variable(long: &str) -> String {
+    format!("{ENVIRONMENT_PREFIX}{}", long.trim_start_matches('-').to_uppercase().replace('-', "_"))
+}
+
 impl Config {
     pub fn usage() {
-        eprintln!("Usage: mchttp [-v] [-l bind_addr] [-t file/dir] [-r file] files");
-        eprintln!("       -v            verbose\n");
-        eprintln!("       -l            address to bind and listen on ({})", &DEFAULT_BIND_ADDR);
-        eprintln!("       -t file.key   use TLS with file.key and file.crt as default site");
-        eprintln!("       -t file.crt   use TLS with file.key and file.crt as default site");
-        eprintln!("       -t /etc/letsencrypt/live");
-        eprintln!("                     use TLS for all sites specified in LetsEncrypt/Certbot directory");
-        eprintln!("                     (ensure readable permissions for UID or GID server runs as)");
+        println!("Usage: mchttp [options] [file...]");
+        println!("Every option can also be set in the environment, named after its long form:");
+        println!("--tls-warn-days is {}. Switches take true or false there, and repeatable", environment_variable("--tls-warn-days"));
+        println!("options several values separated by spaces. The command line overrides the");
+        println!("environment, which overrides the configuration file.\n");
+        println!("       -c, --config file.toml");
+        println!("                     read settings, sites (vhosts) and routes from a TOML file");
+        println!("       --check       check the configuration (roots, TLS identities, handlers, bind");
+        println!("                     addresses), print the effective listeners, routes and vhosts, and");
+        println!("                     exit non-zero listing every problem found");
+        println!("       -v, --verbose verbose logging");
+        println!("       -l, --listen addr");
+        println!("                     address to bind and listen on ({})", &DEFAULT_BIND_ADDR);
+        UnixSocket::usage();
+        println!("       -r, --root dir");
+        println!("                     serve this directory (or file) at /");
+        println!("       -d, --data-dir dir");
+        println!("                     data directory: served at / (or dir/name for each site name) when");
+        println!("                     there's no root, and home of -t auto and WebSocket tail files");
+        println!("       --file file   serve file at /<file>, as file arguments do; repeatable");
+        println!("       -t, --tls file.key|file.crt");
+        println!("                     use TLS with file.key and file.crt as default site");
+        println!("       -t, --tls file.p12");
+        println!("                     use TLS with PKCS#12 bundle file.p12 (or .pfx) as default site");
+        println!("       -t, --tls file.pem");
+        println!("                     use TLS with chain and key combined in file.pem as default site");
+        println!("       -t, --tls /etc/letsencrypt/live");
+        println!("                     use TLS for all sites specified in LetsEncrypt/Certbot directory");
+        println!("                     (ensure readable permissions for UID or GID server runs as)");
+        println!("                     (each site directory may instead hold a .p12/.pfx bundle or a combined .pem)");
+        println!("       -t, --tls auto");
+        println!("                     use TLS with a self-signed certificate generated at startup for");
+        println!("                     localhost, this host's name, every vhost in the data directory and every");
+        println!("                     site and alias in the configuration file");
+        println!("       -p, --tls-persist");
+        println!("                     persist the -t auto certificate in the data directory (-d; reused on restart)");
+        println!("       -k, --tls-passphrase-file file");
+        println!("                     read the passphrase for encrypted keys/PKCS#12 bundles from file");
+        println!("                     (or set MCHTTP_TLS_PASSPHRASE)");
+        println!("       -w, --tls-warn-days days,...");
+        println!("                     warn when a certificate is within these many days of expiry ({:?})", &DEFAULT_TLS_WARN_DAYS);
+        println!("       -N, --tls-allow-name-mismatch");
+        println!("                     load certificates whose names don't cover their -t directory's name (flagged)");
+        println!("       -S, --tls-status-path path");
+        println!("                     serve a JSON report of loaded TLS identities at this URL path");
+        println!("       -O, --ocsp    fetch and refresh OCSP responses from each certificate's responder");
+        println!("                     (otherwise a name.ocsp/fullchain.ocsp file next to the certificate is stapled)");
+        TlsOptions::usage();
+        println!("       -H, --h2c     accept HTTP/2 with prior knowledge (h2c) on plain HTTP");
+        println!("       -Q, --quic addr");
+        println!("                     UDP address for an HTTP/3 (QUIC) listener, advertised with Alt-Svc (needs -t)");
+        println!("       -W, --websocket path=echo");
+        println!("                     accept WebSocket connections at path and echo messages back");
+        println!("       -W, --websocket path=tail:file");
+        println!("                     accept WebSocket connections at path and stream lines appended to");
+        println!("                     file (relative to the data directory) as they're written");
+        println!("       -E, --events  serve Server-Sent Events at {} announcing changes to files under", EVENTS_PATH);
+        println!("                     the site root (watched with inotify)");
+        println!("       --dev         development mode: implies -E, injects a live reload script into HTML");
+        println!("                     pages and sends Cache-Control: no-store on every response");
+        ProxyRoute::usage();
+        println!("       -C, --cgi /prefix=dir");
+        println!("                     run executables in dir as CGI/1.1 scripts for /prefix/script[/path/info]");
+        println!("       -F, --fastcgi .ext=host:port");
+        println!("       -F, --fastcgi .ext=unix:/path/to/socket");
+        println!("                     pass requests for .ext files under the site root to a FastCGI application");
+        println!("                     (e.g. -F .php=unix:/run/php/php-fpm.sock)");
+        println!("       --proxy-protocol 10.0.0.0/8,192.0.2.1,...");
+        println!("                     expect a PROXY protocol v1/v2 header on connections from these");
+        println!("                     networks (load balancers), and use the client address it carries");
+        println!("       --trusted-proxies 10.0.0.0/8,192.0.2.1,...");
+        println!("                     take the client address and scheme of requests from these networks");
+        println!("                     (reverse proxies) from Forwarded or X-Forwarded-For/X-Forwarded-Proto");
+        println!("       --reload-path path");
+        println!("                     reload the configuration on a POST to this URL path, as on SIGHUP;");
+        println!("                     only over a -l unix: socket, from the server's user or root");
+        println!("       --drain-timeout seconds");
+        println!("                     on SIGTERM/SIGINT stop accepting and give open connections this long");
+        println!("                     to finish before exiting ({})", DEFAULT_DRAIN_TIMEOUT.as_secs());
+        println!("       -u, --user name|uid");
+        println!("                     once listening with identities loaded, switch to this user (and its");
+        println!("                     groups); TLS files are read from then on by a helper left running as root");
+        println!("       -g, --group name|gid");
+        println!("                     switch to this group rather than the user's own");
+        println!("       --chroot      once listening, confine the server to the data directory (needs -d);");
+        println!("                     no configuration reloads or upgrades after that");
+        println!("       --allow-root  keep running as root without -u (refused otherwise)");
+        println!("       -h, --help    show this help");
+        println!("       -V, --version show the version and the commit it was built from");
+        // println!(" /usr/bin/openssl req -x509 -newkey rsa:4096 -keyout key.pem -out cert.pem -days 365 -nodes");
+        //println!(" /usr/bin/openssl pkcs12 -export -out cert.p12 -inkey key.pem -in cert.pem");
 
-        eprintln!("Generate self-signed key/cert like this:");
-        eprintln!("/usr/bin/openssl req -x509 -newkey rsa:4096 -keyout mykey.key -out mycert.crt -days 30 -nodes -addext \"subjectAltName = DNS:localhost\"");
-        // eprintln!(" /usr/bin/openssl req -x509 -newkey rsa:4096 -keyout key.pem -out cert.pem -days 365 -nodes");
-        //eprintln!(" /usr/bin/openssl pkcs12 -export -out cert.p12 -inkey key.pem -in cert.pem");
+        process::exit(0);
+    }
 
-        process::exit(1);
+    pub fn version() {
+        println!("{PKG_NAME} {PKG_VERSION}");
+        println!("{COMMIT_ID}");
+        process::exit(0);
     }
 
     pub fn cmdline() -> Config {
-        let mut config = Config::default();
+        match Config::parse() {
+            Ok(config) => config,
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("{error}");
+                }
+                eprintln!("{} configuration error(s)", errors.len());
+                process::exit(1);
+            }
+        }
+    }
+
+    // The command line and environment over the configuration file, as at
+    // startup; on a reload the file may have changed, and its errors are
+    // returned rather than fatal.
+    pub fn parse() -> Result<Config, Vec<String>> {
+        let (config, errors) = Config::parse_all();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The MCHTTP_* environment variables as options, then the command line
+    // (without the program name), so that the command line overrides the
+    // environment
+    fn arguments(
+        environment: impl Fn(&str) -> Result<String, env::VarError>,
+        mut args: impl Iterator<Item = String>,
+        errors: &mut Vec<String>,
+    ) -> Vec<Argument> {
+        let mut arguments = Vec::new();
+
+        for (flag, long, takes, settable) in OPTIONS {
+            let name = environment_variable(long);
+            if !settable {
+                continue;
+            }
+            let value = match environment(&name) {
+                Ok(value) => value,
+                Err(env::VarError::NotPresent) => continue,
+                Err(e) => {
+                    errors.push(format!("{name}: {e}"));
+                    continue;
+                }
+            };
+            let argument = |value: Option<&str>| Argument {
+                flag,
+                value: value.map(String::from),
+                given: name.clone(),
+                from_environment: true,
+            };
+            match takes {
+                Takes::Nothing => match value.to_lowercase().as_str() {
+                    "1" | "true" | "yes" | "on" => arguments.push(argument(None)),
+                    "0" | "false" | "no" | "off" | "" => (),
+                    _ => errors.push(format!("{name}={value}: expected true or false")),
+                },
+                Takes::Value(_) => arguments.push(argument(Some(&value))),
+                Takes::Values(_) => arguments.extend(value.split_whitespace().map(|v| argument(Some(v)))),
+            }
+        }
+
+        let mut options_done = false;
+        while let Some(a) = args.next() {
+            // -- ends the options: whatever follows is a file, even -x
+            if a == "--" && !options_done {
+                options_done = true;
+                continue;
+            }
+            if options_done || a == "-" || !a.starts_with('-') {
+                arguments.push(Argument {
+                    flag: "--file",
+                    value: Some(a),
+                    given: String::new(),
+                    from_environment: false,
+                });
+                continue;
+            }
+
+            // --long=value as well as --long value
+            let (given, inline) = match a.split_once('=') {
+                Some((given, value)) if a.starts_with("--") => (String::from(given), Some(String::from(value))),
+                _ => (a.clone(), None),
+            };
+            let Some(&(flag, _, takes, _)) = OPTIONS.iter().find(|(flag, long, _, _)| given == *flag || given == *long) else {
+                errors.push(format!("{given}: unknown option (see --help)"));
+                continue;
+            };
+            let value = match (takes, inline) {
+                (Takes::Nothing, None) => None,
+                (Takes::Nothing, Some(_)) => {
+                    errors.push(format!("{given}: doesn't take a value"));
+                    continue;
+                }
+                (_, Some(value)) => Some(value),
+                (Takes::Value(expected) | Takes::Values(expected), None) => match args.next() {
+                    Some(value) => Some(value),
+                    None => {
+                        errors.push(format!("{given}: expected {expected}"));
+                        continue;
+                    }
+                },
+            };
+            arguments.push(Argument {
+                flag,
+                value,
+                given,
+                from_environment: false,
+            });
+        }
+        arguments
+    }
 
+    // Everything that could be made of the command line, environment and
+    // configuration file, along with every problem found on the way (for
+    // --check)
+    pub fn parse_all() -> (Config, Vec<String>) {
         let mut args = env::args();
         args.next(); // blow off the first argument
-        while let Some(a) = args.next() {
-            match a.as_str() {
+        Config::parse_arguments(|name| env::var(name), args)
+    }
+
+    fn parse_arguments(
+        environment: impl Fn(&str) -> Result<String, env::VarError>,
+        args: impl Iterator<Item = String>,
+    ) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        let arguments = Config::arguments(environment, args, &mut errors);
+
+        // The configuration file goes first, whatever its position, so that
+        // the rest overrides it
+        if let Some(argument) = arguments.iter().rev().find(|argument| argument.flag == "-c") {
+            if let Err(file_errors) = config.load_file(Path::new(argument.value.as_deref().unwrap_or_default())) {
+                errors.extend(file_errors);
+            }
+        }
+
+        for argument in &arguments {
+            match argument.flag {
+                "-c" => {
+                    continue; // already loaded
+                },
+                "--check" => {
+                    continue; // seen by main
+                },
                 "-v" => {
                     config.verbose = true;
                     continue;
                 },
                 "-l" => {
-                    config.bind_addr = SocketAddr::from_str(
-                        args.next()
-                            .expect("expected bind address specification")
-                            .as_str(),
-                    )
-                    .expect("failued to parse bind address specification");
+                    if let Some(addr) = argument.value(&mut errors, BindAddr::parse) {
+                        config.bind_addr = addr;
+                        config.listen.clear();
+                    }
                     continue;
                 },
                 "-t" => {
-                    let file = args.next().expect("expected path to TLS certificate/identity store");
-                    std::fs::exists(&file).expect("path to TLS certificate/identity store should exist and be readble");
-                    config.tls = Some(file);
+                    config.tls = argument
+                        .value(&mut errors, |file| {
+                            if file != AUTO_TLS && tls_file_kind(Path::new(file)).is_err() {
+                                return Err(Error::msg("no such file or directory"));
+                            }
+                            Ok(String::from(file))
+                        })
+                        .or(config.tls);
+                    continue;
+                },
+                "-p" => {
+                    config.tls_persist = true;
+                    continue;
+                },
+                "-k" => {
+                    config.tls_passphrase_file = argument.value(&mut errors, |v| Ok(PathBuf::from(v))).or(config.tls_passphrase_file);
+                    continue;
+                },
+                "-w" => {
+                    if let Some(days) = argument.value(&mut errors, |v| v.split(',').map(|d| Ok(d.trim().parse::<i64>()?)).collect()) {
+                        config.tls_warn_days = days;
+                    }
+                    continue;
+                },
+                "-N" => {
+                    config.tls_allow_name_mismatch = true;
+                    continue;
+                },
+                "-S" => {
+                    config.tls_status_path = argument.value(&mut errors, |v| Ok(String::from(v))).or(config.tls_status_path);
+                    continue;
+                },
+                "-O" => {
+                    config.ocsp_fetch = true;
+                    continue;
+                },
+                "-T" => {
+                    let options = &mut config.tls_options;
+                    argument.value(&mut errors, |v| options.try_set(v));
+                    continue;
+                },
+                "-H" => {
+                    config.h2c = true;
+                    continue;
+                },
+                "-Q" => {
+                    config.quic_addr = argument.value(&mut errors, |v| Ok(SocketAddr::from_str(v)?)).or(config.quic_addr);
+                    continue;
+                },
+                "-W" => {
+                    if let Some(route) = argument.value(&mut errors, |v| {
+                        v.split_once('=')
+                            .map(|(path, handler)| (String::from(path), String::from(handler)))
+                            .ok_or_else(|| Error::msg("expected WebSocket handler in path=handler form"))
+                    }) {
+                        config.websockets.push(route);
+                    }
+                    continue;
+                },
+                "-E" => {
+                    config.events = true;
+                    continue;
+                },
+                "--dev" => {
+                    config.dev = true;
+                    config.events = true;
+                    continue;
+                },
+                "-P" => {
+                    config.proxies.extend(argument.value(&mut errors, ProxyRoute::parse));
+                    continue;
+                },
+                "-C" => {
+                    config.cgi.extend(argument.value(&mut errors, CgiMapping::parse));
+                    continue;
+                },
+                "-F" => {
+                    config.fastcgi.extend(argument.value(&mut errors, FastcgiMapping::parse));
+                    continue;
+                },
+                "--proxy-protocol" => {
+                    config.proxy_protocol.extend(argument.value(&mut errors, IpNetwork::parse_list).unwrap_or_default());
                     continue;
                 },
-                "-h" => {
+                "--trusted-proxies" => {
+                    config.trusted_proxies.extend(argument.value(&mut errors, IpNetwork::parse_list).unwrap_or_default());
+                    continue;
+                },
+                "--reload-path" => {
+                    config.reload_path = argument.value(&mut errors, |v| Ok(String::from(v))).or(config.reload_path);
+                    continue;
+                },
+                "--drain-timeout" => {
+                    if let Some(seconds) = argument.value(&mut errors, |v| Ok(v.parse::<u64>()?)) {
+                        config.drain_timeout = Duration::from_secs(seconds);
+                    }
+                    continue;
+                },
+                "-u" => {
+                    config.user = argument.value(&mut errors, |v| Ok(String::from(v))).or(config.user);
+                    continue;
+                },
+                "-g" => {
+                    config.group = argument.value(&mut errors, |v| Ok(String::from(v))).or(config.group);
+                    continue;
+                },
+                "--chroot" => {
+                    config.chroot = true;
+                    continue;
+                },
+                "--allow-root" => {
+                    config.allow_root = true;
+                    continue;
+                },
+                "-h" | "-?" => {
                     Self::usage();
                     break;
-                }
-                "-?" => {
-                    Self::usage();
+                },
+                "-V" => {
+                    Self::version();
                     break;
                 },
                 "-d" => {
-                    config.data_dir = Some(std::fs::canonicalize(
-                        args.next().expect("Expected path of data directory")
-                    ).expect("Specified data directory path does not exist"));
+                    config.data_dir = argument.value(&mut errors, |v| Ok(std::fs::canonicalize(v)?)).or(config.data_dir);
                     continue;
                 },
                 "-r" => {
-                    match std::fs::canonicalize(args.next().expect("expect root handler")) {
-                        Ok(p) => {
-                            config.files.insert(String::from("/"), p);
-                        },
-                        _ => {
-                            eprintln!("Specified root URL doesn't exist");
-                        }
+                    if let Some(p) = argument.value(&mut errors, |v| Ok(std::fs::canonicalize(v)?)) {
+                        config.files.insert(String::from("/"), p);
                     }
                     continue;
                 }
@@ -114,25 +693,119 @@ impl Config {
                 // Derive the canonical form of the path being specified
                 // If it relative to our current directory, load it into the
                 // hash with a p
-                _ => match std::fs::canonicalize(&a) {
-                    Ok(p) => {
+                _ => {
+                    if let Some(p) = argument.value(&mut errors, |v| Ok(std::fs::canonicalize(v)?)) {
+                        let a = argument.value.clone().unwrap_or_default();
                         if a.starts_with("/") {
                             config.files.insert(a, p);
                         } else {
                             config.files.insert(format!("/{}", a), p);
                         }
                     }
-                    _ => {
-                        eprintln!("Ignoring {}", &a);
-                    }
                 },
             };
         }
 
-        // Add a make-shift default here
-        // if config.files.len() == 0 {
-        //     config.files.push(PathBuf::from("/tmp/test.txt"));
-        // }
-        config
+        if config.chroot && config.data_dir.is_none() {
+            errors.push(String::from("--chroot: needs a data directory (-d) to confine the server to"));
+        }
+        if config.tls_persist && config.data_dir.is_none() {
+            errors.push(String::from("-p: needs a data directory (-d) to keep the certificate in"));
+        }
+        (config, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(environment: &[(&str, &str)], args: &[&str]) -> (Config, Vec<String>) {
+        let environment: HashMap<String, String> =
+            environment.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect();
+        Config::parse_arguments(
+            |name| environment.get(name).cloned().ok_or(env::VarError::NotPresent),
+            args.iter().map(|a| String::from(*a)),
+        )
+    }
+
+    fn flags(environment: &[(&str, &str)], args: &[&str]) -> (Vec<(&'static str, Option<String>)>, Vec<String>) {
+        let environment: HashMap<String, String> =
+            environment.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect();
+        let mut errors = Vec::new();
+        let arguments = Config::arguments(
+            |name| environment.get(name).cloned().ok_or(env::VarError::NotPresent),
+            args.iter().map(|a| String::from(*a)),
+            &mut errors,
+        );
+        (arguments.into_iter().map(|a| (a.flag, a.value)).collect(), errors)
+    }
+
+    #[test]
+    fn short_long_and_inline_values() {
+        let (arguments, errors) = flags(&[], &["-v", "--listen", "127.0.0.1:9000", "--reload-path=/reload", "index.html"]);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(
+            arguments,
+            vec![
+                ("-v", None),
+                ("-l", Some(String::from("127.0.0.1:9000"))),
+                ("--reload-path", Some(String::from("/reload"))),
+                ("--file", Some(String::from("index.html"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let (arguments, errors) = flags(&[], &["--", "-v"]);
+        assert!(errors.is_empty());
+        assert_eq!(arguments, vec![("--file", Some(String::from("-v")))]);
+    }
+
+    #[test]
+    fn environment_comes_before_command_line() {
+        let (arguments, errors) = flags(
+            &[("MCHTTP_LISTEN", "127.0.0.1:1"), ("MCHTTP_VERBOSE", "yes"), ("MCHTTP_PROXY", "/a=h:1 /b=h:2")],
+            &["-l", "127.0.0.1:2"],
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(
+            arguments,
+            vec![
+                ("-v", None),
+                ("-l", Some(String::from("127.0.0.1:1"))),
+                ("-P", Some(String::from("/a=h:1"))),
+                ("-P", Some(String::from("/b=h:2"))),
+                ("-l", Some(String::from("127.0.0.1:2"))),
+            ]
+        );
+
+        let (config, errors) = parse(&[("MCHTTP_LISTEN", "127.0.0.1:1")], &["-l", "127.0.0.1:2"]);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(config.bind_addr, BindAddr::Tcp("127.0.0.1:2".parse().unwrap()));
+    }
+
+    #[test]
+    fn argument_errors() {
+        let (_, errors) = flags(&[("MCHTTP_VERBOSE", "maybe")], &["--bogus", "-v=1", "-l"]);
+        assert_eq!(
+            errors,
+            vec![
+                "MCHTTP_VERBOSE=maybe: expected true or false",
+                "--bogus: unknown option (see --help)",
+                "-v=1: unknown option (see --help)",
+                "-l: expected bind address specification",
+            ]
+        );
+    }
+
+    #[test]
+    fn option_errors_are_collected() {
+        let (_, errors) = parse(&[], &["-l", "nowhere", "-p", "--proxy-protocol", "10.0.0.0/33"]);
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].starts_with("-l nowhere: "), "{errors:?}");
+        assert!(errors[1].starts_with("--proxy-protocol 10.0.0.0/33: "), "{errors:?}");
+        assert_eq!(errors[2], "-p: needs a data directory (-d) to keep the certificate in");
     }
 }
@@ -0,0 +1,560 @@
+use std::fmt::Display;
+use std::ops::Range;
+use toml_edit::{Document, Item, TableLike};
+use crate::*;
+
+// Reading a TOML configuration file (-c) into a Config. The document is
+// walked by hand, like the command line, so that every unknown key and bad
+// value can be reported with its line and column rather than just the first.
+struct ConfigFile<'a> {
+    path: &'a Path,
+    dir: PathBuf, // relative paths in the file are relative to it
+    text: &'a str,
+    errors: Vec<String>,
+}
+
+impl Config {
+    // Settings from the file, over the defaults; the command line is applied
+    // on top afterwards. Errors are every problem found, one per line.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), Vec<String>> {
+        let text = std::fs::read_to_string(path).map_err(|e| vec![format!("{}: {e}", path.to_string_lossy())])?;
+        let document = Document::parse(text.as_str()).map_err(|e| {
+            let position = e.span().map(|span| line_column(&text, span.start));
+            vec![match position {
+                Some((line, column)) => format!("{}:{line}:{column}: {}", path.to_string_lossy(), e.message()),
+                None => format!("{}: {}", path.to_string_lossy(), e.message()),
+            }]
+        })?;
+
+        let mut file = ConfigFile {
+            path,
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            text: &text,
+            errors: Vec::new(),
+        };
+        file.top_level(self, document.as_table());
+        if file.errors.is_empty() {
+            self.config_file = Some(std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+            Ok(())
+        } else {
+            Err(file.errors)
+        }
+    }
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+fn dotted(section: &str, key: &str) -> String {
+    if section.is_empty() {
+        String::from(key)
+    } else {
+        format!("{section}.{key}")
+    }
+}
+
+impl ConfigFile<'_> {
+    fn error(&mut self, span: Option<Range<usize>>, key: &str, message: impl Display) {
+        let location = match span {
+            Some(span) => {
+                let (line, column) = line_column(self.text, span.start);
+                format!("{}:{line}:{column}", self.path.to_string_lossy())
+            }
+            None => self.path.to_string_lossy().to_string(),
+        };
+        self.errors.push(format!("{location}: {key}: {message}"));
+    }
+
+    fn unknown(&mut self, table: &dyn TableLike, section: &str, key: &str) {
+        let span = table.key(key).and_then(|k| k.span());
+        self.error(span, &dotted(section, key), "unknown key");
+    }
+
+    fn check<T>(&mut self, key: &str, item: &Item, result: Result<T>) -> Option<T> {
+        result.map_err(|e| self.error(item.span(), key, e)).ok()
+    }
+
+    fn boolean(&mut self, key: &str, item: &Item) -> Option<bool> {
+        let value = item.as_bool();
+        if value.is_none() {
+            self.error(item.span(), key, format!("expected true or false, found {}", item.type_name()));
+        }
+        value
+    }
+
+    fn string(&mut self, key: &str, item: &Item) -> Option<String> {
+        let value = item.as_str().map(String::from);
+        if value.is_none() {
+            self.error(item.span(), key, format!("expected a string, found {}", item.type_name()));
+        }
+        value
+    }
+
+    fn integer(&mut self, key: &str, item: &Item) -> Option<u64> {
+        match item.as_integer() {
+            Some(n) if n >= 0 => Some(n as u64),
+            Some(_) => {
+                self.error(item.span(), key, "should not be negative");
+                None
+            }
+            None => {
+                self.error(item.span(), key, format!("expected an integer, found {}", item.type_name()));
+                None
+            }
+        }
+    }
+
+    // An array of strings, or a single string standing for a list of one
+    fn strings(&mut self, key: &str, item: &Item) -> Option<Vec<String>> {
+        if let Some(value) = item.as_str() {
+            return Some(vec![String::from(value)]);
+        }
+        let Some(array) = item.as_array() else {
+            self.error(item.span(), key, format!("expected an array of strings, found {}", item.type_name()));
+            return None;
+        };
+        let mut values = Vec::new();
+        for value in array.iter() {
+            match value.as_str() {
+                Some(s) => values.push(String::from(s)),
+                None => {
+                    self.error(value.span(), key, format!("expected a string, found {}", value.type_name()));
+                    return None;
+                }
+            }
+        }
+        Some(values)
+    }
+
+    fn table<'t>(&mut self, key: &str, item: &'t Item) -> Option<&'t dyn TableLike> {
+        let table = item.as_table_like();
+        if table.is_none() {
+            self.error(item.span(), key, format!("expected a table, found {}", item.type_name()));
+        }
+        table
+    }
+
+    // An existing path, relative to the configuration file's directory
+    fn path(&mut self, key: &str, item: &Item) -> Option<PathBuf> {
+        let path = self.string(key, item)?;
+        let path = self.dir.join(path);
+        let result = std::fs::canonicalize(&path).map_err(|e| anyhow::anyhow!("{}: {e}", path.to_string_lossy()));
+        self.check(key, item, result)
+    }
+
+    fn networks(&mut self, key: &str, item: &Item) -> Option<Vec<IpNetwork>> {
+        let specs = self.strings(key, item)?;
+        let result = specs.iter().map(|spec| IpNetwork::parse_list(spec)).collect::<Result<Vec<_>>>();
+        self.check(key, item, result.map(|lists| lists.concat()))
+    }
+
+    fn socket_address(&mut self, key: &str, item: &Item, spec: &str) -> Option<SocketAddr> {
+        let result = SocketAddr::from_str(spec).map_err(|e| anyhow::anyhow!("{spec}: {e}"));
+        self.check(key, item, result)
+    }
+
+    fn bind_address(&mut self, key: &str, item: &Item, spec: &str) -> Option<BindAddr> {
+        let result = BindAddr::parse(spec).map_err(|e| anyhow::anyhow!("{spec}: {e}"));
+        self.check(key, item, result)
+    }
+
+    fn top_level(&mut self, config: &mut Config, table: &dyn TableLike) {
+        for (key, item) in table.iter() {
+            match key {
+                "verbose" => config.verbose = self.boolean(key, item).unwrap_or(config.verbose),
+                "listen" => {
+                    let Some(specs) = self.strings(key, item) else { continue };
+                    let addresses: Vec<BindAddr> =
+                        specs.iter().filter_map(|spec| self.bind_address(key, item, spec)).collect();
+                    if let Some((first, rest)) = addresses.split_first() {
+                        config.bind_addr = first.clone();
+                        config.listen = rest.to_vec();
+                    }
+                }
+                "data_dir" => config.data_dir = self.path(key, item).or(config.data_dir.take()),
+                "root" => {
+                    if let Some(path) = self.path(key, item) {
+                        config.files.insert(String::from("/"), path);
+                    }
+                }
+                "files" => {
+                    let Some(files) = self.table(key, item) else { continue };
+                    for (url, file) in files.iter() {
+                        let name = dotted(key, url);
+                        if !url.starts_with('/') {
+                            self.error(files.key(url).and_then(|k| k.span()), &name, "URL path should start with /");
+                        } else if let Some(path) = self.path(&name, file) {
+                            config.files.insert(String::from(url), path);
+                        }
+                    }
+                }
+                "dev" => {
+                    if let Some(dev) = self.boolean(key, item) {
+                        config.dev = dev;
+                        config.events |= dev;
+                    }
+                }
+                "events" => config.events |= self.boolean(key, item).unwrap_or(false),
+                "h2c" => config.h2c = self.boolean(key, item).unwrap_or(config.h2c),
+                "quic" => {
+                    if let Some(spec) = self.string(key, item) {
+                        config.quic_addr = self.socket_address(key, item, &spec).or(config.quic_addr);
+                    }
+                }
+                "proxy_protocol" => config.proxy_protocol = self.networks(key, item).unwrap_or_default(),
+                "trusted_proxies" => config.trusted_proxies = self.networks(key, item).unwrap_or_default(),
+                "reload_path" => config.reload_path = self.string(key, item),
+                "drain_timeout" => {
+                    if let Some(seconds) = self.integer(key, item) {
+                        config.drain_timeout = Duration::from_secs(seconds);
+                    }
+                }
+                "user" => config.user = self.string(key, item).or(config.user.take()),
+                "group" => config.group = self.string(key, item).or(config.group.take()),
+                "chroot" => config.chroot = self.boolean(key, item).unwrap_or(config.chroot),
+                "allow_root" => config.allow_root = self.boolean(key, item).unwrap_or(config.allow_root),
+                "tls" => {
+                    if let Some(tls) = self.table(key, item) {
+                        self.tls(config, tls);
+                    }
+                }
+                "limits" => {
+                    if let Some(limits) = self.table(key, item) {
+                        self.limits(config, limits);
+                    }
+                }
+                "route" => match item.as_array_of_tables() {
+                    Some(routes) => {
+                        for route in routes.iter() {
+                            self.route(config, route);
+                        }
+                    }
+                    None => self.error(item.span(), key, "expected [[route]] sections"),
+                },
+                "vhost" => {
+                    let Some(vhosts) = self.table(key, item) else { continue };
+                    for (name, vhost) in vhosts.iter() {
+                        let section = dotted(key, name);
+                        if let Some(vhost) = self.table(&section, vhost) {
+                            self.vhost(config, &section, name, vhost);
+                        }
+                    }
+                }
+                _ => self.unknown(table, "", key),
+            }
+        }
+    }
+
+    fn tls(&mut self, config: &mut Config, table: &dyn TableLike) {
+        for (key, item) in table.iter() {
+            let name = dotted("tls", key);
+            match key {
+                "identities" => {
+                    config.tls = match self.string(&name, item) {
+                        Some(auto) if auto == AUTO_TLS => Some(auto),
+                        Some(_) => self.path(&name, item).map(|path| path.to_string_lossy().to_string()),
+                        None => None,
+                    }
+                }
+                "persist" => config.tls_persist = self.boolean(&name, item).unwrap_or(false),
+                "passphrase_file" => config.tls_passphrase_file = self.path(&name, item),
+                "warn_days" => {
+                    let days = item.as_array().map(|array| array.iter().map(|d| d.as_integer()).collect::<Option<Vec<_>>>());
+                    match days {
+                        Some(Some(days)) => config.tls_warn_days = days,
+                        _ => self.error(item.span(), &name, "expected an array of integers"),
+                    }
+                }
+                "allow_name_mismatch" => config.tls_allow_name_mismatch = self.boolean(&name, item).unwrap_or(false),
+                "status_path" => config.tls_status_path = self.string(&name, item),
+                "ocsp" => config.ocsp_fetch = self.boolean(&name, item).unwrap_or(false),
+                "options" => {
+                    let Some(options) = self.table(&name, item) else { continue };
+                    for (option, option_item) in options.iter() {
+                        let option_name = dotted(&name, option);
+                        let value = match option_item.as_integer() {
+                            Some(n) => n.to_string(),
+                            None => match self.strings(&option_name, option_item) {
+                                Some(values) => values.join(","),
+                                None => continue,
+                            },
+                        };
+                        let result = config.tls_options.try_set(&format!("{option}={value}"));
+                        self.check(&option_name, option_item, result);
+                    }
+                }
+                _ => self.unknown(table, "tls", key),
+            }
+        }
+    }
+
+    fn limits(&mut self, config: &mut Config, table: &dyn TableLike) {
+        for (key, item) in table.iter() {
+            let name = dotted("limits", key);
+            if !matches!(key, "header_timeout" | "max_header_lines" | "max_line_bytes" | "max_upload") {
+                self.unknown(table, "limits", key);
+                continue;
+            }
+            let Some(value) = self.integer(&name, item) else { continue };
+            if value == 0 {
+                self.error(item.span(), &name, "should be at least 1");
+                continue;
+            }
+            match key {
+                "header_timeout" => config.limits.header_timeout = Duration::from_secs(value),
+                "max_header_lines" => config.limits.max_header_lines = value as usize,
+                "max_line_bytes" => config.limits.max_line_bytes = value as usize,
+                _ => config.limits.max_upload = value,
+            }
+        }
+    }
+
+    // One [[route]]: a proxy, CGI, FastCGI or WebSocket mapping, built into
+    // the same form as its command line flag
+    fn route(&mut self, config: &mut Config, table: &toml_edit::Table) {
+        let kinds: Vec<&str> = ["proxy", "cgi", "fastcgi", "websocket"]
+            .into_iter()
+            .filter(|kind| table.contains_key(kind))
+            .collect();
+        let [kind] = kinds.as_slice() else {
+            self.error(table.span(), "route", "needs exactly one of proxy, cgi, fastcgi or websocket");
+            return;
+        };
+        let allowed: &[&str] = match *kind {
+            "proxy" => &["prefix", "proxy", "policy", "health", "interval", "fails", "eject", "keepalive"],
+            "cgi" => &["prefix", "cgi"],
+            "fastcgi" => &["extension", "fastcgi"],
+            _ => &["path", "websocket"],
+        };
+        let mut valid = true;
+        for (key, _) in table.iter() {
+            if !allowed.contains(&key) {
+                self.error(table.key(key).and_then(|k| k.span()), &dotted("route", key), format!("unknown key for a {kind} route"));
+                valid = false;
+            }
+        }
+        let required = allowed[0];
+        let Some(location) = table.get(required) else {
+            self.error(table.span(), "route", format!("a {kind} route needs {required}"));
+            return;
+        };
+        let (Some(location), Some(target)) = (
+            self.string(&dotted("route", required), location),
+            self.string(&dotted("route", kind), &table[kind]),
+        ) else {
+            return;
+        };
+        if !valid {
+            return;
+        }
+
+        let item = &table[kind];
+        match *kind {
+            "proxy" => {
+                let mut spec = format!("{location}={target}");
+                for option in &allowed[2..] {
+                    if let Some(value) = table.get(option) {
+                        let value = match value.as_integer() {
+                            Some(n) => n.to_string(),
+                            None => match self.string(&dotted("route", option), value) {
+                                Some(value) => value,
+                                None => return,
+                            },
+                        };
+                        spec.push_str(&format!(";{option}={value}"));
+                    }
+                }
+                if let Some(route) = self.check("route.proxy", item, ProxyRoute::parse(&spec)) {
+                    config.proxies.push(route);
+                }
+            }
+            "cgi" => {
+                let dir = self.dir.join(&target);
+                let result = CgiMapping::parse(&format!("{location}={}", dir.to_string_lossy()));
+                if let Some(mapping) = self.check("route.cgi", item, result) {
+                    config.cgi.push(mapping);
+                }
+            }
+            "fastcgi" => {
+                if let Some(mapping) = self.check("route.fastcgi", item, FastcgiMapping::parse(&format!("{location}={target}"))) {
+                    config.fastcgi.push(mapping);
+                }
+            }
+            _ => config.websockets.push((location, target)),
+        }
+    }
+
+    fn vhost(&mut self, config: &mut Config, section: &str, name: &str, table: &dyn TableLike) {
+        let mut vhost = VirtualHost {
+            name: name.to_lowercase(),
+            ..VirtualHost::default()
+        };
+        let mut anonymous_upload = false;
+        for (key, item) in table.iter() {
+            let key_name = dotted(section, key);
+            match key {
+                "root" => vhost.root = self.path(&key_name, item),
+                "aliases" => {
+                    vhost.aliases = self.strings(&key_name, item).unwrap_or_default().iter().map(|a| a.to_lowercase()).collect()
+                }
+                "index" => vhost.index = self.strings(&key_name, item).unwrap_or_default(),
+                "headers" => {
+                    let Some(headers) = self.table(&key_name, item) else { continue };
+                    for (header, value_item) in headers.iter() {
+                        let header_name = dotted(&key_name, header);
+                        let Some(value) = self.string(&header_name, value_item) else { continue };
+                        // Anything else would end up as a broken or extra header line
+                        let token = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+                        if header.is_empty() || !header.bytes().all(token) {
+                            let span = headers.key(header).and_then(|k| k.span());
+                            self.error(span, &header_name, "not a valid header name");
+                        } else if value.chars().any(|c| c.is_control() && c != '\t') {
+                            self.error(value_item.span(), &header_name, "line breaks and control characters aren't allowed");
+                        } else {
+                            vhost.headers.push((String::from(header), value));
+                        }
+                    }
+                }
+                "autoindex" => vhost.autoindex = self.boolean(&key_name, item).unwrap_or(false),
+                "upload" => vhost.upload = self.boolean(&key_name, item).unwrap_or(false),
+                "anonymous_upload" => anonymous_upload = self.boolean(&key_name, item).unwrap_or(false),
+                "auth" => {
+                    let Some(auth) = self.table(&key_name, item) else { continue };
+                    let mut realm = String::from(name);
+                    let mut users = None;
+                    for (auth_key, auth_item) in auth.iter() {
+                        let auth_name = dotted(&key_name, auth_key);
+                        match auth_key {
+                            "realm" => realm = self.string(&auth_name, auth_item).unwrap_or(realm),
+                            "users" => {
+                                users = self
+                                    .path(&auth_name, auth_item)
+                                    .and_then(|path| self.check(&auth_name, auth_item, BasicAuth::load(&realm, &path)))
+                            }
+                            _ => self.unknown(auth, &key_name, auth_key),
+                        }
+                    }
+                    match users {
+                        Some(mut users) => {
+                            users.realm = realm;
+                            vhost.auth = Some(users);
+                        }
+                        None if !auth.contains_key("users") => self.error(item.span(), &key_name, "needs a users file"),
+                        None => (),
+                    }
+                }
+                _ => self.unknown(table, section, key),
+            }
+        }
+        // Anyone could overwrite the site otherwise, so that has to be asked for
+        if vhost.upload && !table.contains_key("auth") && !anonymous_upload {
+            let span = table.key("upload").and_then(|k| k.span());
+            self.error(span, &dotted(section, "upload"), "needs auth, or anonymous_upload = true");
+        }
+        if config.vhosts.iter().any(|other| other.name == vhost.name) {
+            self.error(None, section, "site defined twice");
+        }
+        config.vhosts.push(vhost);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, text: &str) -> (Config, Result<(), Vec<String>>) {
+        let dir = std::env::temp_dir().join(format!("mchttp-configfile-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mchttp.toml");
+        std::fs::write(&path, text).unwrap();
+        let mut config = Config::default();
+        let result = config.load_file(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        (config, result.map_err(|errors| {
+            let prefix = format!("{}:", path.to_string_lossy());
+            errors.iter().map(|e| e.strip_prefix(&prefix).unwrap_or(e).to_string()).collect()
+        }))
+    }
+
+    #[test]
+    fn settings_are_applied() {
+        let (config, result) = load(
+            "settings",
+            "verbose = true\nlisten = [\"127.0.0.1:9000\", \"127.0.0.1:9001\"]\n[limits]\nmax_header_lines = 50\n",
+        );
+        assert_eq!(result, Ok(()));
+        assert!(config.verbose);
+        assert_eq!(config.bind_addr, BindAddr::Tcp("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(config.listen, vec![BindAddr::Tcp("127.0.0.1:9001".parse().unwrap())]);
+        assert_eq!(config.limits.max_header_lines, 50);
+    }
+
+    #[tokio::test]
+    async fn files_are_served() {
+        let dir = std::env::temp_dir().join(format!("mchttp-configfile-{}-files", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("robots.txt"), "User-agent: *\n").unwrap();
+        std::fs::write(dir.join("mchttp.toml"), "files = { \"/robots.txt\" = \"robots.txt\" }\n").unwrap();
+        let mut config = Config::default();
+        assert_eq!(config.load_file(&dir.join("mchttp.toml")), Ok(()));
+
+        let (mut client, mut server_io) = tokio::io::duplex(4096);
+        let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        let server = spawn(with_test_config(config, async move { process(&mut server_io, peer, None).await }));
+        client.write_all(b"GET /robots.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\n"), "{response}");
+    }
+
+    #[test]
+    fn every_error_with_its_position() {
+        let (_, result) = load(
+            "errors",
+            "verbose = \"yes\"\nbogus = 1\n[limits]\nmax_upload = -1\n[[route]]\nprefix = \"/x\"\n",
+        );
+        assert_eq!(
+            result,
+            Err(vec![
+                String::from("1:11: verbose: expected true or false, found string"),
+                String::from("2:1: bogus: unknown key"),
+                String::from("4:14: limits.max_upload: should not be negative"),
+                String::from("5:1: route: needs exactly one of proxy, cgi, fastcgi or websocket"),
+            ])
+        );
+    }
+
+    #[test]
+    fn unsafe_settings() {
+        let (_, result) = load(
+            "unsafe",
+            "[limits]\nheader_timeout = 0\n[vhost.\"a\"]\nupload = true\nheaders = { \"X Bad\" = \"1\", \"X-Split\" = \"1\\r\\nSet-Cookie: a=b\" }\n",
+        );
+        assert_eq!(
+            result,
+            Err(vec![
+                String::from("2:18: limits.header_timeout: should be at least 1"),
+                String::from("5:13: vhost.a.headers.X Bad: not a valid header name"),
+                String::from("5:40: vhost.a.headers.X-Split: line breaks and control characters aren't allowed"),
+                String::from("4:1: vhost.a.upload: needs auth, or anonymous_upload = true"),
+            ])
+        );
+
+        let (config, result) = load("anonymous", "[vhost.\"a\"]\nupload = true\nanonymous_upload = true\n");
+        assert_eq!(result, Ok(()));
+        assert!(config.vhosts[0].upload);
+    }
+
+    #[test]
+    fn syntax_error_position() {
+        let (_, result) = load("syntax", "verbose = true\nlisten = [\n");
+        assert_eq!(result, Err(vec![String::from("2:11: unclosed array, expected `]`")]));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::*;
+
+pub const EVENTS_PATH: &str = "/__events";
+
+// Editors tend to produce a burst of writes/renames per save; changes seen
+// within this window are sent together.
+const EVENTS_COALESCE: Duration = Duration::from_millis(100);
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+const EVENTS_RETRY_MS: u32 = 1000;
+const EVENTS_CHANNEL_SIZE: usize = 1024;
+
+// Injected into HTML in --dev mode: reload on any change, and after the
+// server comes back from a restart.
+const LIVE_RELOAD_SCRIPT: &str = "<script>(function(){var down=false,es=new EventSource(\"/__events\");\
+es.addEventListener(\"change\",function(){location.reload();});\
+es.onerror=function(){down=true;};\
+es.onopen=function(){if(down)location.reload();};})();</script>\n";
+
+// Changed paths under the watched roots, and the watcher producing them with
+// the roots it watches (kept here so it lives as long as the process).
+type RootWatcher = (RecommendedWatcher, Vec<PathBuf>);
+static FILE_EVENTS: OnceLock<(Sender<PathBuf>, Mutex<RootWatcher>)> = OnceLock::new();
+
+// Watch the data directory (or the current directory) recursively with
+// inotify, and the root of every site outside it. Called once at startup
+// when -E is given, or by the first reload that turns it on.
+pub fn start_file_watcher() -> Result<()> {
+    let (sender, _) = broadcast::channel::<PathBuf>(EVENTS_CHANNEL_SIZE);
+
+    let event_sender = sender.clone();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                for path in event.paths {
+                    // No receivers is fine: nobody's listening right now
+                    let _ = event_sender.send(chroot_path(&path).into_owned());
+                }
+            }
+        }
+        Err(e) => eprintln!("Events: watch error: {e}"),
+    })?;
+
+    FILE_EVENTS
+        .set((sender, Mutex::new((watcher, Vec::new()))))
+        .map_err(|_| Error::msg("file watcher already started"))?;
+    update_file_watches()
+}
+
+pub fn file_watcher_started() -> bool {
+    FILE_EVENTS.get().is_some()
+}
+
+// Watch the roots the current configuration serves from, and stop watching
+// those it no longer does. Run again on every reload.
+pub fn update_file_watches() -> Result<()> {
+    let Some((_, watching)) = FILE_EVENTS.get() else {
+        return Ok(());
+    };
+    let mut roots = vec![std::fs::canonicalize(site_root(&None))?];
+    for vhost in &CONFIG.vhosts {
+        let root = site_root(&Some(vhost.name.clone()));
+        match std::fs::canonicalize(&root) {
+            Ok(root) => roots.push(root),
+            Err(e) => eprintln!("Events: {}: {e}", root.to_string_lossy()),
+        }
+    }
+    // A root inside another is already covered by its recursive watch
+    roots.sort_by_key(|root| root.components().count());
+    let mut wanted: Vec<PathBuf> = Vec::new();
+    for root in roots {
+        if !wanted.iter().any(|outer| root.starts_with(outer)) {
+            wanted.push(root);
+        }
+    }
+
+    let mut watching = watching.lock().unwrap();
+    let (watcher, watched) = &mut *watching;
+    for root in watched.iter().filter(|root| !wanted.contains(root)) {
+        let _ = watcher.unwatch(root);
+        eprintln!("Events: no longer watching {}", root.to_string_lossy());
+    }
+    watched.retain(|root| wanted.contains(root));
+    for root in wanted {
+        if !watched.contains(&root) {
+            watcher.watch(&root, RecursiveMode::Recursive)?;
+            eprintln!("Events: watching {} for changes", root.to_string_lossy());
+            watched.push(root);
+        }
+    }
+    Ok(())
+}
+
+// URL path of a changed file relative to a site root, skipping editor
+// droppings (dotfiles, backup~ files) nobody would want to reload for.
+fn changed_url(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut url = String::new();
+    for component in relative.components() {
+        let component = component.as_os_str().to_string_lossy();
+        if component.starts_with('.') || component.ends_with('~') {
+            return None;
+        }
+        url.push('/');
+        url.push_str(&component);
+    }
+    Some(if url.is_empty() { String::from("/") } else { url })
+}
+
+// Server-Sent Events stream of "change" events, one per changed file under
+// the site root of the request, until the client goes away.
+pub async fn request_handler_events<S: AsyncRead + AsyncWrite + Unpin>(
+    mut request: HttpRequest<S>,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let Some((sender, _)) = FILE_EVENTS.get() else {
+        send_response(&mut request, "text/plain", None).await?;
+        request.stream.flush().await?;
+        return Ok(());
+    };
+    let mut changes = sender.subscribe();
+    let root = tokio::fs::canonicalize(site_root(&request.server_name)).await?;
+    let server_name = request.server_name.clone();
+
+    send_stream_header(&mut request, "text/event-stream").await?;
+    request.stream.write_all(format!("retry: {}\n\n", EVENTS_RETRY_MS).as_bytes()).await?;
+    request.stream.flush().await?;
+    println!(
+        "Request (server {}) client {} {} {} (events for {})",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        root.to_string_lossy()
+    );
+
+    let mut keepalive = tokio::time::interval(EVENTS_KEEPALIVE);
+    keepalive.tick().await;
+    let mut discard = [0u8; 512];
+    let result: Result<()> = loop {
+        tokio::select! {
+            change = changes.recv() => {
+                let mut urls = BTreeSet::new();
+                match change {
+                    Ok(path) => urls.extend(changed_url(&root, &path).filter(|_| !in_other_site(&server_name, &path))),
+                    // Missed some: tell the client something changed
+                    Err(RecvError::Lagged(_)) => {
+                        urls.insert(String::from("/"));
+                    }
+                    Err(RecvError::Closed) => break Ok(()),
+                }
+                let deadline = sleep(EVENTS_COALESCE);
+                tokio::pin!(deadline);
+                loop {
+                    tokio::select! {
+                        change = changes.recv() => match change {
+                            Ok(path) => urls.extend(changed_url(&root, &path).filter(|_| !in_other_site(&server_name, &path))),
+                            Err(RecvError::Lagged(_)) => {
+                                urls.insert(String::from("/"));
+                            }
+                            Err(RecvError::Closed) => break,
+                        },
+                        _ = &mut deadline => break,
+                    }
+                }
+                if urls.is_empty() {
+                    continue;
+                }
+                let mut events = String::new();
+                for url in &urls {
+                    events.push_str(&format!("event: change\ndata: {}\n\n", url));
+                }
+                if let Err(e) = request.stream.write_all(events.as_bytes()).await {
+                    break Err(e.into());
+                }
+                if let Err(e) = request.stream.flush().await {
+                    break Err(e.into());
+                }
+            },
+            _ = keepalive.tick() => {
+                if let Err(e) = request.stream.write_all(b": keepalive\n\n").await {
+                    break Err(e.into());
+                }
+                if let Err(e) = request.stream.flush().await {
+                    break Err(e.into());
+                }
+            },
+            _ = shutdown_started() => break Ok(()),
+            // Clients don't send anything after the request; EOF means gone
+            n = request.stream.read(&mut discard) => match n {
+                Ok(0) | Err(_) => break Ok(()),
+                Ok(_) => (),
+            },
+        }
+    };
+
+    println!(
+        "Request (server {}) client {} {} {} events closed after {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        start_time.elapsed()
+    );
+    result
+}
+
+// HTML body with the live reload script inserted before the last </body>,
+// or appended if there isn't one.
+pub fn inject_reload_script(mut html: Vec<u8>) -> Vec<u8> {
+    let position = html
+        .windows(7)
+        .rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(html.len());
+    html.splice(position..position, LIVE_RELOAD_SCRIPT.bytes());
+    html
+}
+
+// Response header line for --dev mode, where nothing should be cached
+pub fn cache_control_header() -> &'static str {
+    if CONFIG.dev {
+        "Cache-Control: no-store\r\n"
+    } else {
+        ""
+    }
+}
@@ -0,0 +1,633 @@
+use std::sync::atomic::{AtomicBool, AtomicU16};
+use std::sync::OnceLock;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use crate::*;
+
+// Record types and constants from the FastCGI 1.0 specification
+const FCGI_VERSION_1: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_ABORT_REQUEST: u8 = 2;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_GET_VALUES: u8 = 9;
+const FCGI_GET_VALUES_RESULT: u8 = 10;
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_KEEP_CONN: u8 = 1;
+const FCGI_MAX_CONTENT: usize = 65535;
+
+const FCGI_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const FCGI_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+// How long to wait for an answer to FCGI_GET_VALUES before assuming the
+// application can't multiplex
+const FCGI_VALUES_TIMEOUT: Duration = Duration::from_secs(2);
+const FCGI_MAX_IDLE_CONNECTIONS: usize = 8;
+const FCGI_STDIN_CHUNK: usize = 32 * 1024;
+
+struct Record {
+    kind: u8,
+    content: Vec<u8>,
+}
+
+type NameValuePairs = Vec<(String, String)>;
+
+// One persistent connection to the application. A reader task hands each
+// record to the request it belongs to, so requests can share the connection
+// when the application supports multiplexing.
+struct FcgiConnection {
+    writer: tokio::sync::Mutex<WriteHalf<Box<dyn IoStream>>>,
+    requests: Mutex<HashMap<u16, mpsc::UnboundedSender<Record>>>,
+    values: Mutex<Option<oneshot::Sender<NameValuePairs>>>,
+    next_id: AtomicU16,
+    closed: AtomicBool,
+}
+
+impl FcgiConnection {
+    async fn connect(upstream: &Upstream) -> Result<Arc<FcgiConnection>> {
+        let (reader, writer) = tokio::io::split(upstream.connect().await?);
+        let connection = Arc::new(FcgiConnection {
+            writer: tokio::sync::Mutex::new(writer),
+            requests: Mutex::new(HashMap::new()),
+            values: Mutex::new(None),
+            next_id: AtomicU16::new(1),
+            closed: AtomicBool::new(false),
+        });
+        spawn(Arc::clone(&connection).read_records(reader));
+        Ok(connection)
+    }
+
+    async fn read_records(self: Arc<Self>, reader: ReadHalf<Box<dyn IoStream>>) {
+        let mut reader = tokio::io::BufReader::new(reader);
+        loop {
+            let mut header = [0u8; 8];
+            if reader.read_exact(&mut header).await.is_err() {
+                break;
+            }
+            let id = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut content = vec![0u8; length + header[6] as usize];
+            if reader.read_exact(&mut content).await.is_err() {
+                break;
+            }
+            content.truncate(length);
+
+            if id == 0 {
+                if header[1] == FCGI_GET_VALUES_RESULT {
+                    if let Some(sender) = self.values.lock().unwrap().take() {
+                        let _ = sender.send(decode_pairs(&content));
+                    }
+                }
+                continue;
+            }
+            if let Some(sender) = self.requests.lock().unwrap().get(&id) {
+                let _ = sender.send(Record { kind: header[1], content });
+            }
+        }
+        // Dropping the senders tells every waiting request
+        self.closed.store(true, Ordering::Relaxed);
+        self.requests.lock().unwrap().clear();
+    }
+
+    // Ask whether requests can be multiplexed and how many at once
+    async fn capabilities(&self) -> (bool, usize) {
+        let (sender, receiver) = oneshot::channel();
+        *self.values.lock().unwrap() = Some(sender);
+        let query = encode_pairs(&[
+            (String::from("FCGI_MPXS_CONNS"), String::new()),
+            (String::from("FCGI_MAX_REQS"), String::new()),
+        ]);
+        if self.write_record(FCGI_GET_VALUES, 0, &query).await.is_err() {
+            return (false, 1);
+        }
+        match timeout(FCGI_VALUES_TIMEOUT, receiver).await {
+            Ok(Ok(values)) => {
+                let value = |name: &str| values.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+                let multiplex = value("FCGI_MPXS_CONNS") == Some("1");
+                let max_requests = value("FCGI_MAX_REQS").and_then(|v| v.parse().ok()).unwrap_or(1).max(1);
+                (multiplex, max_requests)
+            }
+            _ => (false, 1),
+        }
+    }
+
+    fn register(&self) -> (u16, mpsc::UnboundedReceiver<Record>) {
+        let mut requests = self.requests.lock().unwrap();
+        let id = loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 && !requests.contains_key(&id) {
+                break id;
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        requests.insert(id, sender);
+        (id, receiver)
+    }
+
+    fn active(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    async fn write_record(&self, kind: u8, id: u16, content: &[u8]) -> Result<()> {
+        let padding = (8 - content.len() % 8) % 8;
+        let mut record = Vec::with_capacity(8 + content.len() + padding);
+        record.extend([FCGI_VERSION_1, kind]);
+        record.extend(id.to_be_bytes());
+        record.extend((content.len() as u16).to_be_bytes());
+        record.extend([padding as u8, 0]);
+        record.extend_from_slice(content);
+        record.resize(record.len() + padding, 0);
+
+        let mut writer = self.writer.lock().await;
+        let result = async {
+            writer.write_all(&record).await?;
+            writer.flush().await
+        }
+        .await;
+        if result.is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        Ok(result?)
+    }
+
+    // A PARAMS or STDIN stream: as many records as needed, then an empty one
+    async fn write_stream(&self, kind: u8, id: u16, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(FCGI_MAX_CONTENT) {
+            self.write_record(kind, id, chunk).await?;
+        }
+        self.write_record(kind, id, &[]).await
+    }
+
+    fn close(self: &Arc<Self>) {
+        self.closed.store(true, Ordering::Relaxed);
+        let connection = Arc::clone(self);
+        spawn(async move {
+            let _ = connection.writer.lock().await.shutdown().await;
+        });
+    }
+}
+
+// A FastCGI application socket and the connections open to it
+pub struct FastcgiBackend {
+    pub upstream: Upstream,
+    connections: Mutex<Vec<Arc<FcgiConnection>>>,
+    capabilities: OnceLock<(bool, usize)>,
+    retired: AtomicBool,
+}
+
+impl std::fmt::Debug for FastcgiBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FastcgiBackend")
+            .field("upstream", &self.upstream)
+            .field("capabilities", &self.capabilities.get())
+            .finish()
+    }
+}
+
+impl FastcgiBackend {
+    // A connection with room for another request (an idle one, or any below
+    // the limit if the application multiplexes), opening one if need be.
+    async fn acquire(&'static self) -> Result<FcgiRequest> {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|c| !c.closed.load(Ordering::Relaxed));
+            let (multiplex, max_requests) = self.capabilities.get().copied().unwrap_or((false, 1));
+            for connection in connections.iter() {
+                let active = connection.active();
+                if active == 0 || (multiplex && active < max_requests) {
+                    return Ok(FcgiRequest::new(self, Arc::clone(connection)));
+                }
+            }
+        }
+
+        let connection = FcgiConnection::connect(&self.upstream).await?;
+        if self.capabilities.get().is_none() {
+            let capabilities = connection.capabilities().await;
+            if CONFIG.verbose {
+                eprintln!(
+                    "FastCGI: {}: multiplexing {}, max requests {}",
+                    &self.upstream, capabilities.0, capabilities.1
+                );
+            }
+            let _ = self.capabilities.set(capabilities);
+        }
+        let request = FcgiRequest::new(self, Arc::clone(&connection));
+        self.connections.lock().unwrap().push(connection);
+        Ok(request)
+    }
+
+    // Close idle connections beyond the limit, or all of them once retired
+    fn trim(&self) {
+        let limit = if self.retired.load(Ordering::Relaxed) { 0 } else { FCGI_MAX_IDLE_CONNECTIONS };
+        let mut connections = self.connections.lock().unwrap();
+        let mut idle = 0;
+        connections.retain(|connection| {
+            if connection.closed.load(Ordering::Relaxed) {
+                return false;
+            }
+            if connection.active() == 0 {
+                idle += 1;
+                if idle > limit {
+                    connection.close();
+                    return false;
+                }
+            }
+            true
+        });
+    }
+}
+
+// A request in flight on a connection. If it's dropped before the
+// application ended it, the connection can't be trusted for another request
+// and is closed.
+struct FcgiRequest {
+    backend: &'static FastcgiBackend,
+    connection: Arc<FcgiConnection>,
+    id: u16,
+    records: mpsc::UnboundedReceiver<Record>,
+    ended: bool,
+}
+
+impl FcgiRequest {
+    fn new(backend: &'static FastcgiBackend, connection: Arc<FcgiConnection>) -> FcgiRequest {
+        let (id, records) = connection.register();
+        FcgiRequest { backend, connection, id, records, ended: false }
+    }
+}
+
+impl Drop for FcgiRequest {
+    fn drop(&mut self) {
+        self.connection.requests.lock().unwrap().remove(&self.id);
+        if !self.ended {
+            let connection = Arc::clone(&self.connection);
+            let id = self.id;
+            spawn(async move {
+                let _ = connection.write_record(FCGI_ABORT_REQUEST, id, &[]).await;
+                connection.close();
+            });
+        }
+        self.backend.trim();
+    }
+}
+
+// Requests for files with an extension (e.g. .php) handed to a FastCGI
+// application, from -F .ext=host:port or -F .ext=unix:/path/to/socket
+#[derive(Debug)]
+pub struct FastcgiMapping {
+    pub extension: String,
+    pub backend: FastcgiBackend,
+}
+
+impl FastcgiMapping {
+    pub fn parse(spec: &str) -> Result<FastcgiMapping> {
+        let (extension, upstream) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected .ext=upstream, got {spec}"))?;
+        if !extension.starts_with('.') || extension.len() < 2 {
+            return Err(anyhow::anyhow!("FastCGI extension {extension} should look like .php"));
+        }
+        Ok(FastcgiMapping {
+            extension: extension.to_lowercase(),
+            backend: FastcgiBackend {
+                upstream: Upstream::parse(upstream)?,
+                connections: Mutex::new(Vec::new()),
+                capabilities: OnceLock::new(),
+                retired: AtomicBool::new(false),
+            },
+        })
+    }
+
+    // Script URL and PATH_INFO: the first path segment with the extension
+    // ends the script, e.g. /app/index.php/user/1
+    fn split<'a>(&self, url: &'a str) -> Option<(&'a str, &'a str)> {
+        let mut end = 0;
+        for segment in url.split('/') {
+            end += segment.len();
+            if segment.len() > self.extension.len() && segment.to_lowercase().ends_with(&self.extension) {
+                return Some((&url[..end], &url[end..]));
+            }
+            end += 1;
+        }
+        None
+    }
+}
+
+pub fn fastcgi_mapping(url: &str) -> Option<&'static FastcgiMapping> {
+    CONFIG.fastcgi.iter().find(|mapping| mapping.split(url).is_some())
+}
+
+// A reload replaced these mappings: close their idle connections now, and
+// the others as the requests on them finish
+pub fn retire_fastcgi_mappings(mappings: &[FastcgiMapping]) {
+    for mapping in mappings {
+        mapping.backend.retired.store(true, Ordering::Relaxed);
+        mapping.backend.trim();
+    }
+}
+
+// Forward the request to the FastCGI application in the responder role and
+// stream its output back as the response.
+pub async fn request_handler_fastcgi<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut request: HttpRequest<S>,
+    mapping: &'static FastcgiMapping,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let (script_url, path_info) = mapping.split(&request.url).unwrap_or_default();
+    let (script_url, path_info) = (String::from(script_url), String::from(path_info));
+
+    // The script has to exist under the site root, as for static files
+    let script = match tokio::fs::canonicalize(site_root(&request.server_name)).await {
+        Ok(root) => match tokio::fs::canonicalize(root.join(script_url.trim_start_matches('/'))).await {
+            Ok(script) if script.starts_with(&root) && script.is_file() && !in_other_site(&request.server_name, &script) => {
+                Some((root, script))
+            }
+            _ => None,
+        },
+        Err(_) => None,
+    };
+    let Some((root, script)) = script else {
+        return fastcgi_error(&mut request, mapping, "404 Not Found", "no such script").await;
+    };
+
+    if request.headers.contains_key("transfer-encoding") {
+        return fastcgi_error(&mut request, mapping, "411 Length Required", "chunked request body").await;
+    }
+    let content_length: u64 = match request.headers.get("content-length").map(|l| l.parse()) {
+        None => 0,
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return fastcgi_error(&mut request, mapping, "400 Bad Request", "bad Content-Length").await,
+    };
+
+    let mut params = cgi_environment(&request, &script_url, &script, &path_info, content_length);
+    params.push((String::from("DOCUMENT_ROOT"), root.to_string_lossy().to_string()));
+
+    let mut fcgi = match timeout(FCGI_CONNECT_TIMEOUT, mapping.backend.acquire()).await {
+        Ok(Ok(fcgi)) => fcgi,
+        Ok(Err(e)) => return fastcgi_error(&mut request, mapping, "502 Bad Gateway", &format!("connect: {e}")).await,
+        Err(_) => return fastcgi_error(&mut request, mapping, "504 Gateway Timeout", "connect timed out").await,
+    };
+    let connection = Arc::clone(&fcgi.connection);
+    let id = fcgi.id;
+
+    let mut begin = Vec::with_capacity(8);
+    begin.extend(FCGI_RESPONDER.to_be_bytes());
+    begin.extend([FCGI_KEEP_CONN, 0, 0, 0, 0, 0]);
+    let sent = async {
+        connection.write_record(FCGI_BEGIN_REQUEST, id, &begin).await?;
+        connection.write_stream(FCGI_PARAMS, id, &encode_pairs(&params)).await
+    };
+    if let Err(e) = sent.await {
+        return fastcgi_error(&mut request, mapping, "502 Bad Gateway", &e.to_string()).await;
+    }
+
+    // STDOUT goes through a pipe so the CGI header parser can read it;
+    // STDERR is logged; END_REQUEST finishes with the application's status.
+    let (mut stdout_writer, stdout_reader) = tokio::io::duplex(H2_BRIDGE_BUFFER);
+    let mut stdout = tokio::io::BufReader::new(stdout_reader);
+    let mut records = std::mem::replace(&mut fcgi.records, mpsc::unbounded_channel().1);
+    let upstream = mapping.backend.upstream.to_string();
+    let mut pump: JoinHandle<Result<u32>> = spawn(async move {
+        while let Some(record) = records.recv().await {
+            match record.kind {
+                FCGI_STDOUT => stdout_writer.write_all(&record.content).await?,
+                FCGI_STDERR => {
+                    for line in String::from_utf8_lossy(&record.content).lines() {
+                        eprintln!("FastCGI: {}: {}", &upstream, line);
+                    }
+                }
+                FCGI_END_REQUEST => {
+                    let status = record.content.get(..4).map_or(0, |s| u32::from_be_bytes([s[0], s[1], s[2], s[3]]));
+                    return Ok(status);
+                }
+                _ => (),
+            }
+        }
+        Err(Error::msg("connection closed before the request ended"))
+    });
+
+    let deadline = tokio::time::Instant::now() + FCGI_RESPONSE_TIMEOUT;
+    let body = async {
+        let mut remaining = content_length;
+        let mut buf = vec![0u8; FCGI_STDIN_CHUNK];
+        while remaining > 0 {
+            let want = buf.len().min(remaining as usize);
+            let n = request.stream.read(&mut buf[..want]).await?;
+            if n == 0 {
+                return Err(Error::msg("client closed during request body"));
+            }
+            connection.write_record(FCGI_STDIN, id, &buf[..n]).await?;
+            remaining -= n as u64;
+        }
+        connection.write_record(FCGI_STDIN, id, &[]).await
+    };
+    let (sent, head) = match tokio::time::timeout_at(deadline, async { tokio::join!(body, read_cgi_head(&mut stdout)) }).await {
+        Ok(result) => result,
+        Err(_) => {
+            pump.abort();
+            return fastcgi_error(&mut request, mapping, "504 Gateway Timeout", "response timed out").await;
+        }
+    };
+    let (status, headers) = match sent.and(head) {
+        Ok(head) => head,
+        Err(e) => {
+            pump.abort();
+            return fastcgi_error(&mut request, mapping, "502 Bad Gateway", &e.to_string()).await;
+        }
+    };
+
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in &headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "{}{}{}Connection: close\r\n\r\n",
+        cache_control_header(),
+        virtual_host_headers(&request.server_name),
+        alt_svc_header()
+    ));
+    request.stream.write_all(response.as_bytes()).await?;
+
+    // The body of a HEAD response is read and dropped, or the pump would
+    // block on a full pipe; the rest of the response is under the same
+    // deadline as its head
+    let is_head = request.method == "head";
+    let tail = async {
+        let bytes = if is_head {
+            tokio::io::copy(&mut stdout, &mut tokio::io::sink()).await?;
+            0
+        } else {
+            tokio::io::copy(&mut stdout, &mut request.stream).await?
+        };
+        request.stream.flush().await?;
+        anyhow::Ok(bytes)
+    };
+    let bytes = match tokio::time::timeout_at(deadline, tail).await {
+        Ok(bytes) => bytes?,
+        Err(_) => {
+            pump.abort();
+            return Err(anyhow::anyhow!("FastCGI: {}: response timed out", &mapping.backend.upstream));
+        }
+    };
+    let app_status = match tokio::time::timeout_at(deadline, &mut pump).await {
+        Ok(status) => status??,
+        Err(_) => {
+            pump.abort();
+            return Err(anyhow::anyhow!("FastCGI: {}: no END_REQUEST in time", &mapping.backend.upstream));
+        }
+    };
+    fcgi.ended = true;
+
+    println!(
+        "Request (server {}) client {} {} {} (FastCGI {} {}) {} exit {}, {} byte(s) in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        &mapping.backend.upstream,
+        script.to_string_lossy(),
+        status,
+        app_status,
+        bytes,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+async fn fastcgi_error<S: AsyncRead + AsyncWrite + Unpin>(
+    request: &mut HttpRequest<S>,
+    mapping: &FastcgiMapping,
+    status: &str,
+    reason: &str,
+) -> Result<()> {
+    eprintln!(
+        "Request (server {}) client {} {} {} -> FastCGI {}: {} ({})",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        &mapping.backend.upstream,
+        reason,
+        status
+    );
+    send_status_response(request, status).await?;
+    request.stream.flush().await?;
+    Ok(())
+}
+
+// Name-value pair encoding: lengths of one byte, or four with the top bit set
+fn encode_pairs(pairs: &[(String, String)]) -> Vec<u8> {
+    fn length(out: &mut Vec<u8>, len: usize) {
+        if len < 128 {
+            out.push(len as u8);
+        } else {
+            out.extend((len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    let mut out = Vec::new();
+    for (name, value) in pairs {
+        length(&mut out, name.len());
+        length(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+fn decode_pairs(mut data: &[u8]) -> NameValuePairs {
+    fn length(data: &mut &[u8]) -> Option<usize> {
+        let first = *data.first()?;
+        if first < 128 {
+            *data = &data[1..];
+            Some(first as usize)
+        } else {
+            let bytes = data.get(..4)?;
+            let len = u32::from_be_bytes([bytes[0] & 0x7f, bytes[1], bytes[2], bytes[3]]) as usize;
+            *data = &data[4..];
+            Some(len)
+        }
+    }
+    let mut pairs = Vec::new();
+    while let (Some(name_len), Some(value_len)) = (length(&mut data), length(&mut data)) {
+        let (Some(name), Some(value)) = (data.get(..name_len), data.get(name_len..name_len + value_len)) else {
+            break;
+        };
+        pairs.push((String::from_utf8_lossy(name).to_string(), String::from_utf8_lossy(value).to_string()));
+        data = &data[name_len + value_len..];
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: u8, id: u16, content: &[u8]) -> Vec<u8> {
+        let mut record = vec![FCGI_VERSION_1, kind];
+        record.extend(id.to_be_bytes());
+        record.extend((content.len() as u16).to_be_bytes());
+        record.extend([0, 0]);
+        record.extend_from_slice(content);
+        record
+    }
+
+    // A responder that answers every request with a body far larger than
+    // any buffer between it and the client
+    async fn application(listener: TcpListener, body: usize) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        loop {
+            let mut header = [0u8; 8];
+            if stream.read_exact(&mut header).await.is_err() {
+                return;
+            }
+            let id = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut content = vec![0u8; length + header[6] as usize];
+            stream.read_exact(&mut content).await.unwrap();
+
+            let mut reply = Vec::new();
+            match header[1] {
+                FCGI_GET_VALUES => reply = record(FCGI_GET_VALUES_RESULT, 0, &[]),
+                FCGI_STDIN if length == 0 => {
+                    let mut output = b"Content-Type: text/plain\r\n\r\n".to_vec();
+                    output.resize(output.len() + body, b'x');
+                    for chunk in output.chunks(FCGI_MAX_CONTENT) {
+                        reply.extend(record(FCGI_STDOUT, id, chunk));
+                    }
+                    reply.extend(record(FCGI_STDOUT, id, &[]));
+                    reply.extend(record(FCGI_END_REQUEST, id, &[0; 8]));
+                }
+                _ => {}
+            }
+            stream.write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn head_of_large_response() {
+        let root = std::env::temp_dir().join(format!("mchttp-fcgi-head-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.php"), "").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let spec = format!(".php={}", listener.local_addr().unwrap());
+        spawn(application(listener, 4 * FCGI_MAX_CONTENT));
+        let config = Config {
+            data_dir: Some(root.clone()),
+            fastcgi: vec![FastcgiMapping::parse(&spec).unwrap()],
+            ..Config::default()
+        };
+
+        let (mut client, mut server_io) = tokio::io::duplex(4096);
+        let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        let server = spawn(with_test_config(config, async move { process(&mut server_io, peer, None).await }));
+        client.write_all(b"HEAD /a.php HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(10), client.read_to_string(&mut response)).await.expect("HEAD stalled").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+        timeout(Duration::from_secs(10), server).await.expect("HEAD request never finished").unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
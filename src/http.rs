@@ -1,17 +1,24 @@
-use rustls::server;
+use rustls::sign::CertifiedKey;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
 use crate::*;
 
-const MAX_HEADER_LINES: usize = 100;
-const MAX_LINE_BYTES: usize = 8 * 1024;
+pub const MAX_HEADER_LINES: usize = 100;
+pub const MAX_LINE_BYTES: usize = 8 * 1024;
+// Time allowed for the client to send the request line and headers. Once a
+// request is read, responses (large files, WebSockets) aren't time limited.
+pub const REQUEST_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 // Unified stream type so a single listener handles both HTTP and HTTPS.
 // Both TcpStream and TlsStream<TcpStream> implement AsyncRead + AsyncWrite + Unpin,
-// so AnyStream inherits all of those automatically.
+// so AnyStream inherits all of those automatically. Unix domain socket
+// connections are always plain.
 pub enum AnyStream {
     Plain(TcpStream),
     Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    Unix(UnixStream),
 }
 
 impl tokio::io::AsyncRead for AnyStream {
@@ -23,6 +30,7 @@ impl tokio::io::AsyncRead for AnyStream {
         match self.get_mut() {
             AnyStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
             AnyStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            AnyStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
@@ -36,6 +44,7 @@ impl tokio::io::AsyncWrite for AnyStream {
         match self.get_mut() {
             AnyStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
             AnyStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            AnyStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
         }
     }
 
@@ -43,6 +52,7 @@ impl tokio::io::AsyncWrite for AnyStream {
         match self.get_mut() {
             AnyStream::Plain(s) => Pin::new(s).poll_flush(cx),
             AnyStream::Tls(s) => Pin::new(s).poll_flush(cx),
+            AnyStream::Unix(s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -50,6 +60,65 @@ impl tokio::io::AsyncWrite for AnyStream {
         match self.get_mut() {
             AnyStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
             AnyStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            AnyStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// Any bidirectional byte stream, for code that has to hold one of several
+// stream types (WebSocket handlers, proxy upstreams)
+pub trait IoStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IoStream for T {}
+
+// Who's at the other end of a Unix domain socket connection (SO_PEERCRED)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerCredentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// A client: an IP address, or a process on this machine connected over a
+// Unix domain socket (with its credentials, when the system says)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Peer {
+    Ip(SocketAddr),
+    Unix(Option<PeerCredentials>),
+}
+
+impl Peer {
+    // For address-based decisions (trusted proxies, the proxy hash,
+    // REMOTE_ADDR) a Unix peer is the loopback address
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Peer::Ip(addr) => addr.ip(),
+            Peer::Unix(_) => IpAddr::from([127, 0, 0, 1]),
+        }
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Peer::Ip(addr) => Some(addr.port()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Peer {
+        Peer::Ip(addr)
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Ip(addr) => write!(f, "{addr}"),
+            Peer::Unix(None) => write!(f, "unix"),
+            Peer::Unix(Some(PeerCredentials { pid: Some(pid), uid, gid })) => {
+                write!(f, "unix(pid {pid}, uid {uid}, gid {gid})")
+            }
+            Peer::Unix(Some(PeerCredentials { pid: None, uid, gid })) => write!(f, "unix(uid {uid}, gid {gid})"),
         }
     }
 }
@@ -57,9 +126,12 @@ impl tokio::io::AsyncWrite for AnyStream {
 #[derive(Debug)]
 pub struct HttpRequest<S> {
     pub server_name: Option<String>,
-    pub client: SocketAddr,
+    pub client: Peer, // the original client when behind a trusted proxy
+    pub peer: Peer,   // the other end of the connection
+    pub scheme: &'static str,
     pub method: String,
     pub url: String,
+    pub target: String, // request-target as sent, before decoding
     pub version: String,
     pub stream: BufStream<S>,
     pub headers: HashMap<String, String>,
@@ -67,41 +139,109 @@ pub struct HttpRequest<S> {
 }
 
 // Build a TlsAcceptor from CONFIG, returning None if TLS is not configured or
-// identity loading fails.
+// identity loading or the TLS options fail.
 fn build_tls_acceptor() -> Option<TlsAcceptor> {
+    build_tls_config().map(|config| TlsAcceptor::from(Arc::new(config)))
+}
+
+// TLS server configuration shared by the TCP and QUIC listeners, with the
+// status of its certificates published and their OCSP state put in place
+pub fn build_tls_config() -> Option<ServerConfig> {
+    let (config, status, ocsp) = load_tls_config()?;
+    publish_tls_status(status);
+    ocsp.commit();
+    Some(config)
+}
+
+// TLS server configuration with the status and OCSP state of its
+// certificates, which nothing uses yet: a reload candidate is checked with it
+pub fn load_tls_config() -> Option<(ServerConfig, Vec<IdentityStatus>, OcspUpdate)> {
     let tls = CONFIG.tls.as_ref()?;
-    let mut identity_resolver = server::ResolvesServerCertUsingSni::new();
-    if let Err(e) = load_identities(&mut identity_resolver, tls) {
+    let builder = match tls_config_builder() {
+        Ok(builder) => builder.with_no_client_auth(),
+        Err(e) => {
+            eprintln!("Failed to configure TLS: {e}");
+            return None;
+        }
+    };
+
+    let mut identity_resolver = IdentityResolver::new();
+    let mut ocsp = OcspUpdate::default();
+    let loaded = if tls == AUTO_TLS {
+        // Self-signed development certificate: one identity for every name
+        auto_identity()
+            .and_then(|(certs, key)| Ok(CertifiedKey::from_der(certs, key, &rustls::crypto::aws_lc_rs::default_provider())?))
+            .and_then(|certified_key| identity_resolver.set_default("default", Path::new("(self-signed)"), certified_key))
+    } else {
+        load_identities(&mut identity_resolver, tls, &mut ocsp)
+    };
+    if let Err(e) = loaded {
         eprintln!("Failed to load TLS identities: {e}");
         return None;
     }
-    let config = ServerConfig::builder_with_protocol_versions(
-        &[&rustls::version::TLS13, &rustls::version::TLS12],
-    )
-    .with_no_client_auth()
-    .with_cert_resolver(Arc::new(identity_resolver));
-    Some(TlsAcceptor::from(Arc::new(config)))
+    let status = identity_resolver.status();
+    let mut config = builder.with_cert_resolver(Arc::new(identity_resolver));
+    if let Err(e) = tls_config_finish(&mut config) {
+        eprintln!("Failed to configure TLS: {e}");
+        return None;
+    }
+    Some((config, status, ocsp))
 }
 
-// Single listener for both HTTP and HTTPS. TLS presence is determined by CONFIG.
-// A background task reloads certificates every 60 seconds when TLS is active.
-pub async fn listener<A: ToSocketAddrs + ?Sized>(addr: &A) -> Result<()> {
-    let tcp = TcpListener::bind(addr).await?;
-    let shared = Arc::new(Mutex::new(build_tls_acceptor()));
-
-    if CONFIG.tls.is_some() {
-        let shared = Arc::clone(&shared);
-        spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(60)).await;
-                *shared.lock().unwrap() = build_tls_acceptor();
-            }
-        });
+// The TLS acceptor for new connections on every TCP listener: None without
+// TLS. Rebuilt periodically and when the configuration is reloaded.
+static TLS_ACCEPTOR: Mutex<Option<TlsAcceptor>> = Mutex::new(None);
+
+lazy_static! {
+    // Bumped on every rebuild, so QUIC endpoints can follow along
+    pub static ref TLS_GENERATION: tokio::sync::watch::Sender<u64> = tokio::sync::watch::channel(0).0;
+}
+
+pub fn refresh_tls_acceptor() {
+    let acceptor = build_tls_acceptor();
+    let mut current = TLS_ACCEPTOR.lock().unwrap();
+    // A failed rebuild keeps what's being served (as QUIC does) rather than
+    // falling back to plain HTTP
+    if acceptor.is_none() && CONFIG.tls.is_some() && current.is_some() {
+        eprintln!("TLS: keeping the certificates already loaded");
+        return;
     }
+    *current = acceptor;
+    drop(current);
+    TLS_GENERATION.send_modify(|generation| *generation += 1);
+}
+
+// A background task reloads certificates every 60 seconds when TLS is active,
+// fetching OCSP responses for them first if enabled.
+pub fn start_tls_refresh() {
+    refresh_tls_acceptor();
+    spawn(async move {
+        // Staple straight away rather than a minute after startup
+        if CONFIG.tls.is_some() && CONFIG.ocsp_fetch && refresh_ocsp().await {
+            refresh_tls_acceptor();
+        }
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if CONFIG.tls.is_none() {
+                continue;
+            }
+            if CONFIG.ocsp_fetch {
+                refresh_ocsp().await;
+            }
+            refresh_tls_acceptor();
+        }
+    });
+}
 
+// Single listener for both HTTP and HTTPS. TLS presence is determined by CONFIG.
+// Each connection is served with the configuration current when it arrived.
+pub async fn listener(tcp: TcpListener) -> Result<()> {
     loop {
-        let (stream, addr) = tcp.accept().await?;
-        let acceptor = shared.lock().unwrap().clone();
+        let (stream, addr) = tokio::select! {
+            accepted = tcp.accept() => accepted?,
+            _ = shutdown_started() => return Ok(()),
+        };
+        let acceptor = TLS_ACCEPTOR.lock().unwrap().clone();
         let is_tls = acceptor.is_some();
         let raw_fd = stream.as_raw_fd();
 
@@ -114,11 +254,41 @@ pub async fn listener<A: ToSocketAddrs + ?Sized>(addr: &A) -> Result<()> {
             );
         }
 
-        spawn(async move {
+        let connection = track_connection(addr.into(), if is_tls { "HTTPS" } else { "HTTP" });
+        spawn(with_config(async move {
+            let _connection = connection;
+            let mut stream = stream;
+            // Load balancers we trust say who the client really is, ahead of
+            // anything else (the TLS handshake included)
+            let addr = if is_trusted_proxy(&CONFIG.proxy_protocol, addr.ip()) {
+                match read_proxy_header(&mut stream).await {
+                    Ok(Some(client)) => {
+                        if CONFIG.verbose {
+                            eprintln!("PROXY: {:?} FD {} client {:?}", &addr, raw_fd, &client);
+                        }
+                        client
+                    }
+                    Ok(None) => addr,
+                    Err(e) => {
+                        eprintln!("PROXY: {:?} FD {}: {e}", &addr, raw_fd);
+                        return;
+                    }
+                }
+            } else {
+                addr
+            };
+
             let result: Result<()> = match acceptor {
                 None => {
-                    let mut s = AnyStream::Plain(stream);
-                    process(&mut s, addr, None).await
+                    if CONFIG.h2c && is_h2c_preface(&stream).await {
+                        if CONFIG.verbose {
+                            eprintln!("HTTP: {:?} FD {} HTTP/2 prior knowledge (h2c)", &addr, raw_fd);
+                        }
+                        serve_h2(AnyStream::Plain(stream), addr.into(), None).await
+                    } else {
+                        let mut s = AnyStream::Plain(stream);
+                        process(&mut s, addr.into(), None).await
+                    }
                 }
                 Some(acceptor) => match acceptor.accept(stream).await {
                     Err(e) => {
@@ -133,35 +303,35 @@ pub async fn listener<A: ToSocketAddrs + ?Sized>(addr: &A) -> Result<()> {
                             let (_, conn) = tls_stream.get_ref();
                             conn.server_name().map(str::to_string)
                         };
+                        let is_h2 = {
+                            let (_, conn) = tls_stream.get_ref();
+                            conn.alpn_protocol() == Some(b"h2")
+                        };
                         if CONFIG.verbose {
                             let (_, conn) = tls_stream.get_ref();
                             eprintln!(
-                                "HTTPS: {:?} FD {} identity {:?} cipher {:?}",
+                                "HTTPS: {:?} FD {} identity {:?} cipher {:?}{}",
                                 &addr,
                                 raw_fd,
                                 conn.server_name(),
-                                conn.negotiated_cipher_suite()
+                                conn.negotiated_cipher_suite(),
+                                if is_h2 { " HTTP/2" } else { "" }
                             );
                         }
-                        let mut s = AnyStream::Tls(Box::new(tls_stream));
-                        let r = match timeout(
-                            Duration::from_secs(5),
-                            process(&mut s, addr, server_name),
-                        )
-                        .await
-                        {
-                            Err(_) => {
-                                eprintln!("HTTPS: {:?}: timed out", &addr);
-                                Ok(())
+                        // HTTP/2 connections are long-lived and multiplexed,
+                        // so they get an idle timeout rather than a header timeout.
+                        if is_h2 {
+                            serve_h2(AnyStream::Tls(Box::new(tls_stream)), addr.into(), server_name).await
+                        } else {
+                            let mut s = AnyStream::Tls(Box::new(tls_stream));
+                            let r = process(&mut s, addr.into(), server_name).await;
+                            if let AnyStream::Tls(ref mut tls) = s {
+                                // send_close_notify borrow ends at ;
+                                tls.get_mut().1.send_close_notify();
+                                let _ = tls.flush().await;
                             }
-                            Ok(r) => r,
-                        };
-                        if let AnyStream::Tls(ref mut tls) = s {
-                            // send_close_notify borrow ends at ;
-                            tls.get_mut().1.send_close_notify();
-                            let _ = tls.flush().await;
+                            r
                         }
-                        r
                     }
                 },
             };
@@ -180,17 +350,58 @@ pub async fn listener<A: ToSocketAddrs + ?Sized>(addr: &A) -> Result<()> {
                     &addr
                 );
             }
-        });
+        }));
     }
 
     #[allow(unreachable_code)]
     Ok(())
 }
 
+// Listener for a Unix domain socket: plain HTTP (and h2c with -H) from a
+// reverse proxy on this machine, which terminates TLS itself
+pub async fn unix_listener(unix: UnixListener) -> Result<()> {
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = unix.accept() => accepted?,
+            _ = shutdown_started() => return Ok(()),
+        };
+        let peer = Peer::Unix(stream.peer_cred().ok().map(|cred| PeerCredentials {
+            pid: cred.pid(),
+            uid: cred.uid(),
+            gid: cred.gid(),
+        }));
+        let raw_fd = stream.as_raw_fd();
+
+        if CONFIG.verbose {
+            eprintln!("HTTP: {} connected on FD {}", &peer, raw_fd);
+        }
+
+        let connection = track_connection(peer, "HTTP");
+        spawn(with_config(async move {
+            let _connection = connection;
+            let result = if CONFIG.h2c && is_h2c_preface(&stream).await {
+                if CONFIG.verbose {
+                    eprintln!("HTTP: {} FD {} HTTP/2 prior knowledge (h2c)", &peer, raw_fd);
+                }
+                serve_h2(AnyStream::Unix(stream), peer, None).await
+            } else {
+                process(&mut AnyStream::Unix(stream), peer, None).await
+            };
+
+            if let Err(e) = result {
+                eprintln!("HTTP: {}: error: {e}", &peer);
+            }
+            if CONFIG.verbose {
+                eprintln!("HTTP: {} closed", &peer);
+            }
+        }));
+    }
+}
+
 // Entry point per HTTP client connection — parse HTTP request then dispatch.
-pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
+pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin + Send>(
     stream: &mut S,
-    client: SocketAddr,
+    client: Peer,
     server_name: Option<String>,
 ) -> Result<()> {
     let mut stream = tokio::io::BufStream::new(stream);
@@ -198,20 +409,37 @@ pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
 
     let mut method = String::new();
     let mut url = String::new();
+    let mut target = String::new();
     let mut version = String::new();
     let mut headers = HashMap::<String, String>::new();
     let mut query = HashMap::<String, String>::new();
+    let header_deadline = tokio::time::Instant::now() + CONFIG.limits.header_timeout;
+
+    // A connection that hasn't started on a request when a shutdown begins
+    // is closed rather than waited on. Timeouts, EOF and errors are left to
+    // the reads below.
+    let idle = tokio::select! {
+        biased;
+        _ = tokio::time::timeout_at(header_deadline, stream.fill_buf()) => false,
+        _ = shutdown_started() => true,
+    };
+    if idle {
+        return Ok(());
+    }
 
     loop {
         let mut buf = Vec::<u8>::new();
-        let bytes_read = stream.read_until(b'\n', &mut buf).await?;
+        let bytes_read = match tokio::time::timeout_at(header_deadline, stream.read_until(b'\n', &mut buf)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(Error::msg(format!("HTTP: {}: timed out reading request", &client))),
+        };
 
         if bytes_read == 0 {
             return Err(Error::msg(format!("HTTP: {}: client EOF", &client)));
         }
 
         // Reject oversized lines before allocating a String from them.
-        if bytes_read > MAX_LINE_BYTES {
+        if bytes_read > CONFIG.limits.max_line_bytes {
             stream
                 .write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
                 .await?;
@@ -248,6 +476,7 @@ pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
                 }
                 method = verb_tokens[0].to_lowercase();
                 (url, query) = query_string(verb_tokens[1]);
+                target = verb_tokens[1].to_string();
                 version = verb_tokens[2].to_string();
             } else if let Some((k, v)) = line
                 .split_once(':')
@@ -257,7 +486,7 @@ pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
             }
 
             line_count += 1;
-            if line_count > MAX_HEADER_LINES {
+            if line_count > CONFIG.limits.max_header_lines {
                 stream
                     .write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
                     .await?;
@@ -272,18 +501,58 @@ pub async fn process<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
         }
     }
 
-    let http_request = HttpRequest {
+    let peer = client;
+    // Unix domain socket connections are never TLS
+    let scheme = if CONFIG.tls.is_some() && matches!(peer, Peer::Ip(_)) { "https" } else { "http" };
+    let (client, scheme) = if is_trusted_proxy(&CONFIG.trusted_proxies, peer.ip()) {
+        forwarded_client(peer, scheme, &headers)
+    } else {
+        (peer, scheme)
+    };
+    if CONFIG.verbose && client != peer {
+        eprintln!("HTTP: {}: client {} ({}) via trusted proxy", &peer, &client, scheme);
+    }
+
+    let server_name = resolve_server_name(server_name, &headers);
+    let mut http_request = HttpRequest {
         server_name,
         client,
+        peer,
+        scheme,
         method,
         url,
+        target,
         version,
         stream,
         headers,
         query,
     };
 
-    request_handler_dir(http_request).await
+    if !check_authorization(&mut http_request).await? {
+        return Ok(());
+    }
+
+    match &CONFIG.tls_status_path {
+        Some(status_path) if *status_path == http_request.url => request_handler_tls_status(http_request).await,
+        _ if is_websocket_request(&http_request) => request_handler_websocket(http_request).await,
+        _ if CONFIG.events && http_request.url == EVENTS_PATH => request_handler_events(http_request).await,
+        _ if CONFIG.reload_path.as_ref() == Some(&http_request.url) => request_handler_reload(http_request).await,
+        _ => {
+            if let Some(route) = proxy_route(&http_request.target) {
+                request_handler_proxy(http_request, route).await
+            } else if let Some(mapping) = cgi_mapping(&http_request.url) {
+                request_handler_cgi(http_request, mapping).await
+            } else if let Some(mapping) = fastcgi_mapping(&http_request.url) {
+                request_handler_fastcgi(http_request, mapping).await
+            } else if http_request.method == "put" && upload_enabled(&http_request.server_name) {
+                request_handler_upload(http_request).await
+            } else if mapped_file(&http_request.url).is_some() {
+                request_handler_static_file(http_request).await
+            } else {
+                request_handler_dir(http_request).await
+            }
+        }
+    }
 }
 
 // Split URL on '?' first (before decoding) to prevent %3F from being misread
@@ -315,12 +584,18 @@ pub fn query_string(url: &str) -> (String, HashMap<String, String>) {
     }
 }
 
+// File mapped to a URL by a file argument, --file, -r or the files key; a
+// directory given to -r is the default site's root instead
+pub fn mapped_file(url: &str) -> Option<PathBuf> {
+    CONFIG.files.get(url).map(|path| chroot_path(path).into_owned()).filter(|path| !path.is_dir())
+}
+
 pub async fn request_handler_static_file<S: AsyncRead + AsyncWrite + Unpin>(
     mut request: HttpRequest<S>,
 ) -> Result<()> {
     let start_time = Instant::now();
 
-    match CONFIG.files.get(&request.url) {
+    match mapped_file(&request.url) {
         Some(path) => {
             let meta = tokio::fs::metadata(&path).await?;
             let content_length = meta.len();
@@ -353,28 +628,46 @@ pub async fn request_handler_static_file<S: AsyncRead + AsyncWrite + Unpin>(
     anyhow::Ok(())
 }
 
-pub async fn request_handler_dir<S: AsyncRead + AsyncWrite + Unpin>(
-    mut request: HttpRequest<S>,
-) -> Result<()> {
-    let start_time = Instant::now();
-
-    // Build root path from configuration
+// Directory serving a site: its configured root, the -r directory for the
+// default site, or the data directory plus the server name for TLS virtual
+// hosts
+pub fn site_root(server_name: &Option<String>) -> PathBuf {
+    match virtual_host(server_name) {
+        Some(vhost) => {
+            if let Some(root) = &vhost.root {
+                return chroot_path(root).into_owned();
+            }
+        }
+        None => {
+            if let Some(root) = CONFIG.files.get("/").map(|path| chroot_path(path)).filter(|path| path.is_dir()) {
+                return root.into_owned();
+            }
+        }
+    }
     let mut root_path = PathBuf::new();
     if let Some(data_dir) = &CONFIG.data_dir {
         root_path.push(data_dir);
     }
-    if let Some(server_name) = &request.server_name {
+    if let Some(server_name) = server_name {
         root_path.push(server_name);
     }
+    if root_path.as_os_str().is_empty() {
+        root_path.push(".");
+    }
+    chroot_path(&root_path).into_owned()
+}
+
+pub async fn request_handler_dir<S: AsyncRead + AsyncWrite + Unpin>(
+    mut request: HttpRequest<S>,
+) -> Result<()> {
+    let start_time = Instant::now();
+
+    // Build root path from configuration
+    let root_path = site_root(&request.server_name);
 
     // Canonicalize root_path now so symlinks in data_dir/server_name can't
     // bypass the starts_with check below.
-    let root_path_base = if root_path.as_os_str().is_empty() {
-        PathBuf::from(".")
-    } else {
-        root_path.clone()
-    };
-    let canon_root = match tokio::fs::canonicalize(&root_path_base).await {
+    let canon_root = match tokio::fs::canonicalize(&root_path).await {
         Ok(p) => p,
         Err(_) => {
             send_response(&mut request, "text/plain", None).await?;
@@ -391,30 +684,71 @@ pub async fn request_handler_dir<S: AsyncRead + AsyncWrite + Unpin>(
         Ok(p) => request_path.push(p),
     }
 
-    // Append index.html for directory requests
+    // Append the first index file that exists for directory requests,
+    // or list the directory if the site allows
     if tokio::fs::metadata(&request_path)
         .await
         .map(|m| m.is_dir())
         .unwrap_or(false)
     {
-        request_path.push("index.html");
+        let mut index = None;
+        for name in index_files(&request.server_name) {
+            if tokio::fs::metadata(request_path.join(&name)).await.is_ok_and(|m| m.is_file()) {
+                index = Some(name);
+                break;
+            }
+        }
+        match index {
+            Some(name) => request_path.push(name),
+            None if autoindex_enabled(&request.server_name) => {
+                let within_root = tokio::fs::canonicalize(&request_path)
+                    .await
+                    .is_ok_and(|path| path.starts_with(&canon_root) && !in_other_site(&request.server_name, &path));
+                if within_root {
+                    let listing = directory_listing(&request.url, &request_path).await?;
+                    send_response(&mut request, "text/html", Some(&listing)).await?;
+                    request.stream.flush().await?;
+                    println!(
+                        "Request (server {}) client {} {} {} ({}) directory listing in {:?}",
+                        &request.server_name.as_ref().map_or("default", |x| x),
+                        &request.client,
+                        &request.method,
+                        &request.url,
+                        request_path.to_string_lossy(),
+                        start_time.elapsed()
+                    );
+                    return Ok(());
+                }
+                request_path.push(DEFAULT_INDEX_FILES[0]);
+            }
+            None => request_path.push(DEFAULT_INDEX_FILES[0]),
+        }
     }
 
     match tokio::fs::canonicalize(&request_path).await {
         Ok(path) => {
-            if path.starts_with(&canon_root) {
+            if path.starts_with(&canon_root) && !in_other_site(&request.server_name, &path) {
                 let content_type = lookup_mimetype(&path);
                 match tokio::fs::metadata(&path).await {
                     Ok(meta) => {
-                        let content_length = meta.len();
-                        let mut file =
-                            tokio::fs::OpenOptions::new().read(true).open(&path).await?;
-                        send_response_header(&mut request, content_type, content_length).await?;
-                        tokio::io::copy(
-                            &mut file.take(content_length),
-                            &mut request.stream,
-                        )
-                        .await?;
+                        let content_length = if CONFIG.dev && content_type == "text/html" {
+                            // Live reload: serve the modified page in one go
+                            let body = inject_reload_script(tokio::fs::read(&path).await?);
+                            send_response_header(&mut request, content_type, body.len() as u64).await?;
+                            request.stream.write_all(&body).await?;
+                            body.len() as u64
+                        } else {
+                            let content_length = meta.len();
+                            let mut file =
+                                tokio::fs::OpenOptions::new().read(true).open(&path).await?;
+                            send_response_header(&mut request, content_type, content_length).await?;
+                            tokio::io::copy(
+                                &mut file.take(content_length),
+                                &mut request.stream,
+                            )
+                            .await?;
+                            content_length
+                        };
                         println!(
                             "Request (server {}) client {} {} {} ({}) type {}, {} byte(s) in {:?}",
                             &request.server_name.as_ref().map_or("default", |x| x),
@@ -483,9 +817,12 @@ where
                 .stream
                 .write_all(
                     format!(
-                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}{}Connection: close\r\n\r\n{}",
                         content_type,
                         content.len(),
+                        cache_control_header(),
+                        virtual_host_headers(&request.server_name),
+                        alt_svc_header(),
                         content
                     )
                     .as_bytes(),
@@ -496,7 +833,13 @@ where
             request
                 .stream
                 .write_all(
-                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    format!(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n{}{}{}Connection: close\r\n\r\n",
+                        cache_control_header(),
+                        virtual_host_headers(&request.server_name),
+                        alt_svc_header()
+                    )
+                    .as_bytes(),
                 )
                 .await?;
         }
@@ -504,6 +847,27 @@ where
     Ok(())
 }
 
+// Bodiless response with the given status, e.g. "502 Bad Gateway"
+pub async fn send_status_response<S>(request: &mut HttpRequest<S>, status: &str) -> Result<()>
+where
+    BufStream<S>: AsyncWrite + AsyncRead,
+    S: AsyncWrite + AsyncRead + Unpin,
+{
+    Ok(request
+        .stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\n{}{}{}Connection: close\r\n\r\n",
+                status,
+                cache_control_header(),
+                virtual_host_headers(&request.server_name),
+                alt_svc_header()
+            )
+            .as_bytes(),
+        )
+        .await?)
+}
+
 pub async fn send_response_header<S>(
     request: &mut HttpRequest<S>,
     content_type: &str,
@@ -517,10 +881,75 @@ where
         .stream
         .write_all(
             format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
-                content_type, content_length
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}{}Connection: close\r\n\r\n",
+                content_type,
+                content_length,
+                cache_control_header(),
+                virtual_host_headers(&request.server_name),
+                alt_svc_header()
             )
             .as_bytes(),
         )
         .await?)
 }
+
+// Header for a response of unknown length that's streamed until the
+// connection closes (events and the like), never cached.
+pub async fn send_stream_header<S>(request: &mut HttpRequest<S>, content_type: &str) -> Result<()>
+where
+    BufStream<S>: AsyncWrite + AsyncRead,
+    S: AsyncWrite + AsyncRead + Unpin,
+{
+    Ok(request
+        .stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-store\r\n{}{}Connection: close\r\n\r\n",
+                content_type,
+                virtual_host_headers(&request.server_name),
+                alt_svc_header()
+            )
+            .as_bytes(),
+        )
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mapped_files() {
+        let dir = std::env::temp_dir().join(format!("mchttp-files-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("site")).unwrap();
+        std::fs::write(dir.join("robots.txt"), "User-agent: *\n").unwrap();
+        std::fs::write(dir.join("site").join("index.html"), "home").unwrap();
+        let files = HashMap::from([
+            (String::from("/robots.txt"), dir.join("robots.txt")),
+            (String::from("/"), dir.join("site")),
+        ]);
+        let config = Config { files, ..Config::default() };
+
+        let get = |path: &'static str| async move {
+            let (mut client, mut server_io) = tokio::io::duplex(4096);
+            let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+            let server = spawn(with_config(async move { process(&mut server_io, peer, None).await }));
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            server.await.unwrap().unwrap();
+            response
+        };
+        with_test_config(config, async {
+            let robots = get("/robots.txt").await;
+            assert!(robots.starts_with("HTTP/1.1 200 OK\r\n"), "{robots}");
+            assert!(robots.ends_with("\r\n\r\nUser-agent: *\n"), "{robots}");
+            let home = get("/").await;
+            assert!(home.starts_with("HTTP/1.1 200 OK\r\n"), "{home}");
+            assert!(home.ends_with("\r\n\r\nhome"), "{home}");
+        })
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
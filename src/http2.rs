@@ -0,0 +1,366 @@
+use bytes::Bytes;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncBufRead, BufReader, DuplexStream, Interest, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+use crate::*;
+
+// Client connection preface that starts every HTTP/2 connection (RFC 9113 3.4)
+pub const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Close an HTTP/2 connection (GOAWAY, letting active streams finish) when no
+// new stream has been opened for this long.
+const H2_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const H2_BRIDGE_BUFFER: usize = 64 * 1024;
+
+// Hop-by-hop HTTP/1 response headers that are illegal in HTTP/2
+// (also illegal in HTTP/3)
+const H2_DROP_HEADERS: [&str; 5] = ["connection", "keep-alive", "transfer-encoding", "upgrade", "proxy-connection"];
+
+// Serve an HTTP/2 connection (h2 via ALPN, or h2c prior knowledge). Each
+// stream is bridged through an in-memory HTTP/1.1 exchange into process(),
+// so the same handlers serve both protocols while the h2 crate takes care
+// of framing, HPACK and flow control.
+pub async fn serve_h2<S>(stream: S, client: Peer, server_name: Option<String>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut connection = h2::server::handshake(stream).await?;
+    let mut streams = JoinSet::<()>::new();
+    let mut closing = false;
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = timeout(H2_IDLE_TIMEOUT, connection.accept()) => accepted,
+            _ = shutdown_started(), if !closing => {
+                // GOAWAY: streams already open finish, no new ones
+                closing = true;
+                connection.graceful_shutdown();
+                continue;
+            }
+        };
+        match accepted {
+            Ok(Some(Ok((request, respond)))) => {
+                let server_name = server_name.clone();
+                streams.spawn(with_config(async move {
+                    if let Err(e) = h2_stream(request, respond, client, server_name).await {
+                        eprintln!("HTTP/2: {}: stream error: {e}", &client);
+                    }
+                }));
+            }
+            Ok(Some(Err(e))) => {
+                if e.is_go_away() || e.is_io() {
+                    break;
+                }
+                return Err(e.into());
+            }
+            Ok(None) => break,
+            Err(_) => {
+                if CONFIG.verbose {
+                    eprintln!("HTTP/2: {}: idle, sending GOAWAY", &client);
+                }
+                connection.graceful_shutdown();
+            }
+        }
+    }
+
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn h2_stream(
+    request: ::http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    client: Peer,
+    server_name: Option<String>,
+) -> Result<()> {
+    let (parts, mut body) = request.into_parts();
+    let is_head = parts.method == ::http::Method::HEAD;
+
+    let has_body = !body.is_end_stream();
+    let (mut bridge_read, mut bridge_body, handler) = spawn_bridge(&parts, has_body, client, server_name).await?;
+
+    // Request body: hand it to the handler, returning flow control credit
+    // to the client as it's consumed.
+    let request_body = spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            let len = chunk.len();
+            bridge_body.write(&chunk).await?;
+            let _ = body.flow_control().release_capacity(len);
+        }
+        bridge_body.finish().await?;
+        anyhow::Ok(())
+    });
+
+    let response = read_response_head(&mut bridge_read).await?;
+    let mut send = respond.send_response(response, is_head)?;
+
+    if is_head {
+        // Handlers write a body for HEAD too: read it off and drop it, or a
+        // response bigger than the bridge would block the handler for good
+        tokio::io::copy(&mut bridge_read, &mut tokio::io::sink()).await?;
+    } else {
+        let mut buf = vec![0u8; H2_BRIDGE_BUFFER];
+        loop {
+            let n = bridge_read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let mut chunk = Bytes::copy_from_slice(&buf[..n]);
+            while !chunk.is_empty() {
+                send.reserve_capacity(chunk.len());
+                let granted = match std::future::poll_fn(|cx| send.poll_capacity(cx)).await {
+                    Some(granted) => granted?,
+                    None => return Err(Error::msg("HTTP/2 stream closed by peer")),
+                };
+                if granted == 0 {
+                    continue;
+                }
+                send.send_data(chunk.split_to(granted.min(chunk.len())), false)?;
+            }
+        }
+        send.send_data(Bytes::new(), true)?;
+    }
+
+    request_body.abort();
+    handler.await??;
+    Ok(())
+}
+
+// The request body's way into the bridge: as it is when the request gave a
+// length, otherwise chunk-encoded so the handler can tell where it ends
+pub struct BridgeBody {
+    writer: WriteHalf<DuplexStream>,
+    chunked: bool,
+}
+
+impl BridgeBody {
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if !self.chunked {
+            return self.writer.write_all(data).await;
+        }
+        // An empty chunk would end the body
+        if !data.is_empty() {
+            self.writer.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+            self.writer.write_all(data).await?;
+            self.writer.write_all(b"\r\n").await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&mut self) -> std::io::Result<()> {
+        if self.chunked {
+            self.writer.write_all(b"0\r\n\r\n").await?;
+        }
+        self.writer.flush().await
+    }
+}
+
+// Re-express an HTTP/2 or HTTP/3 request as HTTP/1.1 over an in-memory
+// stream and start process() on the other end of it. Returns the reader for
+// the handler's response, the writer for the request body and the handler.
+pub async fn spawn_bridge(
+    parts: &::http::request::Parts,
+    has_body: bool,
+    client: Peer,
+    server_name: Option<String>,
+) -> Result<(BufReader<ReadHalf<DuplexStream>>, BridgeBody, JoinHandle<Result<()>>)> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\n",
+        parts.method,
+        parts.uri.path_and_query().map_or("/", |p| p.as_str())
+    );
+    if let Some(authority) = parts.uri.authority() {
+        if !parts.headers.contains_key(::http::header::HOST) {
+            head.push_str(&format!("host: {}\r\n", authority));
+        }
+    }
+    // HTTP/2 and HTTP/3 frame the body themselves, so a length is optional
+    let chunked = has_body && !parts.headers.contains_key(::http::header::CONTENT_LENGTH);
+    for (name, value) in &parts.headers {
+        if name == ::http::header::TRANSFER_ENCODING {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if chunked {
+        head.push_str("transfer-encoding: chunked\r\n");
+    }
+    head.push_str("\r\n");
+
+    // The head goes in before the handler starts, so it never finds the
+    // bridge empty (and, during a shutdown, takes it for an idle connection)
+    let (bridge, mut handler_side) = tokio::io::duplex(H2_BRIDGE_BUFFER.max(head.len()));
+    let (bridge_read, mut bridge_write) = tokio::io::split(bridge);
+    bridge_write.write_all(head.as_bytes()).await?;
+
+    let handler = spawn(with_config(async move { process(&mut handler_side, client, server_name).await }));
+    Ok((BufReader::new(bridge_read), BridgeBody { writer: bridge_write, chunked }, handler))
+}
+
+// Parse the HTTP/1.1 status line and headers a handler wrote into an
+// http::Response for the h2 stream.
+pub async fn read_response_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<::http::Response<()>> {
+    let mut builder = ::http::Response::builder();
+    let mut first = true;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::msg("handler closed without a response"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if first {
+            let status = line
+                .split(' ')
+                .nth(1)
+                .ok_or_else(|| Error::msg("malformed response status line"))?
+                .parse::<u16>()?;
+            if (100..200).contains(&status) {
+                // Interim response (100 Continue): wait for the real one
+                let mut interim = String::new();
+                while reader.read_line(&mut interim).await? > 2 {
+                    interim.clear();
+                }
+                continue;
+            }
+            builder = builder.status(status);
+            first = false;
+        } else if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim().to_lowercase();
+            if !H2_DROP_HEADERS.contains(&name.as_str()) {
+                builder = builder.header(name, value.trim());
+            }
+        }
+    }
+    Ok(builder.body(())?)
+}
+
+// A plaintext connection whose first bytes can be looked at without taking
+// them: TCP, or a Unix domain socket (where tokio has no peek of its own)
+pub trait PeekStream {
+    fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>>;
+}
+
+impl PeekStream for TcpStream {
+    fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        TcpStream::poll_peek(self, cx, &mut ReadBuf::new(buf))
+    }
+}
+
+impl PeekStream for UnixStream {
+    fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        loop {
+            ready!(self.poll_read_ready(cx))?;
+            let peeked = self.try_io(Interest::READABLE, || {
+                let n = unsafe { libc::recv(self.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), libc::MSG_PEEK) };
+                if n == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            match peeked {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                peeked => return Poll::Ready(peeked),
+            }
+        }
+    }
+}
+
+// Does this plaintext connection start with the HTTP/2 preface? Peeks so
+// that the bytes are still there for whichever parser handles it.
+pub async fn is_h2c_preface<S: PeekStream>(stream: &S) -> bool {
+    let mut buf = [0u8; H2_PREFACE.len()];
+    for _ in 0..10 {
+        let peek = std::future::poll_fn(|cx| stream.poll_peek(cx, &mut buf));
+        let n = match timeout(Duration::from_secs(5), peek).await {
+            Ok(Ok(n)) => n,
+            _ => return false,
+        };
+        if n == 0 || buf[..n] != H2_PREFACE[..n] {
+            return false;
+        }
+        if n == H2_PREFACE.len() {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn head_of_large_file_over_h2() {
+        let root = std::env::temp_dir().join(format!("mchttp-h2-head-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("large.bin"), vec![b'x'; 4 * H2_BRIDGE_BUFFER]).unwrap();
+        let config = Config { data_dir: Some(root.clone()), ..Config::default() };
+
+        let (client_io, server_io) = tokio::io::duplex(H2_BRIDGE_BUFFER);
+        let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        let server = spawn(with_test_config(config, serve_h2(server_io, peer, None)));
+
+        let (mut client, connection) = h2::client::handshake(client_io).await.unwrap();
+        spawn(connection);
+        let request = ::http::Request::head("http://localhost/large.bin").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = timeout(Duration::from_secs(10), response).await.expect("HEAD stalled").unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], (4 * H2_BRIDGE_BUFFER).to_string().as_str());
+        assert!(response.into_body().is_end_stream());
+
+        // The handler has to finish too, not sit on a full bridge
+        drop(client);
+        timeout(Duration::from_secs(10), server).await.expect("HEAD stream never finished").unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_without_length_over_h2() {
+        let root = std::env::temp_dir().join(format!("mchttp-h2-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let vhost = VirtualHost {
+            name: String::from("localhost"),
+            root: Some(root.clone()),
+            upload: true,
+            ..VirtualHost::default()
+        };
+        let limits = Limits { max_upload: 100, ..Limits::default() };
+        let config = Config { vhosts: vec![vhost], limits, ..Config::default() };
+
+        let (client_io, server_io) = tokio::io::duplex(H2_BRIDGE_BUFFER);
+        let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        let server = spawn(with_test_config(config, serve_h2(server_io, peer, Some(String::from("localhost")))));
+        let (client, connection) = h2::client::handshake(client_io).await.unwrap();
+        spawn(connection);
+
+        let put = |name: &str, pieces: Vec<&'static [u8]>| {
+            let mut client = client.clone();
+            let request = ::http::Request::put(format!("https://localhost/{name}")).body(()).unwrap();
+            async move {
+                let mut client = client.ready().await.unwrap();
+                let (response, mut body) = client.send_request(request, false).unwrap();
+                for piece in pieces {
+                    body.send_data(Bytes::from_static(piece), false).unwrap();
+                }
+                body.send_data(Bytes::new(), true).unwrap();
+                timeout(Duration::from_secs(10), response).await.expect("upload stalled").unwrap().status()
+            }
+        };
+        assert_eq!(put("a.txt", vec![b"hello, ", b"", b"world\n"]).await, 201);
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"hello, world\n");
+        assert_eq!(put("b.txt", vec![&[b'x'; 60], &[b'x'; 60]]).await, 413);
+        assert!(!root.join("b.txt").exists());
+
+        drop(client);
+        timeout(Duration::from_secs(10), server).await.unwrap().unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
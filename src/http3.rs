@@ -0,0 +1,324 @@
+use bytes::{Buf, Bytes};
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+use crate::*;
+
+// How long Alt-Svc tells clients to remember the HTTP/3 endpoint
+const ALT_SVC_MAX_AGE: u32 = 86400;
+const H3_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// QUIC server configuration from the same identities and TLS options as the
+// TCP listener, offering only "h3". Their status is published when the TCP
+// acceptor is rebuilt.
+pub fn build_quic_config() -> Option<quinn::ServerConfig> {
+    let (mut tls_config, _, _) = load_tls_config()?;
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let quic_config = match QuicServerConfig::try_from(tls_config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("HTTP/3: TLS configuration unusable for QUIC: {e}");
+            return None;
+        }
+    };
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(quic_config));
+    if let Some(transport) = Arc::get_mut(&mut config.transport) {
+        transport.max_idle_timeout(H3_IDLE_TIMEOUT.try_into().ok());
+    }
+    Some(config)
+}
+
+// HTTP/3 endpoint on a bound UDP socket
+pub fn bind_quic(socket: std::net::UdpSocket) -> Result<quinn::Endpoint> {
+    let config = build_quic_config().ok_or_else(|| Error::msg("HTTP/3 requires a working TLS configuration (-t)"))?;
+    Ok(quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?)
+}
+
+// HTTP/3 listener on a UDP socket. Certificates are reloaded along with the
+// TCP listener's.
+pub async fn quic_listener(endpoint: quinn::Endpoint) -> Result<()> {
+    eprintln!("HTTP/3: listening on UDP {}", endpoint.local_addr()?);
+
+    let mut tls_generation = TLS_GENERATION.subscribe();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = shutdown_started() => {
+                if handed_over() {
+                    // The new process reads from the same socket, so our
+                    // connections' packets can reach it, and it answers
+                    // those with stateless resets. They can't be left to
+                    // finish; close them cleanly instead, which also leaves
+                    // it every packet from now on.
+                    endpoint.close(0u32.into(), b"server upgraded");
+                } else {
+                    // Refuse new connections; those open carry on
+                    endpoint.set_server_config(None);
+                }
+                break;
+            }
+            _ = tls_generation.changed() => {
+                if let Some(config) = build_quic_config() {
+                    endpoint.set_server_config(Some(config));
+                }
+                continue;
+            }
+        };
+        let addr = incoming.remote_address();
+        if CONFIG.verbose {
+            eprintln!("HTTP/3: {:?} connecting", &addr);
+        }
+        let connection = track_connection(addr.into(), "HTTP/3");
+        spawn(with_config(async move {
+            let _connection = connection;
+            let result = match incoming.await {
+                Ok(connection) => serve_h3(connection).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                eprintln!("HTTP/3: {:?}: error: {e}", &addr);
+            }
+            if CONFIG.verbose {
+                eprintln!("HTTP/3: {:?} closed", &addr);
+            }
+        }));
+    }
+    Ok(())
+}
+
+async fn serve_h3(connection: quinn::Connection) -> Result<()> {
+    let client = connection.remote_address();
+    let server_name = connection
+        .handshake_data()
+        .and_then(|h| h.downcast::<HandshakeData>().ok())
+        .and_then(|h| h.server_name);
+    if CONFIG.verbose {
+        eprintln!("HTTP/3: {:?} identity {:?}", &client, &server_name);
+    }
+
+    let mut h3_connection: h3::server::Connection<h3_quinn::Connection, Bytes> =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    let mut streams = JoinSet::<()>::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = h3_connection.accept() => accepted,
+            _ = shutdown_started() => {
+                // GOAWAY, then let the requests already accepted finish
+                let _ = h3_connection.shutdown(0).await;
+                break;
+            }
+        };
+        match accepted {
+            Ok(Some(resolver)) => {
+                let server_name = server_name.clone();
+                streams.spawn(with_config(async move {
+                    let result = match resolver.resolve_request().await {
+                        Ok((request, stream)) => h3_stream(request, stream, client.into(), server_name).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("HTTP/3: {:?}: stream error: {e}", &client);
+                    }
+                }));
+            }
+            Ok(None) => break,
+            Err(e) if e.is_h3_no_error() => break,
+            Err(e) => {
+                while streams.join_next().await.is_some() {}
+                return Err(e.into());
+            }
+        }
+    }
+
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn h3_stream(
+    request: ::http::Request<()>,
+    stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    client: Peer,
+    server_name: Option<String>,
+) -> Result<()> {
+    let (parts, _) = request.into_parts();
+    let is_head = parts.method == ::http::Method::HEAD;
+    let (mut send, mut recv) = stream.split();
+
+    // HTTP/3 doesn't say up front whether a body follows. Without a length
+    // it takes its first data (or the end) to know, unless the client is
+    // waiting for 100 Continue before it sends any.
+    let mut first = None;
+    let expects_continue = parts.headers.get(::http::header::EXPECT).is_some_and(|e| e.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let has_body = if parts.headers.contains_key(::http::header::CONTENT_LENGTH) || expects_continue {
+        true
+    } else {
+        first = recv.recv_data().await?.map(|mut chunk| chunk.copy_to_bytes(chunk.remaining()));
+        first.is_some()
+    };
+    let (mut bridge_read, mut bridge_body, handler) = spawn_bridge(&parts, has_body, client, server_name).await?;
+
+    let request_body = spawn(async move {
+        if let Some(first) = first {
+            bridge_body.write(&first).await?;
+        }
+        while let Some(mut chunk) = recv.recv_data().await? {
+            while chunk.has_remaining() {
+                let n = chunk.chunk().len();
+                bridge_body.write(chunk.chunk()).await?;
+                chunk.advance(n);
+            }
+        }
+        bridge_body.finish().await?;
+        anyhow::Ok(())
+    });
+
+    let response = read_response_head(&mut bridge_read).await?;
+    send.send_response(response).await?;
+
+    if is_head {
+        // As over HTTP/2: drop the body the handler writes for HEAD
+        tokio::io::copy(&mut bridge_read, &mut tokio::io::sink()).await?;
+    } else {
+        let mut buf = vec![0u8; H2_BRIDGE_BUFFER];
+        loop {
+            let n = bridge_read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            send.send_data(Bytes::copy_from_slice(&buf[..n])).await?;
+        }
+    }
+    send.finish().await?;
+
+    request_body.abort();
+    handler.await??;
+    Ok(())
+}
+
+// Alt-Svc response header line advertising the HTTP/3 listener, or nothing
+// if it isn't enabled.
+pub fn alt_svc_header() -> String {
+    match &CONFIG.quic_addr {
+        Some(addr) => format!("Alt-Svc: h3=\":{}\"; ma={}\r\n", addr.port(), ALT_SVC_MAX_AGE),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::QuicClientConfig;
+
+    type H3Client = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    // serve_h3() on a loopback QUIC connection, as the site localhost
+    async fn h3_loopback(config: Config) -> (JoinHandle<Result<()>>, quinn::Connection, H3Client) {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+        let mut server_tls = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        server_tls.alpn_protocols = vec![b"h3".to_vec()];
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_tls).unwrap()));
+        let server_endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server_endpoint.local_addr().unwrap();
+        let server = spawn(with_test_config(config, async move {
+            let connection = server_endpoint.accept().await.unwrap().await.unwrap();
+            serve_h3(connection).await
+        }));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client_tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_tls.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client_endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client_endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(client_tls).unwrap(),
+        )));
+        let connection = client_endpoint.connect(server_addr, "localhost").unwrap().await.unwrap();
+
+        let (mut driver, client) = h3::client::new(h3_quinn::Connection::new(connection.clone())).await.unwrap();
+        spawn(async move { driver.wait_idle().await });
+        (server, connection, client)
+    }
+
+    #[tokio::test]
+    async fn head_of_large_file_over_h3() {
+        // TLS names the site, served from its own directory under -d
+        let root = std::env::temp_dir().join(format!("mchttp-h3-head-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("localhost")).unwrap();
+        std::fs::write(root.join("localhost/large.bin"), vec![b'x'; 4 * H2_BRIDGE_BUFFER]).unwrap();
+        let config = Config { data_dir: Some(root.clone()), ..Config::default() };
+        let (server, connection, mut client) = h3_loopback(config).await;
+
+        let request = ::http::Request::head("https://localhost/large.bin").body(()).unwrap();
+        let mut stream = client.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = timeout(Duration::from_secs(10), stream.recv_response()).await.expect("HEAD stalled").unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], (4 * H2_BRIDGE_BUFFER).to_string().as_str());
+        assert!(stream.recv_data().await.unwrap().is_none());
+
+        // The connection only winds down once the handler has finished
+        drop(client);
+        connection.close(0u32.into(), b"done");
+        timeout(Duration::from_secs(10), server).await.expect("HEAD stream never finished").unwrap().ok();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_without_length_over_h3() {
+        let root = std::env::temp_dir().join(format!("mchttp-h3-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let vhost = VirtualHost {
+            name: String::from("localhost"),
+            root: Some(root.clone()),
+            upload: true,
+            ..VirtualHost::default()
+        };
+        let config = Config { vhosts: vec![vhost], ..Config::default() };
+        let (server, connection, mut client) = h3_loopback(config).await;
+
+        let request = ::http::Request::put("https://localhost/a.txt").body(()).unwrap();
+        let mut stream = client.send_request(request).await.unwrap();
+        stream.send_data(Bytes::from_static(b"hello, ")).await.unwrap();
+        stream.send_data(Bytes::from_static(b"world\n")).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = timeout(Duration::from_secs(10), stream.recv_response()).await.expect("upload stalled").unwrap();
+        assert_eq!(response.status(), 201);
+
+        // And a request without a body isn't given one
+        let request = ::http::Request::get("https://localhost/a.txt").body(()).unwrap();
+        let mut stream = client.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = timeout(Duration::from_secs(10), stream.recv_response()).await.expect("GET stalled").unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend(chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(body, b"hello, world\n");
+
+        drop(client);
+        connection.close(0u32.into(), b"done");
+        timeout(Duration::from_secs(10), server).await.unwrap().ok();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
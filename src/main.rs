@@ -20,7 +20,6 @@ use std::io::BufReader;
 
 use anyhow::{Error, Result};
 use lazy_static::lazy_static;
-use tokio;
 use tokio::net::ToSocketAddrs;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -36,10 +35,8 @@ use rustls::server::{ClientHello, ResolvesServerCert};
 use rustls::ServerConfig;
 use rustls::crypto::aws_lc_rs::sign;
 
-use tokio_rustls::rustls::internal::msgs::handshake::ServerExtension::Protocols;
 use tokio_rustls::rustls::ProtocolVersion::TLSv1_3;
 use tokio_rustls::rustls::SupportedProtocolVersion;
-use tokio_rustls;
 use tokio_rustls::rustls;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tokio_rustls::rustls::pki_types::pem::PemObject;
@@ -57,6 +54,71 @@ use http::*;
 mod mimetype;
 use mimetype::*;
 
+mod selfsigned;
+use selfsigned::*;
+
+mod certstatus;
+use certstatus::*;
+
+mod ocsp;
+use ocsp::*;
+
+mod tls;
+use tls::*;
+
+mod http2;
+use http2::*;
+
+mod http3;
+use http3::*;
+
+mod websocket;
+use websocket::*;
+
+mod events;
+use events::*;
+
+mod proxy;
+use proxy::*;
+
+mod cgi;
+use cgi::*;
+
+mod fastcgi;
+use fastcgi::*;
+
+mod proxyprotocol;
+use proxyprotocol::*;
+
+mod vhost;
+use vhost::*;
+
+mod configfile;
+
+mod reload;
+use reload::*;
+
+mod check;
+use check::*;
+
+mod shutdown;
+use shutdown::*;
+
+mod upgrade;
+use upgrade::*;
+
+mod systemd;
+use systemd::*;
+
+mod tlshelper;
+use tlshelper::*;
+
+mod privileges;
+use privileges::*;
+
+mod unixsocket;
+use unixsocket::*;
+
 
 pub const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const PKG_NAME: &str = env!("CARGO_PKG_NAME");
@@ -65,12 +127,42 @@ pub const COMMIT_ID: &str = env!("GIT_COMMITID");
 //#[tokio::main(flavor="current_thread")]
 #[tokio::main]
 pub async fn main() -> Result<()> {
+    if let Some(status) = run_tls_helper() {
+        process::exit(status);
+    }
+    if env::args().any(|a| a == "--check") {
+        process::exit(check_config());
+    }
+
+    // Parsed first, so --help and --version exit before any of this
+    let config = &*CONFIG;
+    record_executable();
     dbg!(&PKG_NAME, &PKG_VERSION, &COMMIT_ID);
-    dbg!(&*CONFIG);
+    dbg!(config);
+
+    for (path, spec) in &CONFIG.websockets {
+        register_websocket_handler(path, websocket_handler_from_spec(spec)?);
+    }
+
+    if CONFIG.events {
+        start_file_watcher()?;
+    }
+
+    start_health_checks();
 
     let mut tasks = JoinSet::<Result<()>>::new();
 
-    tasks.spawn(async move { listener(&CONFIG.bind_addr).await });
+    start_tls_refresh();
+    start_listeners()?;
+    drop_privileges()?;
+    report_upgrade_ready();
+    notify_ready();
+    tasks.spawn(reload_on_sighup());
+    tasks.spawn(shutdown_on_signal());
+    tasks.spawn(upgrade_on_signal());
+    if let Some(interval) = watchdog_interval() {
+        tasks.spawn(systemd_watchdog(interval));
+    }
 
     // General task completion handler
     // Print a message indicating success or failure. If it's panic,
@@ -79,6 +171,10 @@ pub async fn main() -> Result<()> {
         match join_result {
             Ok(result) => {
                 eprintln!("Task completed: {:?}", result);
+                // Drained (or out of time): whatever is left goes with the runtime
+                if shutting_down() {
+                    break;
+                }
                 continue;
             },
             Err(join_error) => {
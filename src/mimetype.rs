@@ -1,6 +1,6 @@
 use crate::*;
 
-pub fn lookup_mimetype(path: &PathBuf) -> &'static str {
+pub fn lookup_mimetype(path: &Path) -> &'static str {
     match path.extension().unwrap_or_default().to_str() {
         Some("doc") => "application/msword",
         Some("md") => "text/markdown",
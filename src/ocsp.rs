@@ -0,0 +1,478 @@
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use aws_lc_rs::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use x509_parser::prelude::*;
+use x509_parser::oid_registry::*;
+use crate::*;
+
+// How long a fetched response is stapled before asking the responder again.
+// Responders typically issue responses valid for several days.
+const OCSP_REFRESH: Duration = Duration::from_secs(4 * 60 * 60);
+const OCSP_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const OCSP_MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+// Leeway for a responder whose clock runs ahead of ours
+const OCSP_CLOCK_SKEW: i64 = 5 * 60;
+
+// id-pkix-ocsp-basic (1.3.6.1.5.5.7.48.1.1)
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+// Responder URL and DER OCSPRequest for a certificate, with the DER
+// certificate and issuer the responses get checked against
+#[derive(Debug, Clone)]
+struct OcspTarget {
+    name: String,
+    url: String,
+    request: Vec<u8>,
+    leaf: Vec<u8>,
+    issuer: Vec<u8>,
+}
+
+// A checked response, when it was fetched and when it stops being good
+// (unix seconds)
+struct OcspStaple {
+    fetched: Instant,
+    expires: i64,
+    response: Vec<u8>,
+}
+
+lazy_static! {
+    // Fetched responses, keyed by end-entity certificate fingerprint
+    static ref OCSP_CACHE: Mutex<HashMap<String, OcspStaple>> = Mutex::new(HashMap::new());
+
+    // Certificates seen at the last (re)load that have an OCSP responder
+    static ref OCSP_TARGETS: Mutex<HashMap<String, OcspTarget>> = Mutex::new(HashMap::new());
+}
+
+// OCSP state that goes with a freshly loaded set of certificates: their
+// responders, and the fingerprints of those that loaded. Put in place by
+// commit() once they're served.
+#[derive(Default)]
+pub struct OcspUpdate {
+    targets: HashMap<String, OcspTarget>,
+    fingerprints: Vec<String>,
+}
+
+impl OcspUpdate {
+    pub fn loaded(&mut self, fingerprint: String) {
+        self.fingerprints.push(fingerprint);
+    }
+
+    // Keep the responses and responders of the loaded certificates only
+    pub fn commit(mut self) {
+        let fingerprints = self.fingerprints;
+        self.targets.retain(|fingerprint, _| fingerprints.contains(fingerprint));
+        *OCSP_TARGETS.lock().unwrap() = self.targets;
+        OCSP_CACHE.lock().unwrap().retain(|fingerprint, _| fingerprints.contains(fingerprint));
+    }
+}
+
+// The response to staple for a freshly loaded chain: a fetched one if we
+// have it, otherwise a .ocsp file alongside the certificate (e.g.
+// fullchain.ocsp next to fullchain.pem).
+pub fn stapled_ocsp(
+    name: &str,
+    source: &IdentitySource,
+    certs: &[CertificateDer<'_>],
+    update: &mut OcspUpdate,
+) -> Option<Vec<u8>> {
+    let leaf = certs.first()?;
+    let fingerprint = sha256_fingerprint(leaf);
+
+    if CONFIG.ocsp_fetch {
+        match ocsp_target(name, certs) {
+            Ok(Some(target)) => {
+                update.targets.insert(fingerprint.clone(), target);
+            }
+            Ok(None) => (),
+            Err(e) => eprintln!("OCSP: {name}: can't build request: {e}"),
+        }
+        // A response past its nextUpdate is no longer stapled, even if
+        // the responder can't be reached for a new one
+        if let Some(staple) = OCSP_CACHE.lock().unwrap().get(&fingerprint) {
+            if staple.expires > unix_now() {
+                return Some(staple.response.clone());
+            }
+        }
+    }
+
+    let ocsp_path = source.path().with_extension("ocsp");
+    let response = tls_file_read(&ocsp_path).ok()?;
+    let checked = match certs.get(1) {
+        Some(issuer) => verify_ocsp_response(&response, leaf, issuer).map(|_| ()),
+        None => match ocsp_response_status(&response) {
+            Some(0) => Ok(()),
+            status => Err(anyhow::anyhow!("unsuccessful response (status {:?})", status)),
+        },
+    };
+    match checked {
+        Ok(()) => Some(response),
+        Err(e) => {
+            eprintln!("OCSP: {}: ignoring response: {e}", ocsp_path.to_string_lossy());
+            None
+        }
+    }
+}
+
+// Fetch responses for every certificate whose cached response is missing or
+// stale, or halfway from being fetched to its nextUpdate. Returns true if
+// anything changed, so the caller knows to rebuild the TLS acceptor.
+pub async fn refresh_ocsp() -> bool {
+    let now = unix_now();
+    let targets: Vec<(String, OcspTarget)> = {
+        let cache = OCSP_CACHE.lock().unwrap();
+        OCSP_TARGETS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(fingerprint, _)| {
+                cache.get(*fingerprint).is_none_or(|staple| {
+                    let remaining = Duration::from_secs(staple.expires.saturating_sub(now).max(0) as u64);
+                    staple.fetched.elapsed() > OCSP_REFRESH.min(remaining / 2)
+                })
+            })
+            .map(|(f, t)| (f.clone(), t.clone()))
+            .collect()
+    };
+
+    let mut updated = false;
+    for (fingerprint, target) in targets {
+        match timeout(OCSP_FETCH_TIMEOUT, ocsp_fetch(&target.url, &target.request)).await {
+            Ok(Ok(response)) => match verify_ocsp_response(&response, &target.leaf, &target.issuer) {
+                Ok(next_update) => {
+                    if CONFIG.verbose {
+                        eprintln!("OCSP: {}: fetched {} byte response from {}", &target.name, response.len(), &target.url);
+                    }
+                    // Without a nextUpdate the responder may have news at
+                    // any time, so the response is only good until the
+                    // next scheduled refresh (plus a retry or two)
+                    let expires = next_update.unwrap_or(now + 2 * OCSP_REFRESH.as_secs() as i64);
+                    let staple = OcspStaple { fetched: Instant::now(), expires, response };
+                    OCSP_CACHE.lock().unwrap().insert(fingerprint, staple);
+                    updated = true;
+                }
+                Err(e) => eprintln!("OCSP: {}: rejected response from {}: {e}", &target.name, &target.url),
+            },
+            Ok(Err(e)) => eprintln!("OCSP: {}: fetch from {} failed: {e}", &target.name, &target.url),
+            Err(_) => eprintln!("OCSP: {}: fetch from {} timed out", &target.name, &target.url),
+        }
+    }
+    updated
+}
+
+// Responder URL from the leaf's Authority Information Access extension and
+// the matching request, which needs the issuer (second certificate in chain).
+fn ocsp_target(name: &str, certs: &[CertificateDer<'_>]) -> Result<Option<OcspTarget>> {
+    let (Some(leaf_der), Some(issuer_der)) = (certs.first(), certs.get(1)) else {
+        return Ok(None);
+    };
+    let (_, leaf) = X509Certificate::from_der(leaf_der)
+        .map_err(|e| anyhow::anyhow!("failed to parse certificate: {e}"))?;
+    let (_, issuer) = X509Certificate::from_der(issuer_der)
+        .map_err(|e| anyhow::anyhow!("failed to parse issuer certificate: {e}"))?;
+
+    let url = leaf.extensions().iter().find_map(|ext| match ext.parsed_extension() {
+        ParsedExtension::AuthorityInfoAccess(aia) => aia.iter().find_map(|desc| {
+            match (&desc.access_method, &desc.access_location) {
+                (method, GeneralName::URI(uri)) if *method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP => Some(uri.to_string()),
+                _ => None,
+            }
+        }),
+        _ => None,
+    });
+    let Some(url) = url else {
+        return Ok(None);
+    };
+
+    // CertID ::= SEQUENCE { hashAlgorithm, issuerNameHash, issuerKeyHash, serialNumber }
+    let sha1_algorithm = der(0x30, &[der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]), der(0x05, &[])].concat());
+    let cert_id = der(0x30, &[
+        sha1_algorithm,
+        der(0x04, &Sha1::digest(issuer.subject().as_raw())),
+        der(0x04, &Sha1::digest(&issuer.public_key().subject_public_key.data)),
+        der(0x02, leaf.raw_serial()),
+    ].concat());
+    // OCSPRequest ::= SEQUENCE { TBSRequest ::= SEQUENCE { requestList SEQUENCE OF Request ::= SEQUENCE { CertID } } }
+    let request = der(0x30, &der(0x30, &der(0x30, &der(0x30, &cert_id))));
+
+    Ok(Some(OcspTarget {
+        name: String::from(name),
+        url,
+        request,
+        leaf: leaf_der.to_vec(),
+        issuer: issuer_der.to_vec(),
+    }))
+}
+
+// Check that a response really vouches for leaf: a successful basic
+// response, signed by the issuer or by a responder the issuer delegated to,
+// giving a good status for leaf's CertID that is current. Returns the
+// nextUpdate (unix seconds), if the response has one.
+fn verify_ocsp_response(response: &[u8], leaf_der: &[u8], issuer_der: &[u8]) -> Result<Option<i64>> {
+    let malformed = || anyhow::anyhow!("malformed OCSP response");
+    let (_, leaf) = X509Certificate::from_der(leaf_der)
+        .map_err(|e| anyhow::anyhow!("failed to parse certificate: {e}"))?;
+    let (_, issuer) = X509Certificate::from_der(issuer_der)
+        .map_err(|e| anyhow::anyhow!("failed to parse issuer certificate: {e}"))?;
+
+    // OCSPResponse ::= SEQUENCE { responseStatus, responseBytes [0] EXPLICIT SEQUENCE { responseType, response } }
+    let mut ocsp_response = DerReader(DerReader(response).expect(0x30).ok_or_else(malformed)?);
+    match ocsp_response.expect(0x0a) {
+        Some([0]) => (),
+        status => return Err(anyhow::anyhow!("unsuccessful response (status {:?})", status)),
+    }
+    let mut response_bytes = DerReader(
+        ocsp_response.expect(0xa0).and_then(|bytes| DerReader(bytes).expect(0x30)).ok_or_else(malformed)?,
+    );
+    if response_bytes.expect(0x06) != Some(OCSP_BASIC) {
+        return Err(anyhow::anyhow!("not a basic OCSP response"));
+    }
+
+    // BasicOCSPResponse ::= SEQUENCE { tbsResponseData, signatureAlgorithm, signature, certs [0] EXPLICIT OPTIONAL }
+    let mut basic = DerReader(
+        response_bytes.expect(0x04).and_then(|basic| DerReader(basic).expect(0x30)).ok_or_else(malformed)?,
+    );
+    let (0x30, tbs, tbs_raw) = basic.element().ok_or_else(malformed)? else {
+        return Err(malformed());
+    };
+    let algorithm = basic.expect(0x30).and_then(|a| DerReader(a).expect(0x06)).ok_or_else(malformed)?;
+    let Some([0, signature @ ..]) = basic.expect(0x03) else {
+        return Err(malformed());
+    };
+    let mut responder_certs = Vec::new();
+    if let Some(certs) = basic.optional(0xa0) {
+        let mut certs = DerReader(DerReader(certs).expect(0x30).ok_or_else(malformed)?);
+        while let Some((_, _, raw)) = certs.element() {
+            responder_certs.push(raw);
+        }
+    }
+
+    let signed_by = |signer: &X509Certificate| verify_signature(signer, algorithm, tbs_raw, signature).is_ok();
+    if !signed_by(&issuer) {
+        // A delegated responder carries a certificate for OCSP signing
+        // that the issuer signed
+        let delegated = responder_certs.iter().any(|der| {
+            let Ok((_, responder)) = X509Certificate::from_der(der) else {
+                return false;
+            };
+            responder.issuer().as_raw() == issuer.subject().as_raw()
+                && matches!(responder.extended_key_usage(), Ok(Some(eku)) if eku.value.ocsp_signing)
+                && responder.validity().is_valid()
+                && verify_signature(
+                    &issuer,
+                    responder.signature_algorithm.algorithm.as_bytes(),
+                    responder.tbs_certificate.as_ref(),
+                    &responder.signature_value.data,
+                )
+                .is_ok()
+                && signed_by(&responder)
+        });
+        if !delegated {
+            return Err(anyhow::anyhow!("not signed by the issuer or a responder it authorised"));
+        }
+    }
+
+    // ResponseData ::= SEQUENCE { version [0] DEFAULT, responderID, producedAt, responses SEQUENCE OF SingleResponse, ... }
+    let mut tbs = DerReader(tbs);
+    tbs.optional(0xa0);
+    tbs.element().ok_or_else(malformed)?;
+    tbs.expect(0x18).ok_or_else(malformed)?;
+    let mut responses = DerReader(tbs.expect(0x30).ok_or_else(malformed)?);
+
+    // SingleResponse ::= SEQUENCE { certID, certStatus, thisUpdate, nextUpdate [0] EXPLICIT OPTIONAL, ... }
+    while let Some(single) = responses.expect(0x30) {
+        let mut single = DerReader(single);
+        if !cert_id_matches(single.expect(0x30).ok_or_else(malformed)?, &leaf, &issuer) {
+            continue;
+        }
+        match single.element().ok_or_else(malformed)? {
+            (0x80, _, _) => (),
+            (0xa1, _, _) => return Err(anyhow::anyhow!("certificate is revoked")),
+            _ => return Err(anyhow::anyhow!("certificate status is unknown")),
+        }
+        let this_update = single.element().and_then(|(_, _, raw)| der_time(raw)).ok_or_else(malformed)?;
+        let next_update = match single.optional(0xa0) {
+            Some(next_update) => Some(der_time(next_update).ok_or_else(malformed)?),
+            None => None,
+        };
+
+        let now = unix_now();
+        if this_update > now + OCSP_CLOCK_SKEW {
+            return Err(anyhow::anyhow!("thisUpdate is in the future"));
+        }
+        if next_update.is_some_and(|next_update| next_update <= now) {
+            return Err(anyhow::anyhow!("response expired (nextUpdate has passed)"));
+        }
+        return Ok(next_update);
+    }
+    Err(anyhow::anyhow!("response doesn't cover this certificate"))
+}
+
+// Does a CertID (SHA-1 or SHA-256) name leaf as issued by issuer?
+fn cert_id_matches(cert_id: &[u8], leaf: &X509Certificate, issuer: &X509Certificate) -> bool {
+    let mut cert_id = DerReader(cert_id);
+    let Some(algorithm) = cert_id.expect(0x30).and_then(|a| DerReader(a).expect(0x06)) else {
+        return false;
+    };
+    let digest: fn(&[u8]) -> Vec<u8> = if algorithm == OID_HASH_SHA1.as_bytes() {
+        |data| Sha1::digest(data).to_vec()
+    } else if algorithm == OID_NIST_HASH_SHA256.as_bytes() {
+        |data| Sha256::digest(data).to_vec()
+    } else {
+        return false;
+    };
+    cert_id.expect(0x04) == Some(digest(issuer.subject().as_raw()).as_slice())
+        && cert_id.expect(0x04) == Some(digest(&issuer.public_key().subject_public_key.data).as_slice())
+        && cert_id.expect(0x02) == Some(leaf.raw_serial())
+}
+
+// Check a signature made with signer's key, for the algorithms CAs and
+// OCSP responders use
+fn verify_signature(signer: &X509Certificate, algorithm: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    let key = signer.public_key();
+    let curve = key.algorithm.parameters.as_ref().and_then(|p| p.as_oid().ok());
+    let p256 = curve.as_ref() == Some(&OID_EC_P256);
+    let p384 = curve.as_ref() == Some(&OID_NIST_EC_P384);
+
+    let verification: &'static dyn VerificationAlgorithm = match algorithm {
+        a if a == OID_PKCS1_SHA256WITHRSA.as_bytes() => &signature::RSA_PKCS1_2048_8192_SHA256,
+        a if a == OID_PKCS1_SHA384WITHRSA.as_bytes() => &signature::RSA_PKCS1_2048_8192_SHA384,
+        a if a == OID_PKCS1_SHA512WITHRSA.as_bytes() => &signature::RSA_PKCS1_2048_8192_SHA512,
+        a if a == OID_SIG_ECDSA_WITH_SHA256.as_bytes() && p256 => &signature::ECDSA_P256_SHA256_ASN1,
+        a if a == OID_SIG_ECDSA_WITH_SHA256.as_bytes() && p384 => &signature::ECDSA_P384_SHA256_ASN1,
+        a if a == OID_SIG_ECDSA_WITH_SHA384.as_bytes() && p256 => &signature::ECDSA_P256_SHA384_ASN1,
+        a if a == OID_SIG_ECDSA_WITH_SHA384.as_bytes() && p384 => &signature::ECDSA_P384_SHA384_ASN1,
+        a if a == OID_SIG_ED25519.as_bytes() => &signature::ED25519,
+        _ => return Err(anyhow::anyhow!("unsupported signature algorithm")),
+    };
+    UnparsedPublicKey::new(verification, &key.subject_public_key.data)
+        .verify(message, signature)
+        .map_err(|_| anyhow::anyhow!("bad signature"))
+}
+
+// UTCTime or GeneralizedTime element as unix seconds
+fn der_time(raw: &[u8]) -> Option<i64> {
+    ASN1Time::from_der(raw).ok().map(|(_, time)| time.timestamp())
+}
+
+// Reads the DER elements of a SEQUENCE's contents one after another
+struct DerReader<'a>(&'a [u8]);
+
+impl<'a> DerReader<'a> {
+    // The next element as (tag, contents, whole encoding)
+    fn element(&mut self) -> Option<(u8, &'a [u8], &'a [u8])> {
+        let input = self.0;
+        let tag = *input.first()?;
+        let (len, header) = match *input.get(1)? as usize {
+            len if len < 0x80 => (len, 2),
+            long => {
+                let count = long & 0x7f;
+                if count == 0 || count > 4 {
+                    return None;
+                }
+                let len = input.get(2..2 + count)?.iter().fold(0, |len, &b| len << 8 | b as usize);
+                (len, 2 + count)
+            }
+        };
+        let end = header.checked_add(len)?;
+        let content = input.get(header..end)?;
+        self.0 = &input[end..];
+        Some((tag, content, &input[..end]))
+    }
+
+    // The contents of the next element, which must have this tag
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.element()? {
+            (t, content, _) if t == tag => Some(content),
+            _ => None,
+        }
+    }
+
+    // The contents of the next element if it has this tag (OPTIONAL and
+    // DEFAULT fields), leaving it in place otherwise
+    fn optional(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.0.first() != Some(&tag) {
+            return None;
+        }
+        self.expect(tag)
+    }
+}
+
+// DER tag-length-value
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+// responseStatus from OCSPResponse ::= SEQUENCE { responseStatus ENUMERATED, ... }
+fn ocsp_response_status(response: &[u8]) -> Option<u8> {
+    if response.first() != Some(&0x30) {
+        return None;
+    }
+    let header_len = match *response.get(1)? {
+        l if l < 0x80 => 2,
+        l => 2 + (l & 0x7f) as usize,
+    };
+    match response.get(header_len..header_len + 3)? {
+        [0x0a, 0x01, status] => Some(*status),
+        _ => None,
+    }
+}
+
+// Plain HTTP/1.0 POST to the responder (RFC 6960 appendix A).
+async fn ocsp_fetch(url: &str, request: &[u8]) -> Result<Vec<u8>> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow::anyhow!("only http:// responders are supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) if !p.contains(']') => (h, p.parse::<u16>()?),
+        _ => (authority, 80),
+    };
+
+    let mut stream = TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+    stream
+        .write_all(
+            format!(
+                "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\nUser-Agent: {}/{}\r\n\r\n",
+                path,
+                authority,
+                request.len(),
+                PKG_NAME,
+                PKG_VERSION
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(request).await?;
+
+    let mut response = Vec::new();
+    stream.take(OCSP_MAX_RESPONSE_BYTES).read_to_end(&mut response).await?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("malformed HTTP response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split(' ').nth(1) != Some("200") {
+        return Err(anyhow::anyhow!("responder said {status_line}"));
+    }
+
+    let body = response[split + 4..].to_vec();
+    match ocsp_response_status(&body) {
+        Some(0) => Ok(body),
+        status => Err(anyhow::anyhow!("unsuccessful OCSP response (status {:?})", status)),
+    }
+}
@@ -0,0 +1,170 @@
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::sync::OnceLock;
+use crate::*;
+
+// The directory we're confined to by --chroot, as it was named outside
+static CHROOT: OnceLock<PathBuf> = OnceLock::new();
+
+// Who --user and --group say to run as
+#[derive(Debug)]
+pub struct RunAs {
+    pub user: Option<(String, libc::uid_t)>,
+    pub gid: libc::gid_t,
+    pub group: String,
+}
+
+impl fmt::Display for RunAs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((user, uid)) = &self.user {
+            write!(f, "user {user} (uid {uid}), ")?;
+        }
+        write!(f, "group {} (gid {})", self.group, self.gid)
+    }
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| anyhow::anyhow!("{name}: not a valid name"))
+}
+
+// A user by name or number: its name, uid and primary group. Looked up at
+// startup, before anything else runs that might use getpwnam().
+pub fn lookup_user(spec: &str) -> Result<(String, libc::uid_t, libc::gid_t)> {
+    let entry = match spec.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => unsafe { libc::getpwnam(c_string(spec)?.as_ptr()) },
+    };
+    if entry.is_null() {
+        return Err(anyhow::anyhow!("{spec}: no such user"));
+    }
+    let entry = unsafe { &*entry };
+    let name = unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned();
+    Ok((name, entry.pw_uid, entry.pw_gid))
+}
+
+pub fn lookup_group(spec: &str) -> Result<(String, libc::gid_t)> {
+    let entry = match spec.parse::<libc::gid_t>() {
+        Ok(gid) => unsafe { libc::getgrgid(gid) },
+        Err(_) => unsafe { libc::getgrnam(c_string(spec)?.as_ptr()) },
+    };
+    if entry.is_null() {
+        return match spec.parse::<libc::gid_t>() {
+            Ok(gid) => Ok((spec.to_string(), gid)),
+            Err(_) => Err(anyhow::anyhow!("{spec}: no such group")),
+        };
+    }
+    let entry = unsafe { &*entry };
+    let name = unsafe { CStr::from_ptr(entry.gr_name) }.to_string_lossy().into_owned();
+    Ok((name, entry.gr_gid))
+}
+
+// The user and group to switch to, if any: --group on its own changes only
+// the group
+pub fn run_as(config: &Config) -> Result<Option<RunAs>> {
+    let user = config.user.as_deref().map(lookup_user).transpose().map_err(|e| anyhow::anyhow!("--user {e}"))?;
+    let group = config.group.as_deref().map(lookup_group).transpose().map_err(|e| anyhow::anyhow!("--group {e}"))?;
+    Ok(match (user, group) {
+        (None, None) => None,
+        (user, Some((group, gid))) => Some(RunAs {
+            user: user.map(|(name, uid, _)| (name, uid)),
+            gid,
+            group,
+        }),
+        (Some((name, uid, gid)), None) => Some(RunAs {
+            group: lookup_group(&gid.to_string()).map_or(gid.to_string(), |(group, _)| group),
+            user: Some((name, uid)),
+            gid,
+        }),
+    })
+}
+
+fn os_error(what: &str) -> Error {
+    anyhow::anyhow!("{what}: {}", std::io::Error::last_os_error())
+}
+
+fn change_root(root: &Path) -> Result<()> {
+    let path = c_string(&root.to_string_lossy())?;
+    if unsafe { libc::chroot(path.as_ptr()) } == -1 {
+        return Err(os_error(&format!("chroot {}", root.to_string_lossy())));
+    }
+    std::env::set_current_dir("/")?;
+    let _ = CHROOT.set(root.to_path_buf());
+    Ok(())
+}
+
+fn change_user(run_as: &RunAs) -> Result<()> {
+    let groups_set = match &run_as.user {
+        // The user's supplementary groups too (ssl-cert, say)
+        Some((user, _)) => unsafe { libc::initgroups(c_string(user)?.as_ptr(), run_as.gid) },
+        None => unsafe { libc::setgroups(1, &run_as.gid) },
+    };
+    if groups_set == -1 {
+        return Err(os_error("setgroups"));
+    }
+    if unsafe { libc::setgid(run_as.gid) } == -1 {
+        return Err(os_error(&format!("setgid {}", run_as.gid)));
+    }
+    if let Some((_, uid)) = &run_as.user {
+        if unsafe { libc::setuid(*uid) } == -1 {
+            return Err(os_error(&format!("setuid {uid}")));
+        }
+        // Make sure there's no way back
+        if *uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::msg("could still switch back to root after setuid"));
+        }
+    }
+    Ok(())
+}
+
+// Once listening with identities loaded: start the TLS helper, confine
+// ourselves to the data directory and switch user, as configured. Running on
+// as root is refused without --allow-root. A process started by an upgrade
+// may already be the user it's meant to be.
+pub fn drop_privileges() -> Result<()> {
+    let run_as = run_as(&CONFIG)?;
+    let is_root = unsafe { libc::geteuid() } == 0;
+
+    if is_root && (run_as.is_some() || CONFIG.chroot) && CONFIG.tls.is_some() {
+        let pid = start_tls_helper(&CONFIG)?;
+        eprintln!("Privileges: TLS files read from now on by a helper (PID {pid}) running as root");
+    }
+    if CONFIG.chroot {
+        let root = CONFIG.data_dir.as_ref().ok_or_else(|| Error::msg("--chroot needs a data directory (-d)"))?;
+        if !is_root {
+            return Err(Error::msg("--chroot needs to be started as root"));
+        }
+        change_root(root)?;
+        eprintln!("Privileges: confined to {}", root.to_string_lossy());
+    }
+    if let Some(run_as) = &run_as {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let switched = run_as.user.as_ref().is_none_or(|(_, wanted)| *wanted == uid) && run_as.gid == gid;
+        if is_root && !switched {
+            change_user(run_as)?;
+            eprintln!("Privileges: running as {run_as}");
+        } else if !switched {
+            return Err(anyhow::anyhow!("can't switch to {run_as}: not running as root"));
+        }
+    }
+
+    if unsafe { libc::geteuid() } == 0 && !CONFIG.allow_root {
+        return Err(Error::msg(
+            "refusing to run as root: give --user (and --group) to switch once listening, or --allow-root",
+        ));
+    }
+    Ok(())
+}
+
+pub fn chrooted() -> bool {
+    CHROOT.get().is_some()
+}
+
+// Where a path named in the configuration is now: inside a chroot, a path
+// under the data directory is relative to /, and anything else is out of
+// reach (and left to fail as not found)
+pub fn chroot_path(path: &Path) -> Cow<'_, Path> {
+    match CHROOT.get().and_then(|root| path.strip_prefix(root).ok()) {
+        Some(inside) => Cow::Owned(Path::new("/").join(inside)),
+        None => Cow::Borrowed(path),
+    }
+}
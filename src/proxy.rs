@@ -0,0 +1,962 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use tokio::io::AsyncBufRead;
+use tokio::net::UnixStream;
+use crate::*;
+
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Covers sending the request and waiting for the upstream's response head
+const PROXY_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+const PROXY_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+const PROXY_MAX_FAILS: u32 = 3;
+const PROXY_EJECT_TIME: Duration = Duration::from_secs(30);
+const PROXY_KEEPALIVE: usize = 8;
+// Idle pooled connections are dropped after this, before most upstreams
+// would time them out themselves
+const PROXY_POOL_IDLE: Duration = Duration::from_secs(15);
+
+// Hop-by-hop headers (RFC 9110 7.6.1) that are never forwarded either way
+const PROXY_HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "expect",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Tcp(addr) => write!(f, "{}", addr),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl Upstream {
+    // host:port or unix:/path/to/socket
+    pub fn parse(spec: &str) -> Result<Upstream> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            return Ok(Upstream::Unix(PathBuf::from(path)));
+        }
+        if spec.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) {
+            return Err(anyhow::anyhow!("upstream {spec} should be host:port or unix:/path"));
+        }
+        Ok(Upstream::Tcp(String::from(spec)))
+    }
+
+    pub async fn connect(&self) -> std::io::Result<Box<dyn IoStream>> {
+        Ok(match self {
+            Upstream::Tcp(addr) => Box::new(TcpStream::connect(addr.as_str()).await?),
+            Upstream::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        })
+    }
+
+    // Host header for requests we originate (health checks)
+    fn host(&self) -> &str {
+        match self {
+            Upstream::Tcp(addr) => addr,
+            Upstream::Unix(_) => "localhost",
+        }
+    }
+}
+
+// How a route with several upstreams picks one per request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalancePolicy {
+    RoundRobin,
+    LeastConnections,
+    // Rendezvous hash of the client address: a client sticks to one
+    // upstream, and only clients of an upstream that goes away move.
+    ClientHash,
+}
+
+type PooledConnection = BufStream<Box<dyn IoStream>>;
+
+// An upstream and what we know about it at runtime
+pub struct Backend {
+    pub upstream: Upstream,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    healthy: AtomicBool,
+    pool: Mutex<Vec<(Instant, PooledConnection)>>,
+    retired: AtomicBool,
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backend")
+            .field("upstream", &self.upstream)
+            .field("active", &self.active.load(Ordering::Relaxed))
+            .field("failures", &self.failures.load(Ordering::Relaxed))
+            .field("healthy", &self.healthy.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Backend {
+    fn new(upstream: Upstream) -> Backend {
+        Backend {
+            upstream,
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+            pool: Mutex::new(Vec::new()),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    // Passing health checks and not ejected after failures
+    fn available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self.ejected_until.lock().unwrap().is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    // Count a connect/exchange failure, ejecting the upstream for a while
+    // once there have been too many in a row.
+    fn failed(&self, route: &ProxyRoute) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if route.max_fails > 0 && failures >= route.max_fails {
+            self.failures.store(0, Ordering::Relaxed);
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + route.eject_time);
+            eprintln!(
+                "Proxy: {} {}: ejected for {:?} after {} consecutive failures",
+                route.display_prefix(),
+                &self.upstream,
+                route.eject_time,
+                failures
+            );
+        }
+    }
+
+    fn take_pooled(&self) -> Option<PooledConnection> {
+        let mut pool = self.pool.lock().unwrap();
+        while let Some((idle_since, connection)) = pool.pop() {
+            if idle_since.elapsed() < PROXY_POOL_IDLE {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    fn return_to_pool(&self, connection: PooledConnection, limit: usize) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.retain(|(idle_since, _)| idle_since.elapsed() < PROXY_POOL_IDLE);
+        if pool.len() < limit && !self.retired.load(Ordering::Relaxed) {
+            pool.push((Instant::now(), connection));
+        }
+    }
+}
+
+// Counts a request against an upstream for least-connections while alive
+struct ActiveRequest<'a>(&'a Backend);
+
+impl<'a> ActiveRequest<'a> {
+    fn new(backend: &'a Backend) -> ActiveRequest<'a> {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest(backend)
+    }
+}
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A path prefix forwarded to one or more upstreams, from
+// -P /prefix=upstream[,upstream...][;option=value...] where each upstream is
+// host:port[/base] or unix:/path/to/socket. With a base, the prefix is
+// replaced by it in the forwarded request line.
+#[derive(Debug)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub backends: Vec<Backend>,
+    pub base: Option<String>,
+    pub policy: BalancePolicy,
+    pub health_path: Option<String>,
+    pub health_interval: Duration,
+    pub max_fails: u32,
+    pub eject_time: Duration,
+    pub keepalive: usize,
+    next: AtomicUsize,
+}
+
+impl ProxyRoute {
+    pub fn usage() {
+        println!("       -P, --proxy /prefix=host:port[/base][,host:port...][;option=value...]");
+        println!("       -P, --proxy /prefix=unix:/path/to/socket[,...][;option=value...]");
+        println!("                     reverse proxy requests under /prefix to upstream HTTP/1.1 servers");
+        println!("                     (with /base, the prefix is replaced by it in the forwarded request)");
+        println!("                     options: policy=round-robin|least-conn|hash (client address)");
+        println!("                              health=/path        active health check of each upstream");
+        println!("                              interval=seconds    between health checks ({})", PROXY_HEALTH_INTERVAL.as_secs());
+        println!("                              fails=n             consecutive failures before ejection ({}, 0=never)", PROXY_MAX_FAILS);
+        println!("                              eject=seconds       how long an upstream stays ejected ({})", PROXY_EJECT_TIME.as_secs());
+        println!("                              keepalive=n         idle connections kept per upstream ({})", PROXY_KEEPALIVE);
+    }
+
+    pub fn parse(spec: &str) -> Result<ProxyRoute> {
+        let mut parts = spec.split(';');
+        let (prefix, upstreams) = parts
+            .next()
+            .and_then(|route| route.split_once('='))
+            .ok_or_else(|| anyhow::anyhow!("expected /prefix=upstream, got {spec}"))?;
+        if !prefix.starts_with('/') {
+            return Err(anyhow::anyhow!("proxy prefix {prefix} should start with /"));
+        }
+
+        let mut route = ProxyRoute {
+            prefix: String::from(prefix.trim_end_matches('/')),
+            backends: Vec::new(),
+            base: None,
+            policy: BalancePolicy::RoundRobin,
+            health_path: None,
+            health_interval: PROXY_HEALTH_INTERVAL,
+            max_fails: PROXY_MAX_FAILS,
+            eject_time: PROXY_EJECT_TIME,
+            keepalive: PROXY_KEEPALIVE,
+            next: AtomicUsize::new(0),
+        };
+
+        for upstream in upstreams.split(',').map(str::trim).filter(|u| !u.is_empty()) {
+            let (upstream, base) = match upstream.find('/') {
+                Some(i) if !upstream.starts_with("unix:") => {
+                    (Upstream::parse(&upstream[..i])?, Some(String::from(&upstream[i..])))
+                }
+                _ => (Upstream::parse(upstream)?, None),
+            };
+            if let Some(base) = base {
+                if route.base.as_ref().is_some_and(|b| *b != base) {
+                    return Err(anyhow::anyhow!("proxy upstreams for {prefix} have different base paths"));
+                }
+                route.base = Some(base);
+            }
+            route.backends.push(Backend::new(upstream));
+        }
+        if route.backends.is_empty() {
+            return Err(anyhow::anyhow!("no upstreams for proxy prefix {prefix}"));
+        }
+
+        for option in parts {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected proxy option key=value, got {option}"))?;
+            let seconds = || -> Result<Duration> { Ok(Duration::from_secs(value.parse()?)) };
+            match key.trim() {
+                "policy" => {
+                    route.policy = match value {
+                        "round-robin" => BalancePolicy::RoundRobin,
+                        "least-conn" => BalancePolicy::LeastConnections,
+                        "hash" => BalancePolicy::ClientHash,
+                        _ => return Err(anyhow::anyhow!("unknown proxy policy {value}")),
+                    }
+                }
+                "health" => route.health_path = Some(String::from(value)),
+                "interval" => route.health_interval = seconds()?,
+                "fails" => route.max_fails = value.parse()?,
+                "eject" => route.eject_time = seconds()?,
+                "keepalive" => route.keepalive = value.parse()?,
+                _ => return Err(anyhow::anyhow!("unknown proxy option {key}")),
+            }
+        }
+        Ok(route)
+    }
+
+    fn display_prefix(&self) -> &str {
+        if self.prefix.is_empty() {
+            "/"
+        } else {
+            &self.prefix
+        }
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        match url.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty(),
+            None => false,
+        }
+    }
+
+    // Request target to send upstream
+    fn rewrite(&self, target: &str) -> String {
+        let rest = match (&self.base, target.strip_prefix(&self.prefix)) {
+            (Some(_), Some(rest)) => rest,
+            _ => return String::from(target),
+        };
+        let base = self.base.as_deref().unwrap_or_default().trim_end_matches('/');
+        match rest.chars().next() {
+            Some('/') => format!("{}{}", base, rest),
+            Some(_) => format!("{}/{}", base, rest),
+            None if base.is_empty() => String::from("/"),
+            None => String::from(base),
+        }
+    }
+
+    // Upstreams in the order they should be tried for this client: the
+    // policy's choice first, then the other available ones. If none are
+    // available, all of them, rather than refusing outright.
+    fn candidates(&self, client: std::net::IpAddr) -> Vec<&Backend> {
+        let mut candidates: Vec<&Backend> = self.backends.iter().filter(|b| b.available()).collect();
+        if candidates.is_empty() {
+            candidates = self.backends.iter().collect();
+        }
+        match self.policy {
+            BalancePolicy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            BalancePolicy::LeastConnections => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+                candidates.sort_by_key(|b| b.active.load(Ordering::Relaxed));
+            }
+            BalancePolicy::ClientHash => {
+                candidates.sort_by_key(|b| {
+                    let mut hasher = std::hash::DefaultHasher::new();
+                    (client, b.upstream.to_string()).hash(&mut hasher);
+                    std::cmp::Reverse(hasher.finish())
+                });
+            }
+        }
+        candidates
+    }
+}
+
+// Longest configured prefix matching the path of the request target, as
+// it will be forwarded
+pub fn proxy_route(target: &str) -> Option<&'static ProxyRoute> {
+    let target = proxy_target(target)?;
+    let path = target.split('?').next().unwrap_or_default();
+    CONFIG
+        .proxies
+        .iter()
+        .filter(|route| route.matches(path))
+        .max_by_key(|route| route.prefix.len())
+}
+
+// The request target with its path normalised: dot segments resolved (RFC
+// 3986 5.2.4), percent-encoded ones included, and every segment encoded the
+// same way. The path matched against the prefixes is then exactly the one
+// that goes upstream, so no spelling of ".." gets outside a route's base.
+// None for a target that isn't a path, or hides dot segments behind an
+// encoded slash.
+pub fn proxy_target(target: &str) -> Option<String> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let path = path.strip_prefix('/')?;
+
+    let mut segments: Vec<String> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        let decoded = urlencoding::decode_binary(segment.as_bytes());
+        trailing_slash = false;
+        match &*decoded {
+            b"." => trailing_slash = true,
+            b".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            bytes => {
+                let hidden_dots = bytes
+                    .split(|&b| b == b'/' || b == b'\\')
+                    .any(|part| part == b"." || part == b"..");
+                if hidden_dots {
+                    return None;
+                }
+                segments.push(path_segment(bytes));
+            }
+        }
+    }
+
+    let mut normalised = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalised.push('/');
+    }
+    if let Some(query) = query {
+        normalised.push('?');
+        normalised.push_str(query);
+    }
+    Some(normalised)
+}
+
+// Percent-encode a path segment, leaving what RFC 3986 allows in one as is
+fn path_segment(bytes: &[u8]) -> String {
+    let mut segment = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+            segment.push(b as char);
+        } else {
+            segment.push_str(&format!("%{:02X}", b));
+        }
+    }
+    segment
+}
+
+// Start the active health checks of every route that has them
+// Health checks for the routes of the current configuration; those of a
+// previous one stop by themselves.
+pub fn start_health_checks() {
+    for route in &CONFIG.proxies {
+        if let Some(path) = &route.health_path {
+            for backend in &route.backends {
+                spawn(health_check(route, backend, path));
+            }
+        }
+    }
+}
+
+// A reload replaced these routes: close their idle connections, and don't
+// keep the ones requests under the old configuration are still using
+pub fn retire_proxy_routes(routes: &[ProxyRoute]) {
+    for backend in routes.iter().flat_map(|route| &route.backends) {
+        backend.retired.store(true, Ordering::Relaxed);
+        backend.pool.lock().unwrap().clear();
+    }
+}
+
+async fn health_check(route: &'static ProxyRoute, backend: &'static Backend, path: &'static str) {
+    let mut interval = tokio::time::interval(route.health_interval);
+    loop {
+        interval.tick().await;
+        // Until a reload replaces the route
+        if !CONFIG.proxies.iter().any(|current| std::ptr::eq(current, route)) {
+            return;
+        }
+        let result = match timeout(PROXY_CONNECT_TIMEOUT, health_probe(&backend.upstream, path)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::msg("timed out")),
+        };
+        let healthy = result.is_ok();
+        if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match result {
+                Ok(_) => eprintln!("Proxy: {} {}: health check passed, back in service", route.display_prefix(), &backend.upstream),
+                Err(e) => eprintln!("Proxy: {} {}: health check failed: {e}", route.display_prefix(), &backend.upstream),
+            }
+        }
+    }
+}
+
+// GET the health check path; any 2xx or 3xx status is healthy
+async fn health_probe(upstream: &Upstream, path: &str) -> Result<()> {
+    let mut stream = BufStream::new(upstream.connect().await?);
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n\r\n",
+                path,
+                upstream.host(),
+                PKG_NAME,
+                PKG_VERSION
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.flush().await?;
+    let response = read_upstream_head(&mut stream).await?;
+    match response.code() {
+        200..=399 => Ok(()),
+        code => Err(anyhow::anyhow!("status {code}")),
+    }
+}
+
+// Forward the request to one of the route's upstreams and relay the
+// response, streaming bodies in both directions. Upstream failures before a
+// response arrives become 502 (or 504 on timeout).
+pub async fn request_handler_proxy<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut request: HttpRequest<S>,
+    route: &ProxyRoute,
+) -> Result<()> {
+    let start_time = Instant::now();
+
+    // A body delimited two ways, or in a way we can't follow, could end in
+    // a different place for the upstream than for us (request smuggling)
+    let transfer_encoding = request.headers.get("transfer-encoding");
+    let content_length = request.headers.get("content-length");
+    let ambiguous = match (transfer_encoding, content_length) {
+        (Some(_), Some(_)) => true,
+        (Some(te), None) => te.rsplit(',').next().is_none_or(|last| !last.trim().eq_ignore_ascii_case("chunked")),
+        (None, Some(length)) => length.parse::<u64>().is_err(),
+        (None, None) => false,
+    };
+    if ambiguous {
+        return proxy_error(&mut request, route, "400 Bad Request", "ambiguous request body length").await;
+    }
+    let Some(target) = proxy_target(&request.target) else {
+        return proxy_error(&mut request, route, "400 Bad Request", "unusable request target").await;
+    };
+
+    let head = proxy_request_head(&request, route, &target);
+    let has_body = is_chunked(request.headers.get("transfer-encoding"))
+        || request.headers.get("content-length").is_some_and(|l| l != "0");
+
+    // Pooled connections may have been closed by the upstream while idle,
+    // so they're only used when the request can be replayed: no body, and
+    // a safe method (RFC 9110 9.2.1) that does no harm if it arrived after all.
+    let replayable = !has_body && ["get", "head", "options", "trace"].contains(&request.method.as_str());
+    let mut attempts: VecDeque<(&Backend, bool)> = route
+        .candidates(request.client.ip())
+        .into_iter()
+        .map(|backend| (backend, replayable))
+        .collect();
+    let mut last_error = (String::new(), "502 Bad Gateway");
+    let mut selected = None;
+
+    while let Some((backend, use_pool)) = attempts.pop_front() {
+        let pooled = if use_pool { backend.take_pooled() } else { None };
+        let reused = pooled.is_some();
+        let mut upstream = match pooled {
+            Some(connection) => connection,
+            None => match timeout(PROXY_CONNECT_TIMEOUT, backend.upstream.connect()).await {
+                Ok(Ok(connection)) => BufStream::new(connection),
+                Ok(Err(e)) => {
+                    backend.failed(route);
+                    last_error = (format!("{}: connect: {e}", &backend.upstream), "502 Bad Gateway");
+                    continue;
+                }
+                Err(_) => {
+                    backend.failed(route);
+                    last_error = (format!("{}: connect timed out", &backend.upstream), "504 Gateway Timeout");
+                    continue;
+                }
+            },
+        };
+
+        let active = ActiveRequest::new(backend);
+        match timeout(PROXY_RESPONSE_TIMEOUT, upstream_exchange(&mut upstream, &head, &mut request)).await {
+            Ok(Ok(response)) => {
+                selected = Some((backend, upstream, response, active));
+                break;
+            }
+            // Stale keep-alive connection: try again on a fresh one
+            Ok(Err(_)) if reused => attempts.push_front((backend, false)),
+            Ok(Err(e)) => {
+                backend.failed(route);
+                let reason = format!("{}: {e}", &backend.upstream);
+                return proxy_error(&mut request, route, "502 Bad Gateway", &reason).await;
+            }
+            Err(_) => {
+                backend.failed(route);
+                let reason = format!("{}: response timed out", &backend.upstream);
+                return proxy_error(&mut request, route, "504 Gateway Timeout", &reason).await;
+            }
+        }
+    }
+    let Some((backend, mut upstream, response, _active)) = selected else {
+        return proxy_error(&mut request, route, last_error.1, &last_error.0).await;
+    };
+    backend.succeeded();
+
+    let mut head = format!("HTTP/1.1 {}\r\n", &response.status);
+    let mut chunked = false;
+    let mut content_length: Option<u64> = None;
+    for (name, value) in &response.headers {
+        let lower = name.to_lowercase();
+        match lower.as_str() {
+            "transfer-encoding" => chunked = is_chunked(Some(value)),
+            "content-length" => content_length = value.parse().ok(),
+            _ => (),
+        }
+        if !PROXY_HOP_BY_HOP.contains(&lower.as_str()) && lower != "alt-svc" {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!(
+        "{}{}{}Connection: close\r\n\r\n",
+        cache_control_header(),
+        virtual_host_headers(&request.server_name),
+        alt_svc_header()
+    ));
+    request.stream.write_all(head.as_bytes()).await?;
+
+    // Chunked responses are decoded and sent close-delimited, which also
+    // suits the HTTP/2 and HTTP/3 bridges. Only responses whose end we can
+    // see leave the upstream connection reusable.
+    let code = response.code();
+    let (bytes, delimited) = if request.method == "head" || (100..200).contains(&code) || code == 204 || code == 304 {
+        (0, true)
+    } else if chunked {
+        (copy_chunked(&mut upstream, &mut request.stream, true, u64::MAX).await?, true)
+    } else if let Some(length) = content_length {
+        (tokio::io::copy(&mut (&mut upstream).take(length), &mut request.stream).await?, true)
+    } else {
+        (tokio::io::copy(&mut upstream, &mut request.stream).await?, false)
+    };
+    request.stream.flush().await?;
+
+    // After a 1xx (101 Switching Protocols above all) the connection is no
+    // longer plain HTTP/1.1 request/response
+    if route.keepalive > 0 && response.keep_alive && delimited && !(100..200).contains(&code) {
+        backend.return_to_pool(upstream, route.keepalive);
+    }
+
+    println!(
+        "Request (server {}) client {} {} {} -> {} {} {}, {} byte(s) in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        &backend.upstream,
+        route.rewrite(&target),
+        code,
+        bytes,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+// Send the request head and body upstream and read the response head
+async fn upstream_exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    upstream: &mut PooledConnection,
+    head: &str,
+    request: &mut HttpRequest<S>,
+) -> Result<UpstreamResponse> {
+    upstream.write_all(head.as_bytes()).await?;
+    // Expect isn't forwarded, so answer it here rather than leave the
+    // client waiting before it sends the body
+    if request
+        .headers
+        .get("expect")
+        .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+    {
+        request.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        request.stream.flush().await?;
+    }
+    if is_chunked(request.headers.get("transfer-encoding")) {
+        copy_chunked(&mut request.stream, upstream, false, u64::MAX).await?;
+    } else if let Some(length) = request.headers.get("content-length") {
+        let length: u64 = length.parse()?;
+        let copied = tokio::io::copy(&mut (&mut request.stream).take(length), upstream).await?;
+        if copied != length {
+            return Err(Error::msg("client closed during request body"));
+        }
+    }
+    upstream.flush().await?;
+    read_upstream_head(upstream).await
+}
+
+async fn proxy_error<S: AsyncRead + AsyncWrite + Unpin>(
+    request: &mut HttpRequest<S>,
+    route: &ProxyRoute,
+    status: &str,
+    reason: &str,
+) -> Result<()> {
+    eprintln!(
+        "Request (server {}) client {} {} {} -> {}: {} ({})",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        route.display_prefix(),
+        reason,
+        status
+    );
+    send_status_response(request, status).await?;
+    request.stream.flush().await?;
+    Ok(())
+}
+
+// Request line and headers for the upstream: hop-by-hop headers dropped,
+// X-Forwarded-* and Forwarded (RFC 7239) added.
+fn proxy_request_head<S>(request: &HttpRequest<S>, route: &ProxyRoute, target: &str) -> String {
+    // Our hop is the peer; the scheme is the original client's when it came
+    // through trusted proxies
+    let proto = request.scheme;
+    let client_ip = request.peer.ip();
+    let host = request.headers.get("host");
+
+    // Headers named in Connection are hop-by-hop too
+    let connection_tokens: Vec<String> = request
+        .headers
+        .get("connection")
+        .map(|c| c.split(',').map(|t| t.trim().to_lowercase()).collect())
+        .unwrap_or_default();
+
+    // A chunked body goes on chunked, never with a length beside it
+    let chunked = is_chunked(request.headers.get("transfer-encoding"));
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method.to_uppercase(), route.rewrite(target));
+    for (name, value) in &request.headers {
+        if PROXY_HOP_BY_HOP.contains(&name.as_str())
+            || connection_tokens.contains(name)
+            || (chunked && name == "content-length")
+            || name.starts_with("x-forwarded-")
+            || name == "forwarded"
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if chunked {
+        head.push_str("transfer-encoding: chunked\r\n");
+    }
+
+    let xff = match request.headers.get("x-forwarded-for") {
+        Some(previous) => format!("{}, {}", previous, client_ip),
+        None => client_ip.to_string(),
+    };
+    head.push_str(&format!("x-forwarded-for: {}\r\n", xff));
+    head.push_str(&format!("x-forwarded-proto: {}\r\n", proto));
+    if let Some(host) = host {
+        head.push_str(&format!("x-forwarded-host: {}\r\n", host));
+    }
+
+    let mut forwarded = match client_ip {
+        std::net::IpAddr::V4(ip) => format!("for={}", ip),
+        std::net::IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    forwarded.push_str(&format!(";proto={}", proto));
+    if let Some(host) = host {
+        forwarded.push_str(&format!(";host=\"{}\"", host));
+    }
+    match request.headers.get("forwarded") {
+        Some(previous) => head.push_str(&format!("forwarded: {}, {}\r\n", previous, forwarded)),
+        None => head.push_str(&format!("forwarded: {}\r\n", forwarded)),
+    }
+
+    if route.keepalive > 0 {
+        head.push_str("connection: keep-alive\r\n\r\n");
+    } else {
+        head.push_str("connection: close\r\n\r\n");
+    }
+    head
+}
+
+fn is_chunked(transfer_encoding: Option<&String>) -> bool {
+    transfer_encoding.is_some_and(|te| te.to_lowercase().split(',').any(|t| t.trim() == "chunked"))
+}
+
+// Upstream response head. status is e.g. "200 OK"; keep_alive is whether
+// the upstream will take another request on the connection.
+struct UpstreamResponse {
+    status: String,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+impl UpstreamResponse {
+    fn code(&self) -> u16 {
+        self.status.split(' ').next().and_then(|c| c.parse().ok()).unwrap_or_default()
+    }
+}
+
+// Read the upstream response head, skipping any interim 1xx responses other
+// than 101.
+async fn read_upstream_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<UpstreamResponse> {
+    loop {
+        let mut status = None;
+        let mut http11 = false;
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            let n = (&mut *reader).take(MAX_LINE_BYTES as u64).read_line(&mut line).await?;
+            if n == 0 {
+                return Err(Error::msg("upstream closed without a response"));
+            }
+            if !line.ends_with('\n') {
+                return Err(Error::msg("upstream response header line too long"));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            match status {
+                None => {
+                    let (version, rest) = line
+                        .split_once(' ')
+                        .ok_or_else(|| Error::msg("malformed upstream status line"))?;
+                    if !version.starts_with("HTTP/1.") {
+                        return Err(anyhow::anyhow!("upstream spoke {version}"));
+                    }
+                    http11 = version == "HTTP/1.1";
+                    status = Some(String::from(rest.trim()));
+                }
+                Some(_) => {
+                    if headers.len() >= MAX_HEADER_LINES {
+                        return Err(Error::msg("too many upstream response headers"));
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((String::from(name.trim()), String::from(value.trim())));
+                    }
+                }
+            }
+        }
+        let status = status.ok_or_else(|| Error::msg("empty upstream response"))?;
+        if !status.starts_with('1') || status.starts_with("101") {
+            let close = headers.iter().any(|(name, value)| {
+                name.eq_ignore_ascii_case("connection")
+                    && value.split(',').any(|t| t.trim().eq_ignore_ascii_case("close"))
+            });
+            return Ok(UpstreamResponse { status, headers, keep_alive: http11 && !close });
+        }
+    }
+}
+
+// Copy a chunked body, either verbatim or decoded. Returns the number of
+// body bytes, or stops short with a number over max at a chunk that would
+// take the body past it.
+pub async fn copy_chunked<R, W>(reader: &mut R, writer: &mut W, decode: bool, max: u64) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0u64;
+    loop {
+        let mut line = String::new();
+        if (&mut *reader).take(MAX_LINE_BYTES as u64).read_line(&mut line).await? == 0 {
+            return Err(Error::msg("chunked body truncated"));
+        }
+        let size_field = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size_field, 16)
+            .map_err(|_| anyhow::anyhow!("bad chunk size {size_field:?}"))?;
+        if !decode {
+            writer.write_all(line.as_bytes()).await?;
+        }
+
+        if size == 0 {
+            // Trailer section, ending with an empty line
+            loop {
+                let mut trailer = String::new();
+                if (&mut *reader).take(MAX_LINE_BYTES as u64).read_line(&mut trailer).await? == 0 {
+                    return Err(Error::msg("chunked body truncated"));
+                }
+                if !decode {
+                    writer.write_all(trailer.as_bytes()).await?;
+                }
+                if trailer.trim_end_matches(['\r', '\n']).is_empty() {
+                    return Ok(total);
+                }
+            }
+        }
+
+        if total.saturating_add(size) > max {
+            return Ok(total.saturating_add(size));
+        }
+        if tokio::io::copy(&mut (&mut *reader).take(size), writer).await? != size {
+            return Err(Error::msg("chunked body truncated"));
+        }
+        total += size;
+        let mut crlf = String::new();
+        (&mut *reader).take(2).read_line(&mut crlf).await?;
+        if !decode {
+            writer.write_all(crlf.as_bytes()).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_routes() {
+        let route = ProxyRoute::parse("/api/=10.0.0.1:8000/v1,unix:/run/app.sock;policy=least-conn;health=/healthz;keepalive=0").unwrap();
+        assert_eq!(route.prefix, "/api");
+        assert_eq!(route.base.as_deref(), Some("/v1"));
+        assert_eq!(
+            route.backends.iter().map(|b| b.upstream.clone()).collect::<Vec<_>>(),
+            [Upstream::Tcp(String::from("10.0.0.1:8000")), Upstream::Unix(PathBuf::from("/run/app.sock"))]
+        );
+        assert_eq!(route.policy, BalancePolicy::LeastConnections);
+        assert_eq!(route.health_path.as_deref(), Some("/healthz"));
+        assert_eq!(route.keepalive, 0);
+        assert_eq!(route.max_fails, PROXY_MAX_FAILS);
+
+        assert_eq!(ProxyRoute::parse("/=127.0.0.1:3000").unwrap().prefix, "");
+        for bad in [
+            "api=127.0.0.1:3000",
+            "/api",
+            "/api=",
+            "/api=localhost",
+            "/api=a:1/x,b:2/y",
+            "/api=a:1;policy=random",
+            "/api=a:1;interval=soon",
+            "/api=a:1;colour=blue",
+        ] {
+            assert!(ProxyRoute::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn retired_routes_keep_no_connections() {
+        let route = ProxyRoute::parse("/api=127.0.0.1:8000").unwrap();
+        let backend = &route.backends[0];
+        let connection = || BufStream::new(Box::new(tokio::io::duplex(64).0) as Box<dyn IoStream>);
+        backend.return_to_pool(connection(), route.keepalive);
+        assert!(backend.take_pooled().is_some());
+        backend.return_to_pool(connection(), route.keepalive);
+
+        retire_proxy_routes(std::slice::from_ref(&route));
+        assert!(backend.take_pooled().is_none());
+        backend.return_to_pool(connection(), route.keepalive);
+        assert!(backend.take_pooled().is_none());
+    }
+
+    #[test]
+    fn match_and_rewrite() {
+        let route = ProxyRoute::parse("/api=127.0.0.1:3000/v1/").unwrap();
+        assert!(route.matches("/api"));
+        assert!(route.matches("/api/users"));
+        assert!(!route.matches("/apis"));
+        assert_eq!(route.rewrite("/api/users?id=1"), "/v1/users?id=1");
+        assert_eq!(route.rewrite("/api"), "/v1");
+
+        let root = ProxyRoute::parse("/api=127.0.0.1:3000/").unwrap();
+        assert_eq!(root.rewrite("/api/users"), "/users");
+        assert_eq!(root.rewrite("/api"), "/");
+
+        // Without a base the target goes as it came
+        let plain = ProxyRoute::parse("/api=127.0.0.1:3000").unwrap();
+        assert_eq!(plain.rewrite("/api/users"), "/api/users");
+        assert!(ProxyRoute::parse("/=127.0.0.1:3000").unwrap().matches("/anything"));
+    }
+
+    #[test]
+    fn normalised_targets() {
+        for (target, normalised) in [
+            ("/", "/"),
+            ("/api/users?q=a/../b", "/api/users?q=a/../b"),
+            ("/api/../admin", "/admin"),
+            ("/api/%2e%2e/admin", "/admin"),
+            ("/api/a/%2E./b", "/api/b"),
+            ("/api/./x/", "/api/x/"),
+            ("/api/x/..", "/api/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/%61pi/a%2Fb", "/api/a%2Fb"),
+            ("/api/caf%c3%a9 x", "/api/caf%C3%A9%20x"),
+            ("/api/a;b=c:d@e", "/api/a;b=c:d@e"),
+        ] {
+            assert_eq!(proxy_target(target).as_deref(), Some(normalised), "{target}");
+        }
+        assert_eq!(proxy_target("*"), None);
+        assert_eq!(proxy_target("http://example.com/"), None);
+        assert_eq!(proxy_target("/api/..%2f..%2fadmin"), None);
+        assert_eq!(proxy_target("/api/%2e%2e%5cadmin"), None);
+    }
+
+    #[test]
+    fn chunked() {
+        assert!(is_chunked(Some(&String::from("gzip, Chunked"))));
+        assert!(!is_chunked(Some(&String::from("gzip"))));
+        assert!(!is_chunked(None));
+    }
+}
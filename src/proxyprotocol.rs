@@ -0,0 +1,352 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::*;
+
+// Longest v1 header line, CRLF included (proxy-protocol.txt section 2.1)
+const PROXY_V1_MAX_BYTES: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Time a balancer has to send the header once it has connected
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// An address block, from 10.0.0.0/8, fd00::/8 or a bare address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn parse(spec: &str) -> Result<IpNetwork> {
+        let (addr, prefix) = match spec.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (spec, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow::anyhow!("bad network address {spec}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(anyhow::anyhow!("bad prefix length in {spec}")),
+            },
+        };
+        Ok(IpNetwork { addr, prefix })
+    }
+
+    // Comma separated list, as taken on the command line
+    pub fn parse_list(spec: &str) -> Result<Vec<IpNetwork>> {
+        spec.split(',').filter(|s| !s.is_empty()).map(IpNetwork::parse).collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers on a dual-stack socket show up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+pub fn is_trusted_proxy(networks: &[IpNetwork], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(ip))
+}
+
+// The client address a load balancer passes ahead of the connection's data
+// with the PROXY protocol, v1 (text) or v2 (binary). Reads exactly the
+// header, so what follows (a TLS ClientHello, the h2c preface) is left
+// untouched. None when the header says the connection is the balancer's own
+// (health checks: v1 UNKNOWN, v2 LOCAL) or carries no IP addresses.
+pub async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header_inner(stream))
+        .await
+        .map_err(|_| Error::msg("timed out reading PROXY protocol header"))?
+}
+
+async fn read_proxy_header_inner(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    // Both versions' headers are at least this long ("PROXY UNKNOWN\r\n" is 15)
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+
+    if &head == PROXY_V2_SIGNATURE {
+        return read_proxy_v2(stream).await;
+    }
+    if !head.starts_with(b"PROXY ") {
+        return Err(Error::msg("connection doesn't start with a PROXY protocol header"));
+    }
+
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_BYTES {
+            return Err(Error::msg("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_proxy_v1(std::str::from_utf8(&line)?.trim_end())
+}
+
+// PROXY TCP4|TCP6 source destination source-port destination-port
+fn parse_proxy_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| anyhow::anyhow!("bad PROXY source address {source}"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(anyhow::anyhow!("PROXY {protocol} with address {source}"));
+            }
+            let port: u16 = port.parse().map_err(|_| anyhow::anyhow!("bad PROXY source port {port}"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow::anyhow!("malformed PROXY protocol v1 header {line:?}")),
+    }
+}
+
+async fn read_proxy_v2(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, high, low] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(anyhow::anyhow!("unsupported PROXY protocol version {}", version_command >> 4));
+    }
+    // Addresses, then TLVs (ALPN, SNI, ...), which are read and ignored
+    let mut body = vec![0u8; u16::from_be_bytes([high, low]) as usize];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        0x0 => return Ok(None), // LOCAL
+        0x1 => (),              // PROXY
+        command => return Err(anyhow::anyhow!("unknown PROXY protocol v2 command {command}")),
+    }
+    // High nibble address family, low nibble transport: only TCP and UDP
+    // over IPv4/IPv6 carry a client address worth using
+    match family {
+        0x11 | 0x12 if body.len() >= 12 => Ok(Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4])?)),
+            u16::from_be_bytes([body[8], body[9]]),
+        ))),
+        0x21 | 0x22 if body.len() >= 36 => Ok(Some(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16])?)),
+            u16::from_be_bytes([body[32], body[33]]),
+        ))),
+        0x11 | 0x12 | 0x21 | 0x22 => Err(Error::msg("PROXY protocol v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+// The client address and scheme of a request that came through trusted HTTP
+// reverse proxies, from Forwarded (RFC 7239) or X-Forwarded-For/-Proto. The
+// hops are walked from the right, skipping our trusted proxies: the first
+// one that isn't trusted is the client, as anything left of it could be
+// made up. Only called when the peer itself is a trusted proxy.
+pub fn forwarded_client(
+    peer: Peer,
+    scheme: &'static str,
+    headers: &HashMap<String, String>,
+) -> (Peer, &'static str) {
+    // (for, proto) per hop, leftmost (the original client) first
+    let hops: Vec<(&str, Option<&str>)> = if let Some(forwarded) = headers.get("forwarded") {
+        forwarded
+            .split(',')
+            .map(|element| {
+                let mut hop = ("", None);
+                for pair in element.split(';') {
+                    match pair.split_once('=').map(|(k, v)| (k.trim().to_lowercase(), v.trim().trim_matches('"'))) {
+                        Some((k, v)) if k == "for" => hop.0 = v,
+                        Some((k, v)) if k == "proto" => hop.1 = Some(v),
+                        _ => (),
+                    }
+                }
+                hop
+            })
+            .collect()
+    } else if let Some(xff) = headers.get("x-forwarded-for") {
+        let addresses: Vec<&str> = xff.split(',').map(str::trim).collect();
+        let protos: Vec<&str> = headers
+            .get("x-forwarded-proto")
+            .map(|p| p.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        addresses
+            .iter()
+            .enumerate()
+            .map(|(i, address)| {
+                // Proxies that set the list hop by hop line up with the addresses;
+                // otherwise it's the scheme the first of our proxies saw
+                let proto = if protos.len() == addresses.len() { protos.get(i) } else { protos.last() };
+                (*address, proto.copied())
+            })
+            .collect()
+    } else {
+        return (peer, scheme);
+    };
+
+    let mut client = (peer, scheme);
+    for (address, proto) in hops.iter().rev() {
+        let Some(address) = parse_forwarded_node(address) else {
+            // "unknown", obfuscated or garbage: nothing to the left can be trusted
+            break;
+        };
+        client = (
+            Peer::Ip(address),
+            match proto {
+                Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+                Some(proto) if proto.eq_ignore_ascii_case("http") => "http",
+                _ => client.1,
+            },
+        );
+        if !is_trusted_proxy(&CONFIG.trusted_proxies, address.ip()) {
+            break;
+        }
+    }
+    client
+}
+
+// 192.0.2.1, 192.0.2.1:80, [2001:db8::1]:80, or a bare IPv6 address
+fn parse_forwarded_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address);
+    }
+    let ip = node.strip_prefix('[').and_then(|n| n.strip_suffix(']')).unwrap_or(node);
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What read_proxy_header makes of these bytes, and what it leaves unread
+    async fn proxy_header(bytes: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        client.write_all(bytes).await.unwrap();
+        drop(client);
+        let header = read_proxy_header(&mut server).await;
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        (header, rest)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn networks() {
+        let networks = IpNetwork::parse_list("10.0.0.0/8,2001:db8::/32,192.0.2.7").unwrap();
+        assert_eq!(networks.iter().map(ToString::to_string).collect::<Vec<_>>(), ["10.0.0.0/8", "2001:db8::/32", "192.0.2.7/32"]);
+        assert!(is_trusted_proxy(&networks, "10.1.2.3".parse().unwrap()));
+        assert!(is_trusted_proxy(&networks, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(is_trusted_proxy(&networks, "2001:db8:1::1".parse().unwrap()));
+        assert!(is_trusted_proxy(&networks, "192.0.2.7".parse().unwrap()));
+        assert!(!is_trusted_proxy(&networks, "192.0.2.8".parse().unwrap()));
+        assert!(!is_trusted_proxy(&networks, "11.0.0.1".parse().unwrap()));
+
+        assert!(IpNetwork::parse("0.0.0.0/0").unwrap().contains("203.0.113.1".parse().unwrap()));
+        assert!(IpNetwork::parse_list("").unwrap().is_empty());
+        assert!(IpNetwork::parse("10.0.0.0/33").is_err());
+        assert!(IpNetwork::parse("::/129").is_err());
+        assert!(IpNetwork::parse("example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_v1() {
+        let (header, rest) = proxy_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /").await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = proxy_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (header, rest) = proxy_header(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+
+        assert!(proxy_header(b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n").await.0.is_err());
+        assert!(proxy_header(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend([b'1'; 120]);
+        assert!(proxy_header(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_v2() {
+        let mut ipv4 = vec![192, 0, 2, 1, 192, 0, 2, 2];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(443u16.to_be_bytes());
+        let mut bytes = v2(0x1, 0x11, &ipv4);
+        bytes.extend(b"\x16\x03\x01");
+        let (header, rest) = proxy_header(&bytes).await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"\x16\x03\x01");
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend(4000u16.to_be_bytes());
+        ipv6.extend(443u16.to_be_bytes());
+        // A TLV after the addresses is skipped
+        ipv6.extend([0x01, 0x00, 0x02, b'h', b'2']);
+        let (header, _) = proxy_header(&v2(0x1, 0x21, &ipv6)).await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        // LOCAL (health check), and a Unix socket source, carry no client
+        assert_eq!(proxy_header(&v2(0x0, 0x11, &ipv4)).await.0.unwrap(), None);
+        assert_eq!(proxy_header(&v2(0x1, 0x31, &[0; 216])).await.0.unwrap(), None);
+        assert!(proxy_header(&v2(0x1, 0x11, &ipv4[..6])).await.0.is_err());
+        assert!(proxy_header(&v2(0x2, 0x11, &ipv4)).await.0.is_err());
+    }
+
+    #[test]
+    fn forwarded_clients() {
+        let config = Config {
+            trusted_proxies: IpNetwork::parse_list("10.0.0.0/8").unwrap(),
+            ..Config::default()
+        };
+        let peer = Peer::Ip("10.0.0.1:4000".parse().unwrap());
+        let forwarded = |headers: &[(&str, &str)]| {
+            let headers = headers.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+            let (client, scheme) = forwarded_client(peer, "http", &headers);
+            (client.to_string(), scheme)
+        };
+        with_candidate_config(Box::leak(Box::new(config)), || {
+            // The rightmost untrusted hop is the client; what's left of it could be made up
+            assert_eq!(
+                forwarded(&[("x-forwarded-for", "198.51.100.1, 203.0.113.9, 10.0.0.2"), ("x-forwarded-proto", "https")]),
+                (String::from("203.0.113.9:0"), "https")
+            );
+            assert_eq!(
+                forwarded(&[("forwarded", "for=198.51.100.1;proto=http, for=\"[2001:db8::1]:443\";proto=https")]),
+                (String::from("[2001:db8::1]:443"), "https")
+            );
+            // Forwarded wins over X-Forwarded-For
+            assert_eq!(
+                forwarded(&[("forwarded", "for=192.0.2.60"), ("x-forwarded-for", "198.51.100.1")]),
+                (String::from("192.0.2.60:0"), "http")
+            );
+            // Nothing usable: the last trusted hop, or the peer itself
+            assert_eq!(forwarded(&[("x-forwarded-for", "unknown, 10.0.0.3")]), (String::from("10.0.0.3:0"), "http"));
+            assert_eq!(forwarded(&[("x-forwarded-for", "_hidden")]), (String::from("10.0.0.1:4000"), "http"));
+            assert_eq!(forwarded(&[]), (String::from("10.0.0.1:4000"), "http"));
+        });
+    }
+}
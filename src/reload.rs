@@ -0,0 +1,383 @@
+use std::fmt;
+use std::os::fd::{OwnedFd, RawFd};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::AbortHandle;
+use crate::*;
+
+// An address being listened on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Quic(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "TCP {addr}"),
+            ListenAddr::Quic(addr) => write!(f, "UDP {addr} (HTTP/3)"),
+            ListenAddr::Unix(path) => write!(f, "Unix {}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl From<&BindAddr> for ListenAddr {
+    fn from(addr: &BindAddr) -> ListenAddr {
+        match addr {
+            BindAddr::Tcp(addr) => ListenAddr::Tcp(*addr),
+            BindAddr::Unix(socket) => ListenAddr::Unix(socket.path.clone()),
+        }
+    }
+}
+
+enum BoundSocket {
+    Tcp(TcpListener),
+    Quic(quinn::Endpoint),
+    // With the socket file, when it's ours to remove
+    Unix(tokio::net::UnixListener, Option<PathBuf>),
+}
+
+// An accept loop, and a duplicate of its socket to hand over on an upgrade
+struct RunningListener {
+    task: AbortHandle,
+    socket: OwnedFd,
+    socket_file: Option<PathBuf>,
+}
+
+lazy_static! {
+    // Accept loops running, by address; connections they've accepted run on
+    // their own and outlive them
+    static ref LISTENERS: Mutex<HashMap<ListenAddr, RunningListener>> = Mutex::new(HashMap::new());
+
+    // One reload (or upgrade) at a time
+    pub static ref RELOADING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+pub fn wanted_listeners(config: &Config) -> Vec<ListenAddr> {
+    let mut wanted = Vec::new();
+    let addresses = std::iter::once(&config.bind_addr).chain(&config.listen).map(ListenAddr::from);
+    for addr in addresses.chain(config.quic_addr.map(ListenAddr::Quic)) {
+        if !wanted.contains(&addr) {
+            wanted.push(addr);
+        }
+    }
+    wanted
+}
+
+// The Unix domain socket a configuration has at a path, with its options
+pub fn unix_socket<'a>(config: &'a Config, path: &Path) -> Option<&'a UnixSocket> {
+    std::iter::once(&config.bind_addr).chain(&config.listen).find_map(|addr| match addr {
+        BindAddr::Unix(socket) if socket.path == path => Some(socket),
+        _ => None,
+    })
+}
+
+// The socket for an address, inherited from the process we're upgrading
+// from, passed by systemd, or else newly bound, with a duplicate of it. An
+// inherited or systemd socket is only given up once its listener starts.
+fn open_socket(config: &Config, addr: &ListenAddr) -> Result<(BoundSocket, OwnedFd)> {
+    let upgraded = inherited_listener(addr);
+    let from_upgrade = upgraded.is_some();
+    let inherited = upgraded.or_else(|| activated_listener(addr));
+    match addr.clone() {
+        ListenAddr::Tcp(tcp) => {
+            let listener = match inherited {
+                Some(fd) => std::net::TcpListener::from(fd),
+                None => std::net::TcpListener::bind(tcp)?,
+            };
+            let duplicate = OwnedFd::from(listener.try_clone()?);
+            listener.set_nonblocking(true)?;
+            Ok((BoundSocket::Tcp(TcpListener::from_std(listener)?), duplicate))
+        }
+        ListenAddr::Quic(udp) => {
+            let socket = match inherited {
+                Some(fd) => std::net::UdpSocket::from(fd),
+                None => std::net::UdpSocket::bind(udp)?,
+            };
+            let duplicate = OwnedFd::from(socket.try_clone()?);
+            Ok((BoundSocket::Quic(bind_quic(socket)?), duplicate))
+        }
+        ListenAddr::Unix(path) => {
+            // Files systemd created are systemd's to remove
+            let (listener, socket_file) = match inherited {
+                Some(fd) => (std::os::unix::net::UnixListener::from(fd), from_upgrade.then(|| path.clone())),
+                None => {
+                    let socket = unix_socket(config, &path).ok_or_else(|| Error::msg("not configured"))?;
+                    (bind_unix_socket(socket)?, Some(path.clone()))
+                }
+            };
+            let duplicate = OwnedFd::from(listener.try_clone()?);
+            listener.set_nonblocking(true)?;
+            Ok((BoundSocket::Unix(tokio::net::UnixListener::from_std(listener)?, socket_file), duplicate))
+        }
+    }
+}
+
+// Open the sockets a configuration listens on that aren't open already. Run
+// with the configuration as the candidate, as QUIC needs its TLS settings.
+fn bind_listeners(config: &Config) -> Result<Vec<(ListenAddr, BoundSocket, OwnedFd)>, Vec<String>> {
+    let running = LISTENERS.lock().unwrap();
+    let mut bound = Vec::new();
+    let mut errors = Vec::new();
+    for addr in wanted_listeners(config) {
+        if running.contains_key(&addr) {
+            continue;
+        }
+        match open_socket(config, &addr).map(|(socket, duplicate)| (addr.clone(), socket, duplicate)) {
+            Ok(socket) => bound.push(socket),
+            Err(e) => errors.push(format!("{addr}: {e}")),
+        }
+    }
+    if errors.is_empty() {
+        Ok(bound)
+    } else {
+        Err(errors)
+    }
+}
+
+// Start accepting on newly bound sockets, and stop accepting on the ones the
+// configuration no longer has
+fn replace_listeners(config: &Config, bound: Vec<(ListenAddr, BoundSocket, OwnedFd)>) {
+    let wanted = wanted_listeners(config);
+    let mut running = LISTENERS.lock().unwrap();
+    running.retain(|addr, listener| {
+        let keep = wanted.contains(addr);
+        if !keep {
+            eprintln!("Listener: {addr}: closed");
+            listener.task.abort();
+            if let Some(path) = &listener.socket_file {
+                remove_unix_socket(path);
+            }
+        }
+        keep
+    });
+    for (addr, socket, duplicate) in bound {
+        claim_inherited_listener(&addr);
+        claim_activated_listener(&addr);
+        let mut socket_file = None;
+        let name = addr.clone();
+        let task = match socket {
+            BoundSocket::Tcp(tcp) => spawn(async move {
+                if let Err(e) = listener(tcp).await {
+                    eprintln!("Listener: {name}: failed: {e}");
+                }
+            }),
+            BoundSocket::Quic(endpoint) => spawn(async move {
+                if let Err(e) = quic_listener(endpoint).await {
+                    eprintln!("Listener: {name}: failed: {e}");
+                }
+            }),
+            BoundSocket::Unix(unix, file) => {
+                socket_file = file;
+                spawn(async move {
+                    if let Err(e) = unix_listener(unix).await {
+                        eprintln!("Listener: {name}: failed: {e}");
+                    }
+                })
+            }
+        };
+        if CONFIG.verbose {
+            eprintln!("Listener: {addr}: accepting");
+        }
+        running.insert(
+            addr,
+            RunningListener {
+                task: task.abort_handle(),
+                socket: duplicate,
+                socket_file,
+            },
+        );
+    }
+}
+
+// The listening sockets, for a new process to take over
+pub fn listening_sockets() -> Vec<(ListenAddr, RawFd)> {
+    LISTENERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, listener)| (addr.clone(), listener.socket.as_raw_fd()))
+        .collect()
+}
+
+// On the way out, unless a new process has the listeners now: remove the
+// socket files we created, so nothing connects to a server that's gone
+pub fn remove_socket_files() {
+    for listener in LISTENERS.lock().unwrap().values() {
+        if let Some(path) = &listener.socket_file {
+            remove_unix_socket(path);
+        }
+    }
+}
+
+// Listeners for the startup configuration; failing to bind any is fatal
+pub fn start_listeners() -> Result<()> {
+    let bound = bind_listeners(&CONFIG).map_err(|errors| Error::msg(errors.join("; ")))?;
+    replace_listeners(&CONFIG, bound);
+    Ok(())
+}
+
+// Re-read the command line and configuration file, check everything that's
+// only checked when used (TLS identities, WebSocket handlers, listening
+// addresses), and if all is well make it current: new connections use it,
+// open ones carry on with the snapshot they started with.
+pub async fn reload_config() -> Result<(), Vec<String>> {
+    let _reloading = RELOADING.lock().await;
+    if shutting_down() {
+        return Err(vec![String::from("shutting down")]);
+    }
+    // The configuration's paths are outside, and so likely is the file
+    if chrooted() {
+        return Err(vec![String::from("can't reload inside --chroot, restart instead")]);
+    }
+    notify_reloading();
+    let result = apply_config();
+    notify_systemd("READY=1");
+    result
+}
+
+fn apply_config() -> Result<(), Vec<String>> {
+    // Snapshots live as long as the process, as connections may hold on to
+    // them, but a candidate that fails validation is freed again
+    let candidate = Box::into_raw(Box::new(Config::parse()?));
+    let config: &'static Config = unsafe { &*candidate };
+
+    let validated = with_candidate_config(config, || {
+        let mut errors = Vec::new();
+        if config.tls.is_some() && load_tls_config().is_none() {
+            errors.push(String::from("TLS configuration failed (see above)"));
+        }
+        let mut handlers = Vec::new();
+        for (path, spec) in &config.websockets {
+            match websocket_handler_from_spec(spec) {
+                Ok(handler) => handlers.push((path.clone(), handler)),
+                Err(e) => errors.push(format!("WebSocket handler {path}={spec}: {e}")),
+            }
+        }
+        match bind_listeners(config) {
+            Ok(bound) if errors.is_empty() => Ok((handlers, bound)),
+            Ok(_) => Err(errors),
+            Err(bind_errors) => Err(errors.into_iter().chain(bind_errors).collect()),
+        }
+    });
+    let (handlers, bound) = match validated {
+        Ok(validated) => validated,
+        Err(errors) => {
+            // Validation only builds values of its own (TLS configuration,
+            // handlers, sockets), so nothing refers to the candidate now
+            drop(unsafe { Box::from_raw(candidate) });
+            return Err(errors);
+        }
+    };
+
+    let previous = publish_config(config);
+    // The old snapshot's pooled upstream connections would otherwise stay
+    // open for good
+    retire_proxy_routes(&previous.proxies);
+    retire_fastcgi_mappings(&previous.fastcgi);
+    // Still as the candidate: a reload requested over HTTP runs in a
+    // connection holding the old snapshot
+    with_candidate_config(config, || {
+        replace_websocket_handlers(handlers);
+        refresh_tls_acceptor();
+        start_health_checks();
+        if config.events {
+            let watching = if file_watcher_started() { update_file_watches() } else { start_file_watcher() };
+            if let Err(e) = watching {
+                eprintln!("Config: events: {e}");
+            }
+        }
+        replace_listeners(config, bound);
+    });
+    eprintln!(
+        "Config: reloaded{}",
+        config.config_file.as_ref().map_or(String::new(), |file| format!(" {}", file.to_string_lossy()))
+    );
+    Ok(())
+}
+
+fn report_reload(result: &Result<(), Vec<String>>) {
+    if let Err(errors) = result {
+        for error in errors {
+            eprintln!("Config: {error}");
+        }
+        eprintln!("Config: reload failed, still running the previous configuration");
+    }
+}
+
+// Reload on every SIGHUP, for as long as the server runs
+pub async fn reload_on_sighup() -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        eprintln!("Config: SIGHUP, reloading");
+        report_reload(&reload_config().await);
+    }
+    Ok(())
+}
+
+// Only a process of the server's own user (or root) could send it SIGHUP
+// anyway. An address proves nothing: a reverse proxy on this machine
+// connects from loopback, or over a Unix socket, on behalf of anyone.
+fn may_reload(peer: &Peer) -> bool {
+    let uid = unsafe { libc::geteuid() };
+    matches!(peer, Peer::Unix(Some(credentials)) if credentials.uid == uid || credentials.uid == 0)
+}
+
+// POST to the --reload-path URL reloads, from a Unix socket peer running as
+// the server's user or root only. The response lists any errors.
+pub async fn request_handler_reload<S: AsyncRead + AsyncWrite + Unpin>(mut request: HttpRequest<S>) -> Result<()> {
+    let start_time = Instant::now();
+    let (status, body) = if !may_reload(&request.peer) {
+        ("403 Forbidden", String::from("reload is only accepted over a Unix socket from the server's user or root\n"))
+    } else if request.method != "post" {
+        ("405 Method Not Allowed", String::from("use POST to reload\n"))
+    } else {
+        let result = reload_config().await;
+        report_reload(&result);
+        match result {
+            Ok(()) => ("200 OK", String::from("reloaded\n")),
+            Err(errors) => ("422 Unprocessable Content", errors.join("\n") + "\n"),
+        }
+    };
+
+    request
+        .stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    request.stream.flush().await?;
+    println!(
+        "Request (server {}) client {} {} {} reload {} in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        status,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_by_user_not_address() {
+        let uid = unsafe { libc::geteuid() };
+        let unix = |uid| Peer::Unix(Some(PeerCredentials { pid: None, uid, gid: 0 }));
+        assert!(may_reload(&unix(uid)));
+        assert!(may_reload(&unix(0)));
+        assert!(!may_reload(&unix(uid.wrapping_add(1).max(1))));
+        assert!(!may_reload(&Peer::Unix(None)));
+        assert!(!may_reload(&Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap())));
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::OnceLock;
+use std::os::unix::fs::OpenOptionsExt;
+use std::io::Write;
+use sha2::{Digest, Sha256};
+use crate::*;
+
+pub const AUTO_TLS: &str = "auto";
+
+const PERSIST_CERT_FILE: &str = "mchttp-auto.crt";
+const PERSIST_KEY_FILE: &str = "mchttp-auto.key";
+
+// Generated once per process so that the periodic certificate reload in
+// listener() doesn't change the fingerprint underneath clients that pinned it.
+static AUTO_IDENTITY: OnceLock<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> = OnceLock::new();
+
+// Certificate chain and key for "-t auto": either reloaded from the data
+// directory (when persisting) or freshly generated in memory.
+pub fn auto_identity() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    if let Some((certs, key)) = AUTO_IDENTITY.get() {
+        return Ok((certs.clone(), key.clone_key()));
+    }
+
+    let (certs, key) = match persist_paths() {
+        Some((cert_path, key_path)) if cert_path.exists() && key_path.exists() => {
+            eprintln!("TLS: using persisted self-signed certificate {}", cert_path.to_string_lossy());
+            let certs = CertificateDer::pem_file_iter(&cert_path)?
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = PrivateKeyDer::from_pem_file(&key_path)?;
+            (certs, key)
+        }
+        persist => {
+            let names = selfsigned_names();
+            eprintln!("TLS: generating self-signed certificate for {}", names.join(", "));
+            let key_pair = rcgen::KeyPair::generate()?;
+            let cert = rcgen::CertificateParams::new(names)?.self_signed(&key_pair)?;
+            if let Some((cert_path, key_path)) = persist {
+                std::fs::write(&cert_path, cert.pem())?;
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&key_path)?
+                    .write_all(key_pair.serialize_pem().as_bytes())?;
+                eprintln!("TLS: persisted self-signed certificate to {}", cert_path.to_string_lossy());
+            }
+            let key = PrivateKeyDer::try_from(key_pair.serialize_der())
+                .map_err(|e| anyhow::anyhow!("generated key unusable: {e}"))?;
+            (vec![cert.der().clone()], key)
+        }
+    };
+
+    eprintln!("TLS: certificate SHA-256 fingerprint {}", sha256_fingerprint(&certs[0]));
+    let _ = AUTO_IDENTITY.set((certs.clone(), key.clone_key()));
+    Ok((certs, key))
+}
+
+// Colon-separated upper case hex, as printed by `openssl x509 -fingerprint -sha256`.
+pub fn sha256_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn persist_paths() -> Option<(PathBuf, PathBuf)> {
+    if !CONFIG.tls_persist {
+        return None;
+    }
+    let data_dir = CONFIG.data_dir.as_ref()?;
+    Some((data_dir.join(PERSIST_CERT_FILE), data_dir.join(PERSIST_KEY_FILE)))
+}
+
+// localhost, the machine hostname and every vhost that request_handler_dir()
+// may serve: sub-directories of the data directory, and the names and
+// aliases of the configuration file's sites.
+fn selfsigned_names() -> Vec<String> {
+    let mut names = vec![String::from("localhost"), String::from("127.0.0.1"), String::from("::1")];
+
+    let mut candidates: Vec<String> = hostname().into_iter().collect();
+    if let Some(data_dir) = &CONFIG.data_dir {
+        if let Ok(entries) = std::fs::read_dir(data_dir) {
+            for entry in entries.flatten() {
+                if entry.path().is_dir() {
+                    candidates.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    for vhost in &CONFIG.vhosts {
+        candidates.push(vhost.name.clone());
+        candidates.extend(vhost.aliases.iter().cloned());
+    }
+    // A directory that isn't named for a host (or a hostname that isn't a
+    // DNS name) would make the whole certificate fail
+    for name in candidates {
+        if rustls::pki_types::DnsName::try_from(name.as_str()).is_ok() {
+            names.push(name);
+        } else {
+            eprintln!("TLS: {name}: not a valid DNS name, left out of the self-signed certificate");
+        }
+    }
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(s) if !s.is_empty() => Some(String::from(s)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_include_vhosts() {
+        let vhost = VirtualHost {
+            name: String::from("example.com"),
+            aliases: vec![String::from("www.example.com"), String::from("bad name")],
+            ..VirtualHost::default()
+        };
+        let config = Config { vhosts: vec![vhost], ..Config::default() };
+        let names = with_candidate_config(Box::leak(Box::new(config)), selfsigned_names);
+        assert!(names.contains(&String::from("localhost")));
+        assert!(names.contains(&String::from("example.com")));
+        assert!(names.contains(&String::from("www.example.com")));
+        assert!(!names.contains(&String::from("bad name")));
+    }
+}
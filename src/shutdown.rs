@@ -0,0 +1,121 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use crate::*;
+
+// How long open connections get to finish once a shutdown has started
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+static CONNECTION_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // Set once SIGTERM/SIGINT has been received
+    static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+    // Why, when it's not a signal
+    static ref SHUTDOWN_REQUEST: watch::Sender<Option<&'static str>> = watch::channel(None).0;
+
+    // Open client connections, for draining and the shutdown summary
+    static ref CONNECTIONS: Mutex<HashMap<u64, (Peer, &'static str)>> = Mutex::new(HashMap::new());
+    static ref DRAINED: Notify = Notify::new();
+}
+
+// A client connection being served; dropped when it's done with
+pub struct ConnectionGuard(u64);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        connections.remove(&self.0);
+        if connections.is_empty() {
+            DRAINED.notify_waiters();
+        }
+    }
+}
+
+pub fn track_connection(client: Peer, protocol: &'static str) -> ConnectionGuard {
+    let id = CONNECTION_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    CONNECTIONS.lock().unwrap().insert(id, (client, protocol));
+    ConnectionGuard(id)
+}
+
+// Shut down as if signalled
+pub fn request_shutdown(reason: &'static str) {
+    SHUTDOWN_REQUEST.send_replace(Some(reason));
+}
+
+pub fn shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+// Resolves once a shutdown has started: listeners stop accepting, HTTP/2 and
+// HTTP/3 connections are sent GOAWAY, idle and long-lived (WebSocket, event
+// stream) connections are closed, and requests under way are left to finish.
+pub async fn shutdown_started() {
+    let mut shutdown = SHUTDOWN.subscribe();
+    let _ = shutdown.wait_for(|started| *started).await;
+}
+
+async fn connections_drained() {
+    loop {
+        let drained = DRAINED.notified();
+        if CONNECTIONS.lock().unwrap().is_empty() {
+            return;
+        }
+        drained.await;
+    }
+}
+
+// On SIGTERM or SIGINT (or request_shutdown()) stop accepting, give open
+// connections up to the drain timeout to finish (a second signal cuts that
+// short), then report any that had to be cut off. Returns when it's time to
+// exit.
+pub async fn shutdown_on_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut requested = SHUTDOWN_REQUEST.subscribe();
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+        reason = requested.wait_for(Option::is_some) => reason.ok().and_then(|reason| *reason).unwrap_or("requested"),
+    };
+
+    // Once handed over, the service carries on in the new process
+    if !handed_over() {
+        notify_systemd("STOPPING=1");
+    }
+    let drain_timeout = CONFIG.drain_timeout;
+    eprintln!(
+        "Shutdown: {name}, no longer accepting; draining {} connection(s) for up to {:?}",
+        CONNECTIONS.lock().unwrap().len(),
+        drain_timeout
+    );
+    let start_time = Instant::now();
+    SHUTDOWN.send_replace(true);
+    // Unix domain sockets included
+    if !handed_over() {
+        remove_socket_files();
+    }
+
+    tokio::select! {
+        _ = connections_drained() => (),
+        _ = sleep(drain_timeout) => (),
+        _ = terminate.recv() => eprintln!("Shutdown: SIGTERM again, not waiting any longer"),
+        _ = interrupt.recv() => eprintln!("Shutdown: SIGINT again, not waiting any longer"),
+    }
+
+    let connections = CONNECTIONS.lock().unwrap();
+    if connections.is_empty() {
+        eprintln!("Shutdown: all connections finished in {:?}", start_time.elapsed());
+    } else {
+        let mut cut_off: Vec<_> = connections.values().collect();
+        cut_off.sort();
+        for (client, protocol) in &cut_off {
+            eprintln!("Shutdown: cutting off {protocol} connection from {client}");
+        }
+        eprintln!(
+            "Shutdown: {} connection(s) cut off after {:?}",
+            cut_off.len(),
+            start_time.elapsed()
+        );
+    }
+    Ok(())
+}
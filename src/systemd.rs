@@ -0,0 +1,162 @@
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use crate::*;
+
+// sd_listen_fds(3): sockets passed by systemd start at this descriptor
+const LISTEN_FDS_START: RawFd = 3;
+
+lazy_static! {
+    // Sockets systemd bound for us, by address, with their names
+    static ref ACTIVATED_LISTENERS: Mutex<HashMap<ListenAddr, (String, OwnedFd)>> = Mutex::new(activated_listeners());
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// What a passed socket listens on: TCP or a Unix domain socket path for a
+// listening stream socket, HTTP/3 for a datagram one
+fn activated_address(fd: RawFd) -> std::io::Result<Option<(ListenAddr, OwnedFd)>> {
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    match socket_option(fd, libc::SO_TYPE)? {
+        libc::SOCK_STREAM if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 => Ok(None),
+        libc::SOCK_STREAM if socket_option(fd, libc::SO_DOMAIN)? == libc::AF_UNIX => {
+            let listener = net::UnixListener::from(socket);
+            // Abstract and unnamed sockets have no path to configure
+            let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+            Ok(path.map(|path| (ListenAddr::Unix(path), listener.into())))
+        }
+        libc::SOCK_STREAM => {
+            let listener = std::net::TcpListener::from(socket);
+            Ok(Some((ListenAddr::Tcp(listener.local_addr()?), listener.into())))
+        }
+        libc::SOCK_DGRAM => {
+            let socket = std::net::UdpSocket::from(socket);
+            Ok(Some((ListenAddr::Quic(socket.local_addr()?), socket.into())))
+        }
+        _ => Ok(None),
+    }
+}
+
+// The sockets systemd passed (LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES),
+// when they're meant for this process rather than one before it
+fn activated_listeners() -> HashMap<ListenAddr, (String, OwnedFd)> {
+    let mut activated = HashMap::new();
+    if env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+        return activated;
+    }
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().filter(|name| !name.is_empty()).map_or(format!("FD {fd}"), String::from);
+        // Not to be passed on to CGI scripts
+        if let Err(e) = set_close_on_exec(fd, true) {
+            eprintln!("Listener: systemd socket {name}: {e}");
+            continue;
+        }
+        match activated_address(fd) {
+            Ok(Some((addr, socket))) => {
+                activated.insert(addr, (name, socket));
+            }
+            Ok(None) => eprintln!("Listener: systemd socket {name}: not a listening TCP, UDP or Unix socket, ignored"),
+            Err(e) => eprintln!("Listener: systemd socket {name}: {e}"),
+        }
+    }
+    activated
+}
+
+// A duplicate of the socket systemd bound for this address, if it did. The
+// socket stays here until claimed, in case the configuration is rejected.
+pub fn activated_listener(addr: &ListenAddr) -> Option<OwnedFd> {
+    let activated = ACTIVATED_LISTENERS.lock().unwrap();
+    let (name, socket) = activated.get(addr)?;
+    match socket.try_clone() {
+        Ok(socket) => {
+            eprintln!("Listener: {addr}: from systemd socket {name}");
+            Some(socket)
+        }
+        Err(e) => {
+            eprintln!("Listener: systemd socket {name}: {e}");
+            None
+        }
+    }
+}
+
+// Once a listener is running on it, the socket is no longer ours to hand out
+pub fn claim_activated_listener(addr: &ListenAddr) {
+    ACTIVATED_LISTENERS.lock().unwrap().remove(addr);
+}
+
+// Send a state change to the service manager, if there is one (sd_notify(3))
+pub fn notify_systemd(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let notify = || -> std::io::Result<()> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => net::SocketAddr::from_abstract_name(name)?,
+            None => net::SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    };
+    if let Err(e) = notify() {
+        eprintln!("systemd: failed to send {}: {e}", state.replace('\n', " "));
+    }
+}
+
+// Type=notify-reload wants the time the reload started with RELOADING=1
+pub fn notify_reloading() {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+    notify_systemd(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+}
+
+// Listening: note any sockets systemd passed that the configuration doesn't
+// listen on, and report READY=1
+pub fn notify_ready() {
+    for (addr, (name, _)) in ACTIVATED_LISTENERS.lock().unwrap().drain() {
+        eprintln!("Listener: systemd socket {name} ({addr}) isn't configured, ignored");
+    }
+    notify_systemd("READY=1");
+}
+
+// How often the systemd watchdog (WatchdogSec=) wants pinging, if it's on
+// and meant for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok())?;
+    let pid = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    if pid.is_some_and(|pid| pid != process::id()) {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+// Ping the watchdog at half its interval, for as long as the runtime keeps
+// running tasks
+pub async fn systemd_watchdog(interval: Duration) -> Result<()> {
+    let mut pings = tokio::time::interval(interval / 2);
+    loop {
+        pings.tick().await;
+        notify_systemd("WATCHDOG=1");
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::OnceLock;
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use rustls::crypto::CryptoProvider;
+use rustls::server::ProducesTickets;
+use rustls::{KeyLogFile, ServerConfig, WantsVerifier};
+use sha2::{Digest, Sha256};
+use crate::*;
+
+const DEFAULT_TICKET_LIFETIME: u32 = 6 * 60 * 60;
+const TICKET_KEY_ID_LEN: usize = 4;
+const DEFAULT_ALPN: [&str; 2] = ["h2", "http/1.1"];
+
+// How TLS session resumption tickets are issued
+#[derive(Debug, Clone, PartialEq)]
+pub enum TicketMode {
+    // No tickets; TLS 1.3 resumption uses the in-memory session cache
+    Off,
+    // rustls' ticketer, keys generated in-process and rotated automatically
+    Rotating,
+    // Keys read from a file shared between servers; the first key encrypts,
+    // all of them decrypt. Rotate by prepending a new key and later dropping
+    // the last one.
+    SharedKeys(PathBuf),
+}
+
+// Settings from -T key=value
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub min_version: Option<String>,
+    pub cipher_suites: Vec<String>,
+    pub kx_groups: Vec<String>,
+    pub alpn: Vec<String>,
+    pub tickets: TicketMode,
+    pub ticket_lifetime: u32,
+}
+
+impl Default for TlsOptions {
+    fn default() -> TlsOptions {
+        TlsOptions {
+            min_version: None,
+            cipher_suites: Vec::new(),
+            kx_groups: Vec::new(),
+            alpn: DEFAULT_ALPN.iter().map(|p| String::from(*p)).collect(),
+            tickets: TicketMode::Off,
+            ticket_lifetime: DEFAULT_TICKET_LIFETIME,
+        }
+    }
+}
+
+impl TlsOptions {
+    pub fn usage() {
+        println!("       -T, --tls-option key=value");
+        println!("                     TLS parameters, repeatable:");
+        println!("       -T min-version=1.2|1.3          lowest TLS version accepted (1.2)");
+        println!("       -T ciphers=SUITE,...            allowed cipher suites, e.g. TLS13_AES_256_GCM_SHA384");
+        println!("       -T groups=GROUP,...             allowed key exchange groups, e.g. X25519,secp256r1");
+        println!("       -T alpn=PROTO,...               ALPN protocols to offer ({})", DEFAULT_ALPN.join(","));
+        println!("       -T tickets=off|on|keyfile       session tickets: none, in-process rotating keys, or");
+        println!("                                       keys shared between servers (one hex key per line, first encrypts)");
+        println!("       -T ticket-lifetime=seconds      lifetime of shared key tickets ({})", DEFAULT_TICKET_LIFETIME);
+        println!("                     Set SSLKEYLOGFILE in the environment to log session keys for debugging");
+        println!("                     Supported suites: {}", supported_cipher_suites().join(","));
+        println!("                     Supported groups: {}", supported_kx_groups().join(","));
+    }
+
+    // Apply one key=value option, from -T or the configuration file
+    pub fn try_set(&mut self, option: &str) -> Result<()> {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| Error::msg("expected TLS option in key=value form"))?;
+        let list = || -> Vec<String> {
+            value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+        };
+        match key {
+            "min-version" => match value {
+                "1.2" | "1.3" => self.min_version = Some(String::from(value)),
+                _ => return Err(Error::msg("TLS min-version should be 1.2 or 1.3")),
+            },
+            "ciphers" => self.cipher_suites = list(),
+            "groups" => self.kx_groups = list(),
+            "alpn" => self.alpn = list(),
+            "tickets" => {
+                self.tickets = match value {
+                    "off" => TicketMode::Off,
+                    "on" => TicketMode::Rotating,
+                    path => TicketMode::SharedKeys(
+                        std::fs::canonicalize(path)
+                            .map_err(|e| anyhow::anyhow!("session ticket key file {path}: {e}"))?,
+                    ),
+                }
+            }
+            "ticket-lifetime" => {
+                self.ticket_lifetime = value
+                    .parse()
+                    .map_err(|_| Error::msg("failed to parse ticket-lifetime seconds"))?
+            }
+            _ => return Err(anyhow::anyhow!("unknown TLS option {key}")),
+        }
+        Ok(())
+    }
+}
+
+fn supported_cipher_suites() -> Vec<String> {
+    rustls::crypto::aws_lc_rs::ALL_CIPHER_SUITES
+        .iter()
+        .map(|s| format!("{:?}", s.suite()))
+        .collect()
+}
+
+fn supported_kx_groups() -> Vec<String> {
+    rustls::crypto::aws_lc_rs::ALL_KX_GROUPS
+        .iter()
+        .map(|g| format!("{:?}", g.name()))
+        .collect()
+}
+
+// Crypto provider restricted to the configured suites and groups
+fn tls_provider(options: &TlsOptions) -> Result<CryptoProvider> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+
+    if !options.cipher_suites.is_empty() {
+        for name in &options.cipher_suites {
+            if !supported_cipher_suites().contains(name) {
+                return Err(anyhow::anyhow!("unknown cipher suite {name}"));
+            }
+        }
+        provider.cipher_suites = rustls::crypto::aws_lc_rs::ALL_CIPHER_SUITES
+            .iter()
+            .filter(|s| options.cipher_suites.contains(&format!("{:?}", s.suite())))
+            .copied()
+            .collect();
+    }
+
+    if !options.kx_groups.is_empty() {
+        for name in &options.kx_groups {
+            if !supported_kx_groups().contains(name) {
+                return Err(anyhow::anyhow!("unknown key exchange group {name}"));
+            }
+        }
+        provider.kx_groups = rustls::crypto::aws_lc_rs::ALL_KX_GROUPS
+            .iter()
+            .filter(|g| options.kx_groups.contains(&format!("{:?}", g.name())))
+            .copied()
+            .collect();
+    }
+
+    Ok(provider)
+}
+
+// ServerConfig builder with provider and protocol versions from CONFIG
+pub fn tls_config_builder() -> Result<rustls::ConfigBuilder<ServerConfig, WantsVerifier>> {
+    let options = &CONFIG.tls_options;
+    let versions: &[&'static rustls::SupportedProtocolVersion] = match options.min_version.as_deref() {
+        Some("1.3") => &[&rustls::version::TLS13],
+        _ => &[&rustls::version::TLS13, &rustls::version::TLS12],
+    };
+    Ok(ServerConfig::builder_with_provider(Arc::new(tls_provider(options)?))
+        .with_protocol_versions(versions)?)
+}
+
+// ALPN, session tickets and key logging on a built ServerConfig
+pub fn tls_config_finish(config: &mut ServerConfig) -> Result<()> {
+    let options = &CONFIG.tls_options;
+    config.alpn_protocols = options.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+    match &options.tickets {
+        TicketMode::Off => (),
+        TicketMode::Rotating => {
+            // Keep one ticketer for the life of the process: the acceptor is
+            // rebuilt every minute and a new ticketer would orphan every ticket.
+            static ROTATING: OnceLock<Arc<dyn ProducesTickets>> = OnceLock::new();
+            let ticketer = match ROTATING.get() {
+                Some(t) => t.clone(),
+                None => {
+                    let t = rustls::crypto::aws_lc_rs::Ticketer::new()?;
+                    ROTATING.get_or_init(|| t).clone()
+                }
+            };
+            config.ticketer = ticketer;
+        }
+        TicketMode::SharedKeys(path) => {
+            config.ticketer = Arc::new(SharedKeyTicketer::load(path, options.ticket_lifetime)?);
+        }
+    }
+
+    if env::var_os("SSLKEYLOGFILE").is_some() {
+        static WARNED: OnceLock<()> = OnceLock::new();
+        WARNED.get_or_init(|| eprintln!("TLS: WARNING: writing session keys to SSLKEYLOGFILE, traffic can be decrypted"));
+        config.key_log = Arc::new(KeyLogFile::new());
+    }
+
+    Ok(())
+}
+
+// Stateless session tickets sealed with AES-256-GCM under keys shared by
+// every server behind the same name. Ticket layout:
+// key id (first 4 bytes of SHA-256 of the key) | nonce | ciphertext+tag
+struct SharedKeyTicketer {
+    keys: Vec<([u8; TICKET_KEY_ID_LEN], LessSafeKey)>,
+    lifetime: u32,
+    rng: SystemRandom,
+}
+
+impl std::fmt::Debug for SharedKeyTicketer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedKeyTicketer")
+            .field("keys", &self.keys.len())
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+impl SharedKeyTicketer {
+    fn load(path: &Path, lifetime: u32) -> Result<SharedKeyTicketer> {
+        let text = tls_file_read(path)
+            .map(|text| String::from_utf8_lossy(&text).into_owned())
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.to_string_lossy()))?;
+        let mut keys = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = decode_hex(line)
+                .filter(|k| k.len() == 32)
+                .ok_or_else(|| anyhow::anyhow!("{}:{}: expected 64 hex digits", path.to_string_lossy(), n + 1))?;
+            let mut id = [0u8; TICKET_KEY_ID_LEN];
+            id.copy_from_slice(&Sha256::digest(&key)[..TICKET_KEY_ID_LEN]);
+            let key = LessSafeKey::new(
+                UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow::anyhow!("bad ticket key"))?,
+            );
+            keys.push((id, key));
+        }
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("{}: no session ticket keys", path.to_string_lossy()));
+        }
+        Ok(SharedKeyTicketer { keys, lifetime, rng: SystemRandom::new() })
+    }
+}
+
+impl ProducesTickets for SharedKeyTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let (id, key) = self.keys.first()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(id), &mut sealed)
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(TICKET_KEY_ID_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(id);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend(sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < TICKET_KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = cipher.split_at(TICKET_KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let (id, key) = self.keys.iter().find(|(k, _)| k == id)?;
+
+        let mut sealed = sealed.to_vec();
+        let plain = key
+            .open_in_place(Nonce::try_assume_unique_for_key(nonce).ok()?, Aad::from(id), &mut sealed)
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,262 @@
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Component;
+use crate::*;
+
+// Set for the helper process: its end of the connection
+const TLS_HELPER_ENV: &str = "MCHTTP_TLS_HELPER";
+// Set for a process started by an upgrade: the server's end, passed on
+const TLS_HELPER_CONNECTION_ENV: &str = "MCHTTP_TLS_HELPER_FD";
+
+// Requests: one of these, then the length of the path (4 bytes, big endian)
+// and the path. Responses: 0 (or 1 for an error), then the length of the
+// payload and the payload (or the error message).
+const ALLOW: u8 = b'a';
+const SEAL: u8 = b's';
+const READ: u8 = b'r';
+const LIST: u8 = b'l';
+const KIND: u8 = b'k';
+const MAX_PATH_LEN: usize = 4096;
+
+// What a TLS path names, following symlinks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsFileKind {
+    File,
+    Dir,
+    Other,
+}
+
+lazy_static! {
+    // The connection to the helper, once privileges are dropped; until then
+    // (and without one) TLS files are read directly
+    static ref TLS_HELPER: Mutex<Option<UnixStream>> = Mutex::new(inherited_tls_helper());
+}
+
+fn inherited_tls_helper() -> Option<UnixStream> {
+    let fd = env::var(TLS_HELPER_CONNECTION_ENV).ok()?.parse::<RawFd>().ok()?;
+    if let Err(e) = set_close_on_exec(fd, true) {
+        eprintln!("TLS: ignoring inherited helper connection: {e}");
+        return None;
+    }
+    Some(unsafe { UnixStream::from_raw_fd(fd) })
+}
+
+fn write_message(stream: &mut UnixStream, kind: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn read_message(stream: &mut UnixStream, max_len: usize) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head)?;
+    let len = u32::from_be_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    if len > max_len {
+        return Err(std::io::Error::other("TLS helper: message too long"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((head[0], payload))
+}
+
+// Ask the helper, if there is one
+fn through_helper(request: u8, path: &Path) -> Option<std::io::Result<Vec<u8>>> {
+    let mut helper = TLS_HELPER.lock().unwrap();
+    let stream = helper.as_mut()?;
+    let mut exchange = || -> std::io::Result<Vec<u8>> {
+        write_message(stream, request, path.as_os_str().as_bytes())?;
+        match read_message(stream, usize::MAX)? {
+            (0, payload) => Ok(payload),
+            (_, message) => Err(std::io::Error::other(String::from_utf8_lossy(&message).into_owned())),
+        }
+    };
+    Some(exchange())
+}
+
+// A certificate, key, OCSP response, passphrase or ticket key file
+pub fn tls_file_read(path: &Path) -> std::io::Result<Vec<u8>> {
+    through_helper(READ, path).unwrap_or_else(|| std::fs::read(path))
+}
+
+// The entries of a TLS directory, as paths
+pub fn tls_file_list(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    match through_helper(LIST, path) {
+        Some(names) => Ok(names?
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| path.join(OsStr::from_bytes(name)))
+            .collect()),
+        None => std::fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect(),
+    }
+}
+
+pub fn tls_file_kind(path: &Path) -> std::io::Result<TlsFileKind> {
+    let kind = match through_helper(KIND, path) {
+        Some(kind) => kind?.first().copied().unwrap_or_default(),
+        None => {
+            let metadata = std::fs::metadata(path)?;
+            if metadata.is_file() {
+                b'f'
+            } else if metadata.is_dir() {
+                b'd'
+            } else {
+                b'o'
+            }
+        }
+    };
+    Ok(match kind {
+        b'f' => TlsFileKind::File,
+        b'd' => TlsFileKind::Dir,
+        _ => TlsFileKind::Other,
+    })
+}
+
+// Everything the helper may read for a configuration: the -t directory (or
+// file, with its .crt/.key/.ocsp siblings), the passphrase file and the
+// session ticket keys
+fn tls_helper_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(tls) = config.tls.as_deref().filter(|tls| *tls != AUTO_TLS) {
+        let tls = PathBuf::from(tls);
+        if !tls.is_dir() {
+            paths.extend(["crt", "key", "ocsp"].map(|extension| tls.with_extension(extension)));
+        }
+        paths.push(tls);
+    }
+    paths.extend(config.tls_passphrase_file.clone());
+    if let TicketMode::SharedKeys(path) = &config.tls_options.tickets {
+        paths.push(path.clone());
+    }
+    paths
+}
+
+// Before privileges are dropped: start our binary again as a helper, still
+// running as root (and outside any chroot), that reads the configuration's
+// TLS files for us and nothing else. Returns its PID.
+pub fn start_tls_helper(config: &Config) -> Result<u32> {
+    let (mut ours, theirs) = UnixStream::pair()?;
+    let fd = theirs.as_raw_fd();
+    let mut command = std::process::Command::new(env::current_exe()?);
+    command
+        .env_clear()
+        .env(TLS_HELPER_ENV, fd.to_string())
+        .stdin(std::process::Stdio::null());
+    // Between fork and exec, in the child: only async-signal-safe calls
+    unsafe {
+        command.pre_exec(move || set_close_on_exec(fd, false));
+    }
+    let helper = command.spawn()?;
+    drop(theirs);
+
+    for path in tls_helper_paths(config) {
+        write_message(&mut ours, ALLOW, path.as_os_str().as_bytes())?;
+        read_message(&mut ours, MAX_PATH_LEN)?;
+    }
+    write_message(&mut ours, SEAL, &[])?;
+    read_message(&mut ours, MAX_PATH_LEN)?;
+
+    *TLS_HELPER.lock().unwrap() = Some(ours);
+    Ok(helper.id())
+}
+
+// For an upgrade: the connection, taken out of use here until it's either
+// handed back (the new process failed) or dropped (it took over)
+pub fn lend_tls_helper() -> Option<UnixStream> {
+    TLS_HELPER.lock().unwrap().take()
+}
+
+pub fn restore_tls_helper(stream: UnixStream) {
+    *TLS_HELPER.lock().unwrap() = Some(stream);
+}
+
+// How a new process finds a lent connection
+pub fn tls_helper_environment(stream: &UnixStream) -> (&'static str, String) {
+    (TLS_HELPER_CONNECTION_ENV, stream.as_raw_fd().to_string())
+}
+
+// Requested paths are taken as given (symlinks are followed: certbot's live
+// directory links into its archive), but must lie under an allowed one
+fn helper_allows(allowed: &[PathBuf], path: &Path) -> bool {
+    let Ok(path) = std::path::absolute(path) else {
+        return false;
+    };
+    !path.components().any(|component| component == Component::ParentDir)
+        && allowed.iter().any(|allowed| path.starts_with(allowed))
+}
+
+fn helper_request(allowed: &mut Vec<PathBuf>, sealed: &mut bool, request: u8, path: &Path) -> std::io::Result<Vec<u8>> {
+    match request {
+        ALLOW if !*sealed => {
+            allowed.push(std::path::absolute(path)?);
+            return Ok(Vec::new());
+        }
+        SEAL => {
+            *sealed = true;
+            return Ok(Vec::new());
+        }
+        _ if !*sealed || !helper_allows(allowed, path) => {
+            return Err(std::io::Error::other("not a TLS file the configuration names (changing -t needs a restart)"));
+        }
+        _ => (),
+    }
+    match request {
+        READ => std::fs::read(path),
+        LIST => {
+            let mut names = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                names.extend_from_slice(entry?.file_name().as_bytes());
+                names.push(0);
+            }
+            Ok(names)
+        }
+        KIND => {
+            let metadata = std::fs::metadata(path)?;
+            Ok(vec![if metadata.is_file() {
+                b'f'
+            } else if metadata.is_dir() {
+                b'd'
+            } else {
+                b'o'
+            }])
+        }
+        _ => Err(std::io::Error::other("unknown request")),
+    }
+}
+
+// In the helper process: serve requests until every server process holding
+// the connection has gone. Returns None in the server.
+pub fn run_tls_helper() -> Option<i32> {
+    let fd = env::var(TLS_HELPER_ENV).ok()?.parse::<RawFd>().ok()?;
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    // The server's signals are for the server (pkill -HUP mchttp, Ctrl-C on
+    // a terminal); this goes when it does
+    for signal in [libc::SIGHUP, libc::SIGINT, libc::SIGTERM, libc::SIGUSR2] {
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+    }
+
+    let mut allowed = Vec::new();
+    let mut sealed = false;
+    loop {
+        let (request, path) = match read_message(&mut stream, MAX_PATH_LEN) {
+            Ok(message) => message,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Some(0),
+            Err(e) => {
+                eprintln!("TLS helper: {e}");
+                return Some(1);
+            }
+        };
+        let path = PathBuf::from(OsStr::from_bytes(&path));
+        let sent = match helper_request(&mut allowed, &mut sealed, request, &path) {
+            Ok(payload) => write_message(&mut stream, 0, &payload),
+            Err(e) => write_message(&mut stream, 1, e.to_string().as_bytes()),
+        };
+        if sent.is_err() {
+            return Some(0);
+        }
+    }
+}
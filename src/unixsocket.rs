@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use crate::*;
+
+// A Unix domain socket to listen on, from -l unix:/path[;option=value...]
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl fmt::Display for UnixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unix:{}", self.path.to_string_lossy())
+    }
+}
+
+impl UnixSocket {
+    pub fn usage() {
+        println!("       -l, --listen unix:/path/to/socket[;option=value...]");
+        println!("                     listen on a Unix domain socket instead, for a reverse proxy on this");
+        println!("                     machine (plain HTTP, and h2c with -H); a stale socket file is replaced");
+        println!("                     options: mode=0660           permissions of the socket file");
+        println!("                              owner=name|uid      owner of the socket file");
+        println!("                              group=name|gid      group of the socket file");
+    }
+
+    pub fn parse(spec: &str) -> Result<UnixSocket> {
+        let mut parts = spec.split(';');
+        let path = parts.next().and_then(|path| path.strip_prefix("unix:")).unwrap_or_default();
+        if !path.starts_with('/') {
+            return Err(anyhow::anyhow!("expected unix:/absolute/path, got {spec}"));
+        }
+
+        let mut socket = UnixSocket {
+            path: PathBuf::from(path),
+            mode: None,
+            owner: None,
+            group: None,
+        };
+        for option in parts {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected socket option key=value, got {option}"))?;
+            match key.trim() {
+                "mode" => {
+                    socket.mode = match u32::from_str_radix(value, 8) {
+                        Ok(mode) if mode <= 0o777 => Some(mode),
+                        _ => return Err(anyhow::anyhow!("socket mode {value} should be octal, like 0660")),
+                    }
+                }
+                "owner" => socket.owner = Some(String::from(value)),
+                "group" => socket.group = Some(String::from(value)),
+                _ => return Err(anyhow::anyhow!("unknown socket option {key}")),
+            }
+        }
+        Ok(socket)
+    }
+
+    // The owner and group to give the socket file, as IDs
+    pub fn ownership(&self) -> Result<(Option<libc::uid_t>, Option<libc::gid_t>)> {
+        let uid = match &self.owner {
+            Some(owner) => Some(lookup_user(owner).map_err(|e| anyhow::anyhow!("socket owner {e}"))?.1),
+            None => None,
+        };
+        let gid = match &self.group {
+            Some(group) => Some(lookup_group(group).map_err(|e| anyhow::anyhow!("socket group {e}"))?.1),
+            None => None,
+        };
+        Ok((uid, gid))
+    }
+}
+
+// Whether a socket file is left over from a server that's gone: false when
+// there's nothing there, an error when it's in use or not a socket at all
+pub fn stale_unix_socket(path: &Path) -> std::io::Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => (),
+        Ok(_) => return Err(std::io::Error::new(ErrorKind::AlreadyExists, "exists and isn't a socket")),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(std::io::Error::new(ErrorKind::AddrInUse, "in use by another server")),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+// Bind a socket file, replacing a stale one, and give it the mode and
+// ownership asked for before anyone gets to connect. Binding already
+// listens, so that's done in a directory only we can enter, and the socket
+// is then renamed into place.
+pub fn bind_unix_socket(socket: &UnixSocket) -> Result<std::os::unix::net::UnixListener> {
+    let (uid, gid) = socket.ownership()?;
+    let stale = stale_unix_socket(&socket.path)?;
+    if uid.is_none() && gid.is_none() && socket.mode.is_none() {
+        if stale {
+            std::fs::remove_file(&socket.path)?;
+        }
+        return Ok(std::os::unix::net::UnixListener::bind(&socket.path)?);
+    }
+
+    let (Some(dir), Some(name)) = (socket.path.parent(), socket.path.file_name()) else {
+        return Err(anyhow::anyhow!("{}: not a file path", socket.path.to_string_lossy()));
+    };
+    let private = dir.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join(name);
+    let listener = (|| -> std::io::Result<_> {
+        let listener = std::os::unix::net::UnixListener::bind(&bound)?;
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(&bound, uid, gid)?;
+        }
+        if let Some(mode) = socket.mode {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        }
+        std::fs::rename(&bound, &socket.path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private);
+    Ok(listener?)
+}
+
+// Once it's no longer listened on; only ever a socket, and inside a chroot
+// only one still within reach
+pub fn remove_unix_socket(path: &Path) {
+    let path = chroot_path(path);
+    if chrooted() && matches!(path, Cow::Borrowed(_)) {
+        return;
+    }
+    let removed = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path),
+        _ => return,
+    };
+    if let Err(e) = removed {
+        eprintln!("Listener: {}: can't remove socket file: {e}", path.to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sockets() {
+        assert_eq!(
+            UnixSocket::parse("unix:/run/mchttp.sock;mode=0660;owner=www-data;group=1000").unwrap(),
+            UnixSocket {
+                path: PathBuf::from("/run/mchttp.sock"),
+                mode: Some(0o660),
+                owner: Some(String::from("www-data")),
+                group: Some(String::from("1000")),
+            }
+        );
+        assert_eq!(UnixSocket::parse("unix:/tmp/s").unwrap().mode, None);
+        for bad in ["/run/mchttp.sock", "unix:run/mchttp.sock", "unix:/s;mode=0999", "unix:/s;mode=01777", "unix:/s;mode", "unix:/s;size=1"] {
+            assert!(UnixSocket::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn bind_addresses() {
+        assert_eq!(BindAddr::parse("127.0.0.1:8080").unwrap().port(), Some(8080));
+        let unix = BindAddr::parse("unix:/tmp/mchttp.sock;mode=0600").unwrap();
+        assert_eq!(unix.port(), None);
+        assert!(matches!(unix, BindAddr::Unix(socket) if socket.mode == Some(0o600)));
+        assert!(BindAddr::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn bound_with_mode_in_place() {
+        let dir = std::env::temp_dir().join(format!("mchttp-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("s.sock");
+        std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(stale_unix_socket(&path).unwrap());
+
+        let socket = UnixSocket::parse(&format!("unix:{};mode=0604", path.to_string_lossy())).unwrap();
+        let listener = bind_unix_socket(&socket).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o604);
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        assert!(bind_unix_socket(&socket).is_err());
+
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["s.sock"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::AtomicBool;
+use std::sync::OnceLock;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::*;
+
+// Set for a process started by an upgrade: the listening sockets it inherits
+// (tcp:address=fd,udp:address=fd,unix:path=fd) and the pipe it reports
+// readiness on
+const UPGRADE_LISTENERS_ENV: &str = "MCHTTP_UPGRADE_LISTENERS";
+const UPGRADE_READY_ENV: &str = "MCHTTP_UPGRADE_READY_FD";
+// How long the new process has to get its listeners going
+const UPGRADE_READY_TIMEOUT: Duration = Duration::from_secs(30);
+const UPGRADE_READY: &str = "ready";
+
+// The binary we were started from, resolved at startup: once a deployment
+// replaces it, /proc/self/exe names the deleted old one
+static EXECUTABLE: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref INHERITED_LISTENERS: Mutex<HashMap<ListenAddr, OwnedFd>> = Mutex::new(inherited_listeners());
+}
+
+impl ListenAddr {
+    fn spec(&self) -> String {
+        match self {
+            ListenAddr::Tcp(addr) => format!("tcp:{addr}"),
+            ListenAddr::Quic(addr) => format!("udp:{addr}"),
+            ListenAddr::Unix(path) => format!("unix:{}", path.to_string_lossy()),
+        }
+    }
+
+    fn parse(spec: &str) -> Option<ListenAddr> {
+        match spec.split_once(':')? {
+            ("tcp", addr) => addr.parse().ok().map(ListenAddr::Tcp),
+            ("udp", addr) => addr.parse().ok().map(ListenAddr::Quic),
+            ("unix", path) => Some(ListenAddr::Unix(PathBuf::from(path))),
+            _ => None,
+        }
+    }
+}
+
+pub fn record_executable() {
+    EXECUTABLE.get_or_init(|| env::current_exe().map_err(|e| e.to_string()));
+}
+
+// True once a new process has taken over our listeners
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Relaxed)
+}
+
+pub fn set_close_on_exec(fd: RawFd, close: bool) -> std::io::Result<()> {
+    let flags = if close { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn inherited_listeners() -> HashMap<ListenAddr, OwnedFd> {
+    let mut inherited = HashMap::new();
+    let Ok(spec) = env::var(UPGRADE_LISTENERS_ENV) else {
+        return inherited;
+    };
+    for listener in spec.split(',').filter(|l| !l.is_empty()) {
+        let parsed = listener
+            .rsplit_once('=')
+            .and_then(|(addr, fd)| Some((ListenAddr::parse(addr)?, fd.parse::<RawFd>().ok()?)));
+        match parsed {
+            // Not to be passed on to CGI scripts
+            Some((addr, fd)) if set_close_on_exec(fd, true).is_ok() => {
+                inherited.insert(addr, unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+            _ => eprintln!("Upgrade: ignoring inherited listener {listener}"),
+        }
+    }
+    inherited
+}
+
+// A duplicate of the listening socket the previous process handed over for
+// this address. The socket stays here until claimed, in case the
+// configuration is rejected.
+pub fn inherited_listener(addr: &ListenAddr) -> Option<OwnedFd> {
+    let inherited = INHERITED_LISTENERS.lock().unwrap();
+    match inherited.get(addr)?.try_clone() {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            eprintln!("Upgrade: {addr}: {e}");
+            None
+        }
+    }
+}
+
+// Once a listener is running on it, the socket is no longer ours to hand out
+pub fn claim_inherited_listener(addr: &ListenAddr) {
+    INHERITED_LISTENERS.lock().unwrap().remove(addr);
+}
+
+// Once listening, tell the process that started us (if an upgrade did) that
+// it can go, and close any inherited sockets the configuration didn't want
+pub fn report_upgrade_ready() {
+    for (addr, _) in INHERITED_LISTENERS.lock().unwrap().drain() {
+        eprintln!("Upgrade: {addr}: no longer configured, closed");
+    }
+    let Some(fd) = env::var(UPGRADE_READY_ENV).ok().and_then(|fd| fd.parse::<RawFd>().ok()) else {
+        return;
+    };
+    // Before the old process goes: systemd takes its exit for the service's
+    notify_systemd(&format!("MAINPID={}", process::id()));
+    let mut ready = unsafe { File::from_raw_fd(fd) };
+    if let Err(e) = ready.write_all(UPGRADE_READY.as_bytes()) {
+        eprintln!("Upgrade: failed to report readiness: {e}");
+    }
+}
+
+// Start our binary (most likely a new build by now) with the same arguments,
+// handing it the listening sockets. Once it says it's ready both accept on
+// them until we stop; if it doesn't, it's killed and we carry on.
+async fn upgrade() -> Result<u32> {
+    let _reloading = RELOADING.lock().await;
+    if shutting_down() {
+        return Err(Error::msg("shutting down"));
+    }
+    if chrooted() {
+        return Err(Error::msg("the binary is out of reach inside --chroot"));
+    }
+    let executable = match EXECUTABLE.get() {
+        Some(Ok(executable)) => executable.clone(),
+        Some(Err(e)) => return Err(anyhow::anyhow!("can't tell which binary to run: {e}")),
+        None => return Err(Error::msg("can't tell which binary to run")),
+    };
+
+    let sockets = listening_sockets();
+    let listeners: Vec<String> = sockets.iter().map(|(addr, fd)| format!("{}={fd}", addr.spec())).collect();
+    let (mut ready_read, ready_write) = std::io::pipe()?;
+    let ready_fd = ready_write.as_raw_fd();
+    let mut inherit: Vec<RawFd> = sockets.iter().map(|(_, fd)| *fd).collect();
+    inherit.push(ready_fd);
+
+    let mut command = tokio::process::Command::new(&executable);
+    command
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_LISTENERS_ENV, listeners.join(","))
+        .env(UPGRADE_READY_ENV, ready_fd.to_string())
+        // The new process becomes the one to ping the systemd watchdog
+        .env_remove("WATCHDOG_PID");
+    // Having dropped privileges, it needs our TLS helper; we don't use it
+    // meanwhile, or the two would talk over each other
+    let tls_helper = lend_tls_helper();
+    if let Some(tls_helper) = &tls_helper {
+        let (name, fd) = tls_helper_environment(tls_helper);
+        command.env(name, fd);
+        inherit.push(tls_helper.as_raw_fd());
+    }
+    // Between fork and exec, in the child: only async-signal-safe calls
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherit {
+                set_close_on_exec(*fd, false)?;
+            }
+            Ok(())
+        });
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(tls_helper) = tls_helper {
+                restore_tls_helper(tls_helper);
+            }
+            return Err(e.into());
+        }
+    };
+    let pid = child.id().unwrap_or_default();
+    eprintln!(
+        "Upgrade: started {} (PID {pid}) with {} listener(s)",
+        executable.to_string_lossy(),
+        sockets.len()
+    );
+    // Ours closed, so the pipe reads EOF if the child exits before it's ready
+    drop(ready_write);
+
+    let ready = timeout(
+        UPGRADE_READY_TIMEOUT,
+        tokio::task::spawn_blocking(move || {
+            let mut report = String::new();
+            ready_read.read_to_string(&mut report).map(|_| report)
+        }),
+    )
+    .await;
+    match ready {
+        Ok(Ok(Ok(report))) if report == UPGRADE_READY => {
+            HANDED_OVER.store(true, Ordering::Relaxed);
+            request_shutdown("upgraded");
+            Ok(pid)
+        }
+        _ => {
+            let _ = child.start_kill();
+            let status = child.wait().await;
+            if let Some(tls_helper) = tls_helper {
+                restore_tls_helper(tls_helper);
+            }
+            let status = status?;
+            Err(anyhow::anyhow!("new process (PID {pid}) wasn't ready in time, or exited: {status}"))
+        }
+    }
+}
+
+// SIGUSR2 upgrades to the binary on disk: a new process takes over the
+// listening sockets (none are closed in between) and this one drains.
+pub async fn upgrade_on_signal() -> Result<()> {
+    let mut upgrades = signal(SignalKind::user_defined2())?;
+    while upgrades.recv().await.is_some() {
+        eprintln!("Upgrade: SIGUSR2, starting new process");
+        match upgrade().await {
+            Ok(pid) => eprintln!("Upgrade: PID {pid} ready, handing over"),
+            Err(e) => eprintln!("Upgrade: failed, carrying on: {e}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,434 @@
+use std::fmt;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use crate::*;
+
+pub const DEFAULT_INDEX_FILES: [&str; 1] = ["index.html"];
+pub const DEFAULT_MAX_UPLOAD: u64 = 100 * 1024 * 1024;
+
+// Tells apart the temporary files of concurrent uploads
+static UPLOAD_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// A site from a [vhost."name"] section of the configuration file
+#[derive(Debug, Clone, Default)]
+pub struct VirtualHost {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub root: Option<PathBuf>,
+    pub index: Vec<String>,
+    pub headers: Vec<(String, String)>,
+    pub autoindex: bool,
+    pub upload: bool,
+    pub auth: Option<BasicAuth>,
+}
+
+// HTTP Basic authentication (RFC 7617) against an htpasswd style file of
+// user:{SHA}base64 (htpasswd -s), user:{SHA256}base64 or user:$2y$... bcrypt
+// (htpasswd -B) lines
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub realm: String,
+    pub users: HashMap<String, PasswordHash>,
+}
+
+#[derive(Clone)]
+pub enum PasswordHash {
+    Sha1(Vec<u8>),
+    Sha256(Vec<u8>),
+    Bcrypt(String),
+}
+
+// Keep hashes out of the startup config dump
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordHash::Sha1(_) => write!(f, "{{SHA}}"),
+            PasswordHash::Sha256(_) => write!(f, "{{SHA256}}"),
+            PasswordHash::Bcrypt(_) => write!(f, "bcrypt"),
+        }
+    }
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Result<PasswordHash> {
+        let decode = |b64: &str| {
+            base64::engine::general_purpose::STANDARD
+                .decode(b64)
+                .map_err(|e| anyhow::anyhow!("bad base64 in password hash: {e}"))
+        };
+        if let Some(b64) = hash.strip_prefix("{SHA}") {
+            Ok(PasswordHash::Sha1(decode(b64)?))
+        } else if let Some(b64) = hash.strip_prefix("{SHA256}") {
+            Ok(PasswordHash::Sha256(decode(b64)?))
+        } else if hash.starts_with("$2") {
+            hash.parse::<bcrypt::HashParts>().map_err(|e| anyhow::anyhow!("bad bcrypt password hash: {e}"))?;
+            Ok(PasswordHash::Bcrypt(String::from(hash)))
+        } else {
+            Err(Error::msg("unsupported password hash (use {SHA}, {SHA256} or bcrypt)"))
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let (expected, actual) = match self {
+            PasswordHash::Sha1(hash) => (hash, Sha1::digest(password.as_bytes()).to_vec()),
+            PasswordHash::Sha256(hash) => (hash, Sha256::digest(password.as_bytes()).to_vec()),
+            PasswordHash::Bcrypt(hash) => return bcrypt::verify(password, hash).unwrap_or(false),
+        };
+        // Compare every byte, so timing doesn't give away how much matched
+        expected.len() == actual.len() && expected.iter().zip(&actual).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl BasicAuth {
+    pub fn load(realm: &str, users: &Path) -> Result<BasicAuth> {
+        let text = std::fs::read_to_string(users).map_err(|e| anyhow::anyhow!("{}: {e}", users.to_string_lossy()))?;
+        let mut table = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("{}:{}: expected user:hash", users.to_string_lossy(), number + 1))?;
+            let hash = PasswordHash::parse(hash)
+                .map_err(|e| anyhow::anyhow!("{}:{}: {e}", users.to_string_lossy(), number + 1))?;
+            table.insert(String::from(user), hash);
+        }
+        Ok(BasicAuth {
+            realm: String::from(realm),
+            users: table,
+        })
+    }
+
+    // User named by a valid Authorization: Basic header
+    fn authenticate(&self, authorization: Option<&String>) -> Option<String> {
+        let (scheme, encoded) = authorization?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        self.users
+            .get(user)
+            .filter(|hash| hash.matches(password))
+            .map(|_| String::from(user))
+    }
+}
+
+impl VirtualHost {
+    fn answers_to(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+pub fn virtual_host(server_name: &Option<String>) -> Option<&'static VirtualHost> {
+    let server_name = server_name.as_ref()?;
+    CONFIG.vhosts.iter().find(|vhost| vhost.name == *server_name)
+}
+
+// Server name a request is for: the TLS SNI name or, on plain HTTP, the
+// Host header, with configured aliases mapped to their site's name.
+pub fn resolve_server_name(sni: Option<String>, headers: &HashMap<String, String>) -> Option<String> {
+    let requested = match &sni {
+        Some(sni) => sni.clone(),
+        None => {
+            let host = headers.get("host")?;
+            let host = match host.rsplit_once(':') {
+                Some((name, port)) if !port.contains(']') => name,
+                _ => host.as_str(),
+            };
+            host.trim_start_matches('[').trim_end_matches(']').to_lowercase()
+        }
+    };
+    match CONFIG.vhosts.iter().find(|vhost| vhost.answers_to(&requested)) {
+        Some(vhost) => Some(vhost.name.clone()),
+        None => sni,
+    }
+}
+
+// Extra response header lines for the site
+pub fn virtual_host_headers(server_name: &Option<String>) -> String {
+    let mut lines = String::new();
+    if let Some(vhost) = virtual_host(server_name) {
+        for (name, value) in &vhost.headers {
+            lines.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    lines
+}
+
+// Answers 401 and returns false when the site wants credentials the
+// request doesn't have
+pub async fn check_authorization<S: AsyncRead + AsyncWrite + Unpin>(request: &mut HttpRequest<S>) -> Result<bool> {
+    let Some(auth) = virtual_host(&request.server_name).and_then(|vhost| vhost.auth.as_ref()) else {
+        return Ok(true);
+    };
+    // bcrypt is slow on purpose, so it's kept off the runtime's threads
+    let authorization = request.headers.get("authorization").cloned();
+    if tokio::task::spawn_blocking(move || auth.authenticate(authorization.as_ref())).await?.is_some() {
+        return Ok(true);
+    }
+    eprintln!(
+        "Request (server {}) client {} {} {}: authorization required (401)",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url
+    );
+    request
+        .stream
+        .write_all(
+            format!(
+                "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"\r\nContent-Length: 0\r\n{}{}{}Connection: close\r\n\r\n",
+                auth.realm.replace('"', ""),
+                cache_control_header(),
+                virtual_host_headers(&request.server_name),
+                alt_svc_header()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    request.stream.flush().await?;
+    Ok(false)
+}
+
+// Whether a canonical path inside the site root of server_name is in the
+// root of another, more specific site (e.g. data_dir/<vhost> seen from the
+// default site), which has to be asked for by its own name so its
+// authorization and settings apply
+pub fn in_other_site(server_name: &Option<String>, path: &Path) -> bool {
+    let Ok(own) = std::fs::canonicalize(site_root(server_name)) else {
+        return true;
+    };
+    CONFIG.vhosts.iter().filter(|vhost| Some(&vhost.name) != server_name.as_ref()).any(|vhost| {
+        std::fs::canonicalize(site_root(&Some(vhost.name.clone())))
+            .is_ok_and(|root| path.starts_with(&root) && !own.starts_with(&root))
+    })
+}
+
+pub fn index_files(server_name: &Option<String>) -> Vec<String> {
+    match virtual_host(server_name) {
+        Some(vhost) if !vhost.index.is_empty() => vhost.index.clone(),
+        _ => DEFAULT_INDEX_FILES.iter().map(|name| String::from(*name)).collect(),
+    }
+}
+
+pub fn autoindex_enabled(server_name: &Option<String>) -> bool {
+    virtual_host(server_name).is_some_and(|vhost| vhost.autoindex)
+}
+
+pub fn upload_enabled(server_name: &Option<String>) -> bool {
+    virtual_host(server_name).is_some_and(|vhost| vhost.upload)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// HTML listing of a directory for autoindex sites, directories first.
+// Hidden (dot) files aren't listed.
+pub async fn directory_listing(url: &str, dir: &Path) -> Result<String> {
+    let mut entries = Vec::new();
+    let mut reader = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = reader.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        entries.push((!meta.is_dir(), name, meta.len()));
+    }
+    entries.sort();
+
+    let base = if url.ends_with('/') { String::from(url) } else { format!("{url}/") };
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<ul>\n",
+        html_escape(&base)
+    );
+    if base != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    // The URL arrives decoded, so it's encoded again, segment by segment
+    let href_base: Vec<_> = base.split('/').map(urlencoding::encode).collect();
+    let href_base = href_base.join("/");
+    for (is_file, name, size) in entries {
+        let href = format!("{}{}", href_base, urlencoding::encode(&name));
+        if is_file {
+            html.push_str(&format!("<li><a href=\"{}\">{}</a> {} bytes</li>\n", href, html_escape(&name), size));
+        } else {
+            html.push_str(&format!("<li><a href=\"{}/\">{}/</a></li>\n", href, html_escape(&name)));
+        }
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    Ok(html)
+}
+
+// PUT a file into an upload enabled site: written to a temporary file next
+// to it, then renamed into place. The directory must already exist.
+pub async fn request_handler_upload<S: AsyncRead + AsyncWrite + Unpin>(mut request: HttpRequest<S>) -> Result<()> {
+    let start_time = Instant::now();
+    let status = match upload(&mut request).await {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!(
+                "Request (server {}) client {} {} {}: upload failed: {e}",
+                &request.server_name.as_ref().map_or("default", |x| x),
+                &request.client,
+                &request.method,
+                &request.url
+            );
+            "500 Internal Server Error"
+        }
+    };
+    send_status_response(&mut request, status).await?;
+    request.stream.flush().await?;
+    println!(
+        "Request (server {}) client {} {} {} upload {} in {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        status,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+async fn upload<S: AsyncRead + AsyncWrite + Unpin>(request: &mut HttpRequest<S>) -> Result<&'static str> {
+    // A length, or a chunked body (as HTTP/2 and HTTP/3 uploads without
+    // one are passed on) whose size only shows as it arrives
+    let limit = CONFIG.limits.max_upload;
+    let content_length = match (request.headers.get("content-length"), request.headers.get("transfer-encoding")) {
+        (Some(_), Some(_)) => return Ok("400 Bad Request"),
+        (None, Some(te)) if te.trim().eq_ignore_ascii_case("chunked") => None,
+        (None, Some(_)) => return Ok("501 Not Implemented"),
+        (Some(length), None) => match length.parse::<u64>() {
+            Ok(length) if length > limit => return Ok("413 Content Too Large"),
+            Ok(length) => Some(length),
+            Err(_) => return Ok("400 Bad Request"),
+        },
+        (None, None) => return Ok("411 Length Required"),
+    };
+
+    let root = tokio::fs::canonicalize(site_root(&request.server_name)).await?;
+    let relative = request.url.trim_start_matches('/');
+    let Some(name) = Path::new(relative).file_name().map(|n| n.to_string_lossy().to_string()) else {
+        return Ok("405 Method Not Allowed");
+    };
+    if relative.ends_with('/') || name.starts_with('.') {
+        return Ok("403 Forbidden");
+    }
+    let parent = match tokio::fs::canonicalize(root.join(relative).parent().unwrap_or(&root)).await {
+        Ok(parent) if parent.starts_with(&root) && !in_other_site(&request.server_name, &parent) => parent,
+        Ok(_) => return Ok("403 Forbidden"),
+        Err(_) => return Ok("409 Conflict"),
+    };
+    let target = parent.join(&name);
+    if tokio::fs::metadata(&target).await.is_ok_and(|meta| meta.is_dir()) {
+        return Ok("409 Conflict");
+    }
+    let existed = tokio::fs::symlink_metadata(&target).await.is_ok();
+
+    if request.headers.get("expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+        request.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        request.stream.flush().await?;
+    }
+    let temporary = parent.join(format!(
+        ".{}.upload-{}-{}",
+        name,
+        process::id(),
+        UPLOAD_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = tokio::fs::File::create(&temporary).await?;
+    // The body's size once it's all in the file
+    let copied = match content_length {
+        Some(length) => tokio::io::copy(&mut (&mut request.stream).take(length), &mut file)
+            .await
+            .map(|n| (n == length).then_some(n))
+            .map_err(Error::from),
+        None => copy_chunked(&mut request.stream, &mut file, true, limit).await.map(Some),
+    };
+    match copied {
+        Ok(Some(n)) if n <= limit => {
+            file.sync_all().await?;
+            tokio::fs::rename(&temporary, &target).await?;
+            Ok(if existed { "204 No Content" } else { "201 Created" })
+        }
+        result => {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            Ok(if result?.is_none() { "400 Bad Request" } else { "413 Content Too Large" })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_hashes() {
+        let sha = format!("{{SHA}}{}", base64::engine::general_purpose::STANDARD.encode(Sha1::digest(b"secret")));
+        let bcrypt = bcrypt::hash("secret", 4).unwrap().replacen("$2b$", "$2y$", 1);
+        for hash in [sha, bcrypt] {
+            let hash = PasswordHash::parse(&hash).unwrap();
+            assert!(hash.matches("secret"), "{hash:?}");
+            assert!(!hash.matches("Secret"), "{hash:?}");
+        }
+        assert!(PasswordHash::parse("$2y$04$tooshort").is_err());
+        assert!(PasswordHash::parse("$1$salt$md5crypt").is_err());
+    }
+
+    #[tokio::test]
+    async fn listing_escapes_names() {
+        let dir = std::env::temp_dir().join(format!("mchttp-listing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a <b>.txt"), "").unwrap();
+        let listing = directory_listing("/x\"><script>/", &dir).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(listing.contains("<li><a href=\"/x%22%3E%3Cscript%3E/a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a> 0 bytes</li>"), "{listing}");
+        assert!(!listing.contains("<script>"), "{listing}");
+    }
+
+    #[tokio::test]
+    async fn other_sites_need_their_own_name() {
+        let root = std::env::temp_dir().join(format!("mchttp-other-site-{}", std::process::id()));
+        for dir in ["", "secret.example", "private"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join("index.html"), "x").unwrap();
+        }
+        let auth = BasicAuth { realm: String::from("secret"), users: HashMap::new() };
+        let vhosts = vec![
+            VirtualHost { name: String::from("secret.example"), auth: Some(auth.clone()), ..VirtualHost::default() },
+            VirtualHost {
+                name: String::from("private.example"),
+                root: Some(root.join("private")),
+                auth: Some(auth),
+                ..VirtualHost::default()
+            },
+        ];
+        let config = Config { data_dir: Some(root.clone()), vhosts, ..Config::default() };
+
+        let get = |host: &'static str, path: &'static str| async move {
+            let (mut client, mut server_io) = tokio::io::duplex(4096);
+            let peer = Peer::from("127.0.0.1:40000".parse::<SocketAddr>().unwrap());
+            let server = spawn(with_config(async move { process(&mut server_io, peer, None).await }));
+            let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            server.await.unwrap().unwrap();
+            response.lines().next().unwrap_or_default().to_string()
+        };
+        with_test_config(config, async {
+            assert_eq!(get("localhost", "/index.html").await, "HTTP/1.1 200 OK");
+            assert_eq!(get("localhost", "/secret.example/index.html").await, "HTTP/1.1 404 Not Found");
+            assert_eq!(get("localhost", "/private/index.html").await, "HTTP/1.1 404 Not Found");
+            assert_eq!(get("secret.example", "/index.html").await, "HTTP/1.1 401 Unauthorized");
+            assert_eq!(get("private.example", "/index.html").await, "HTTP/1.1 401 Unauthorized");
+        })
+        .await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
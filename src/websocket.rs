@@ -0,0 +1,586 @@
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::RwLock;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncSeekExt;
+use crate::*;
+
+// RFC 6455 section 1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// Close status codes (RFC 6455 section 7.4.1)
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+pub type WsFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// What a handler gets to know about the request that was upgraded
+#[derive(Debug, Clone)]
+pub struct WebSocketRequest {
+    pub client: Peer,
+    pub server_name: Option<String>,
+    pub url: String,
+    pub query: HashMap<String, String>,
+}
+
+// A handler owns the connection from the 101 response until it returns.
+pub trait WebSocketHandler: Send + Sync {
+    fn name(&self) -> String;
+    fn handle<'a>(&'a self, ws: WebSocket<'a>, request: &'a WebSocketRequest) -> WsFuture<'a>;
+}
+
+lazy_static! {
+    // URL path -> handler
+    static ref WEBSOCKET_HANDLERS: RwLock<HashMap<String, Arc<dyn WebSocketHandler>>> = RwLock::new(HashMap::new());
+}
+
+pub fn register_websocket_handler(path: &str, handler: Arc<dyn WebSocketHandler>) {
+    eprintln!("WebSocket: {} -> {}", path, handler.name());
+    WEBSOCKET_HANDLERS.write().unwrap().insert(String::from(path), handler);
+}
+
+// The handlers of a reloaded configuration, in place of the old ones
+pub fn replace_websocket_handlers(handlers: Vec<(String, Arc<dyn WebSocketHandler>)>) {
+    let mut registry = WEBSOCKET_HANDLERS.write().unwrap();
+    registry.clear();
+    for (path, handler) in handlers {
+        eprintln!("WebSocket: {} -> {}", path, handler.name());
+        registry.insert(path, handler);
+    }
+}
+
+pub fn websocket_handler(path: &str) -> Option<Arc<dyn WebSocketHandler>> {
+    WEBSOCKET_HANDLERS.read().unwrap().get(path).cloned()
+}
+
+// Built-in handler named by a -W path=spec option: "echo" or
+// "tail:relative/file" (relative to the data directory).
+pub fn websocket_handler_from_spec(spec: &str) -> Result<Arc<dyn WebSocketHandler>> {
+    if spec == "echo" {
+        return Ok(Arc::new(EchoHandler));
+    }
+    if let Some(file) = spec.strip_prefix("tail:") {
+        let data_dir = CONFIG
+            .data_dir
+            .as_ref()
+            .ok_or_else(|| Error::msg("tail: WebSocket handler needs a data directory (-d)"))?;
+        let path = std::fs::canonicalize(data_dir.join(file))
+            .map_err(|e| anyhow::anyhow!("tail:{file}: {e}"))?;
+        if !path.starts_with(data_dir) {
+            return Err(anyhow::anyhow!("tail:{file}: must be inside the data directory"));
+        }
+        return Ok(Arc::new(TailHandler { path }));
+    }
+    Err(anyhow::anyhow!("unknown WebSocket handler {spec} (expected echo or tail:file)"))
+}
+
+// Is this an upgrade request for a path with a registered handler?
+pub fn is_websocket_request<S>(request: &HttpRequest<S>) -> bool {
+    request.method == "get"
+        && request
+            .headers
+            .get("upgrade")
+            .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+        && request
+            .headers
+            .get("connection")
+            .is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")))
+        && websocket_handler(&request.url).is_some()
+}
+
+// Complete the RFC 6455 handshake and run the handler for the path
+pub async fn request_handler_websocket<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut request: HttpRequest<S>,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let handler = websocket_handler(&request.url).ok_or_else(|| Error::msg("no WebSocket handler"))?;
+
+    let key = match (request.headers.get("sec-websocket-key"), request.headers.get("sec-websocket-version")) {
+        (Some(key), Some(version)) if version == "13" => key.clone(),
+        _ => {
+            request
+                .stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await?;
+            request.stream.flush().await?;
+            return Err(Error::msg("unsupported WebSocket handshake"));
+        }
+    };
+
+    let accept = base64::engine::general_purpose::STANDARD
+        .encode(Sha1::digest(format!("{key}{WEBSOCKET_GUID}").as_bytes()));
+    request
+        .stream
+        .write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )
+            .as_bytes(),
+        )
+        .await?;
+    request.stream.flush().await?;
+
+    println!(
+        "Request (server {}) client {} {} {} (WebSocket {})",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        handler.name()
+    );
+
+    let ws_request = WebSocketRequest {
+        client: request.client,
+        server_name: request.server_name.clone(),
+        url: request.url.clone(),
+        query: request.query.clone(),
+    };
+    let result = handler.handle(WebSocket::new(&mut request.stream), &ws_request).await;
+
+    println!(
+        "Request (server {}) client {} {} {} WebSocket closed after {:?}",
+        &request.server_name.as_ref().map_or("default", |x| x),
+        &request.client,
+        &request.method,
+        &request.url,
+        start_time.elapsed()
+    );
+    result
+}
+
+// Server side of a WebSocket connection. recv() is cancellation safe, so it
+// can be used in select! alongside whatever else a handler waits on: frames
+// go out through a queue that's written a piece at a time, so dropping
+// recv() (or a send) part-way never leaves half a frame on the wire.
+pub struct WebSocket<'a> {
+    stream: &'a mut (dyn IoStream + 'a),
+    buffer: Vec<u8>,
+    queued: Vec<u8>,
+    fragments: Vec<u8>,
+    fragment_opcode: Option<u8>,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(stream: &'a mut (dyn IoStream + 'a)) -> WebSocket<'a> {
+        WebSocket {
+            stream,
+            buffer: Vec::new(),
+            queued: Vec::new(),
+            fragments: Vec::new(),
+            fragment_opcode: None,
+            closed: false,
+        }
+    }
+
+    // Next complete message, answering pings along the way. None once the
+    // client has closed the connection, or the server is shutting down (the
+    // client is told it's going away).
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        loop {
+            // Pongs and the close reply go out before anything else is read
+            self.write_queued().await?;
+            if self.closed {
+                return Ok(None);
+            }
+
+            let frame = tokio::select! {
+                frame = self.next_frame() => frame?,
+                _ = shutdown_started() => {
+                    self.queue_close(CLOSE_GOING_AWAY);
+                    continue;
+                }
+            };
+            let (fin, opcode, payload) = match frame {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            // Control frames can't be fragmented and fit in a short header
+            // (RFC 6455 section 5.5)
+            if opcode & 0x08 != 0 && (!fin || payload.len() > 125) {
+                return self.fail(CLOSE_PROTOCOL_ERROR, "fragmented or oversized control frame").await;
+            }
+
+            match opcode {
+                OPCODE_PING => self.queue_frame(OPCODE_PONG, &payload),
+                OPCODE_PONG => (),
+                OPCODE_CLOSE => {
+                    // Echo the code, if it's one that may be sent at all
+                    // (RFC 6455 section 7.4); 1005 and 1006 never are
+                    let code = match payload.len() {
+                        0 => CLOSE_NORMAL,
+                        1 => return self.fail(CLOSE_PROTOCOL_ERROR, "truncated close code").await,
+                        _ => u16::from_be_bytes([payload[0], payload[1]]),
+                    };
+                    if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, "invalid close code").await;
+                    }
+                    if std::str::from_utf8(&payload[2.min(payload.len())..]).is_err() {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, "invalid UTF-8 in close reason").await;
+                    }
+                    self.queue_close(code);
+                }
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    match (opcode, self.fragment_opcode) {
+                        (OPCODE_CONTINUATION, None) => return self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation frame").await,
+                        (OPCODE_CONTINUATION, Some(_)) => (),
+                        (_, Some(_)) => return self.fail(CLOSE_PROTOCOL_ERROR, "interleaved data frames").await,
+                        (_, None) => self.fragment_opcode = Some(opcode),
+                    }
+                    if self.fragments.len() + payload.len() > MAX_MESSAGE_BYTES {
+                        return self.fail(CLOSE_TOO_BIG, "message too large").await;
+                    }
+                    self.fragments.extend(payload);
+                    if fin {
+                        let data = std::mem::take(&mut self.fragments);
+                        return match self.fragment_opcode.take() {
+                            Some(OPCODE_TEXT) => match String::from_utf8(data) {
+                                Ok(text) => Ok(Some(Message::Text(text))),
+                                Err(_) => self.fail(CLOSE_PROTOCOL_ERROR, "invalid UTF-8 in text message").await,
+                            },
+                            _ => Ok(Some(Message::Binary(data))),
+                        };
+                    }
+                }
+                _ => return self.fail(CLOSE_PROTOCOL_ERROR, "unknown opcode").await,
+            }
+        }
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<()> {
+        self.queue_frame(OPCODE_TEXT, text.as_bytes());
+        self.write_queued().await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<()> {
+        self.queue_frame(OPCODE_BINARY, data);
+        self.write_queued().await
+    }
+
+    pub async fn close(&mut self, code: u16) -> Result<()> {
+        self.queue_close(code);
+        self.write_queued().await
+    }
+
+    async fn fail(&mut self, code: u16, reason: &str) -> Result<Option<Message>> {
+        let _ = self.close(code).await;
+        Err(anyhow::anyhow!("WebSocket: {reason}"))
+    }
+
+    fn queue_close(&mut self, code: u16) {
+        if !self.closed {
+            self.queue_frame(OPCODE_CLOSE, &code.to_be_bytes());
+            self.closed = true;
+        }
+    }
+
+    // Server frames are never masked or fragmented
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let frame = &mut self.queued;
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            n if n < 126 => frame.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend((n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(127);
+                frame.extend((n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+    }
+
+    // Send the queued frames. Each write() takes some bytes or none, so
+    // dropping this mid-way leaves the rest queued for next time.
+    async fn write_queued(&mut self) -> Result<()> {
+        while !self.queued.is_empty() {
+            let n = self.stream.write(&self.queued).await?;
+            if n == 0 {
+                return Err(Error::msg("WebSocket: connection closed while sending"));
+            }
+            self.queued.drain(..n);
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    // Read until a whole frame is buffered. Only read_buf() is awaited, which
+    // either appends to the buffer or doesn't, so dropping this mid-way loses
+    // nothing.
+    async fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>> {
+        loop {
+            if let Some((header_len, payload_len)) = self.frame_size()? {
+                if self.buffer.len() >= header_len + payload_len {
+                    let fin = self.buffer[0] & 0x80 != 0;
+                    let opcode = self.buffer[0] & 0x0f;
+                    let mask = [
+                        self.buffer[header_len - 4],
+                        self.buffer[header_len - 3],
+                        self.buffer[header_len - 2],
+                        self.buffer[header_len - 1],
+                    ];
+                    let mut payload: Vec<u8> = self.buffer.drain(..header_len + payload_len).skip(header_len).collect();
+                    for (i, b) in payload.iter_mut().enumerate() {
+                        *b ^= mask[i % 4];
+                    }
+                    return Ok(Some((fin, opcode, payload)));
+                }
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Header and payload length of the buffered frame, once enough of the
+    // header has arrived to tell.
+    fn frame_size(&self) -> Result<Option<(usize, usize)>> {
+        let Some(&[_, b1]) = self.buffer.get(..2) else {
+            return Ok(None);
+        };
+        if b1 & 0x80 == 0 {
+            return Err(Error::msg("WebSocket: unmasked client frame"));
+        }
+        let (extra, payload_len) = match b1 & 0x7f {
+            126 => match self.buffer.get(2..4) {
+                Some(l) => (2, u16::from_be_bytes([l[0], l[1]]) as usize),
+                None => return Ok(None),
+            },
+            127 => match self.buffer.get(2..10) {
+                Some(l) => (8, u64::from_be_bytes(l.try_into()?) as usize),
+                None => return Ok(None),
+            },
+            n => (0, n as usize),
+        };
+        if payload_len > MAX_MESSAGE_BYTES {
+            return Err(Error::msg("WebSocket: frame too large"));
+        }
+        Ok(Some((2 + extra + 4, payload_len)))
+    }
+}
+
+// Sends every message straight back
+pub struct EchoHandler;
+
+impl WebSocketHandler for EchoHandler {
+    fn name(&self) -> String {
+        String::from("echo")
+    }
+
+    fn handle<'a>(&'a self, mut ws: WebSocket<'a>, _request: &'a WebSocketRequest) -> WsFuture<'a> {
+        Box::pin(async move {
+            while let Some(message) = ws.recv().await? {
+                match message {
+                    Message::Text(text) => ws.send_text(&text).await?,
+                    Message::Binary(data) => ws.send_binary(&data).await?,
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+// Streams lines appended to a file (e.g. a log under the data directory)
+// as text messages, starting from the current end of the file.
+pub struct TailHandler {
+    path: PathBuf,
+}
+
+impl WebSocketHandler for TailHandler {
+    fn name(&self) -> String {
+        format!("tail {}", self.path.to_string_lossy())
+    }
+
+    fn handle<'a>(&'a self, mut ws: WebSocket<'a>, _request: &'a WebSocketRequest) -> WsFuture<'a> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(chroot_path(&self.path)).await?;
+            let mut position = file.seek(SeekFrom::End(0)).await?;
+            let mut partial = Vec::<u8>::new();
+
+            loop {
+                tokio::select! {
+                    message = ws.recv() => match message? {
+                        Some(_) => (),
+                        None => return Ok(()),
+                    },
+                    _ = sleep(TAIL_POLL_INTERVAL) => {
+                        let len = tokio::fs::metadata(chroot_path(&self.path)).await?.len();
+                        if len < position {
+                            // Truncated or rotated in place: start again from the top
+                            position = file.seek(SeekFrom::Start(0)).await?;
+                            partial.clear();
+                        }
+                        if len > position {
+                            let mut appended = Vec::new();
+                            (&mut file).take(len - position).read_to_end(&mut appended).await?;
+                            position += appended.len() as u64;
+                            partial.extend(appended);
+                            while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+                                let line: Vec<u8> = partial.drain(..=end).collect();
+                                let line = String::from_utf8_lossy(&line);
+                                ws.send_text(line.trim_end_matches(['\r', '\n'])).await?;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client frame, masked as clients must
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n => {
+                frame.push(0x80 | 126);
+                frame.extend((n as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Feed the frames to a server side WebSocket; what recv() returned each
+    // time until it ended or failed, and what the server sent back
+    async fn exchange(frames: &[Vec<u8>]) -> (Vec<Result<Option<Message>>>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(&frames.concat()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        {
+            let mut ws = WebSocket::new(&mut server);
+            loop {
+                let message = ws.recv().await;
+                let done = !matches!(message, Ok(Some(_)));
+                received.push(message);
+                if done {
+                    break;
+                }
+            }
+        }
+        drop(server);
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        (received, sent)
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let long = vec![b'x'; 300];
+        let (received, sent) = exchange(&[
+            frame(true, OPCODE_TEXT, b"hello"),
+            frame(true, OPCODE_BINARY, &long),
+            frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes()),
+        ])
+        .await;
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].as_ref().unwrap(), &Some(Message::Text(String::from("hello"))));
+        assert_eq!(received[1].as_ref().unwrap(), &Some(Message::Binary(long)));
+        assert_eq!(received[2].as_ref().unwrap(), &None);
+        // The close is answered with the client's code
+        assert_eq!(sent, [0x88, 0x02, 0x03, 0xe8]);
+
+        // Application codes too, and a close without one as a normal close
+        let mut close = 4000u16.to_be_bytes().to_vec();
+        close.extend(b"done");
+        let (_, sent) = exchange(&[frame(true, OPCODE_CLOSE, &close)]).await;
+        assert_eq!(sent, [0x88, 0x02, 0x0f, 0xa0]);
+        let (_, sent) = exchange(&[frame(true, OPCODE_CLOSE, b"")]).await;
+        assert_eq!(sent, [0x88, 0x02, 0x03, 0xe8]);
+    }
+
+    #[tokio::test]
+    async fn fragments_and_pings() {
+        let (received, sent) = exchange(&[
+            frame(false, OPCODE_TEXT, b"hel"),
+            frame(true, OPCODE_PING, b"are you there"),
+            frame(true, OPCODE_CONTINUATION, b"lo"),
+        ])
+        .await;
+        assert_eq!(received[0].as_ref().unwrap(), &Some(Message::Text(String::from("hello"))));
+        // Ends when the client goes away
+        assert_eq!(received[1].as_ref().unwrap(), &None);
+        let mut pong = vec![0x8a, 13];
+        pong.extend(b"are you there");
+        assert_eq!(sent, pong);
+    }
+
+    #[tokio::test]
+    async fn protocol_errors() {
+        let close_protocol_error = [0x88, 0x02, 0x03, 0xea];
+        for frames in [
+            vec![frame(true, OPCODE_PING, &[0; 126])],
+            vec![frame(false, OPCODE_PING, b"x")],
+            vec![frame(true, OPCODE_CONTINUATION, b"x")],
+            vec![frame(false, OPCODE_TEXT, b"a"), frame(true, OPCODE_TEXT, b"b")],
+            vec![frame(true, OPCODE_TEXT, &[0xff, 0xfe])],
+            vec![frame(true, 0x3, b"x")],
+            vec![frame(true, OPCODE_CLOSE, &[0x03])],
+            vec![frame(true, OPCODE_CLOSE, &1005u16.to_be_bytes())],
+            vec![frame(true, OPCODE_CLOSE, &1006u16.to_be_bytes())],
+            vec![frame(true, OPCODE_CLOSE, &999u16.to_be_bytes())],
+            vec![frame(true, OPCODE_CLOSE, &2000u16.to_be_bytes())],
+            vec![frame(true, OPCODE_CLOSE, &5000u16.to_be_bytes())],
+            vec![frame(true, OPCODE_CLOSE, &[0x03, 0xe8, 0xff])],
+        ] {
+            let (received, sent) = exchange(&frames).await;
+            assert!(received.last().unwrap().is_err());
+            assert_eq!(sent, close_protocol_error);
+        }
+
+        // Clients have to mask their frames
+        let mut unmasked = frame(true, OPCODE_TEXT, b"");
+        unmasked[1] &= 0x7f;
+        unmasked.truncate(2);
+        let (received, _) = exchange(&[unmasked]).await;
+        assert!(received[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn sends() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut ws = WebSocket::new(&mut server);
+        ws.send_text("hi").await.unwrap();
+        ws.send_binary(&[0; 200]).await.unwrap();
+        ws.close(CLOSE_GOING_AWAY).await.unwrap();
+        assert_eq!(ws.recv().await.unwrap(), None);
+        drop(server);
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        let mut expected = vec![0x81, 2, b'h', b'i', 0x82, 126, 0, 200];
+        expected.extend([0; 200]);
+        expected.extend([0x88, 2, 0x03, 0xe9]);
+        assert_eq!(sent, expected);
+    }
+}